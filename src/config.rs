@@ -4,6 +4,7 @@ use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncReadExt, BufReader};
+use url::Url;
 
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct IndexConfig {
@@ -21,6 +22,39 @@ pub struct IndexConfig {
     #[serde(default = "IndexConfig::name_default")]
     pub name: String,
     pub email: Option<String>,
+    #[serde(default = "IndexConfig::sparse_path_default")]
+    pub sparse_path: Vec<String>,
+    pub public_url: Option<String>,
+    /// Whether the crate files, the index served by ktra and the crate APIs require a valid token.
+    /// The git clients need to send the token in the `Authorization` header,
+    /// e.g. with `http.extraHeader`, to clone the index hosted on `bare_path`.
+    #[serde(default)]
+    pub auth_required: bool,
+    /// The bare repository ktra hosts the index in, which is initialized if it does not exist
+    /// and cloned into `local_path` when `local_path` does not exist yet.
+    /// The server fails to start if the existing `local_path` is not cloned from it.
+    pub bare_path: Option<PathBuf>,
+    #[serde(default = "IndexConfig::git_http_path_default")]
+    pub git_http_path: Vec<String>,
+    #[serde(default = "IndexConfig::push_max_retries_default")]
+    pub push_max_retries: usize,
+    /// The backoff in milliseconds before the first push retry, which doubles on every retry up to a minute.
+    #[serde(default = "IndexConfig::push_retry_backoff_ms_default")]
+    pub push_retry_backoff_ms: u64,
+    pub commit_batch_window_ms: Option<u64>,
+    pub pull_interval_secs: Option<u64>,
+    pub webhook_secret: Option<String>,
+    pub signing_method: Option<SigningMethod>,
+    /// The key ID passed to `gpg` or the private key path passed to `ssh-keygen`.
+    pub signing_key: Option<String>,
+}
+
+/// The way to sign index commits.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningMethod {
+    Gpg,
+    Ssh,
 }
 
 impl IndexConfig {
@@ -35,6 +69,22 @@ impl IndexConfig {
     fn name_default() -> String {
         "ktra-driver".to_owned()
     }
+
+    pub fn sparse_path_default() -> Vec<String> {
+        vec!["index".to_owned()]
+    }
+
+    pub fn git_http_path_default() -> Vec<String> {
+        vec!["git".to_owned(), "index".to_owned()]
+    }
+
+    fn push_max_retries_default() -> usize {
+        5
+    }
+
+    fn push_retry_backoff_ms_default() -> u64 {
+        100
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -46,6 +96,19 @@ pub struct CrateFilesConfig {
     pub cache_dir_path: PathBuf,
     #[serde(default = "CrateFilesConfig::dl_path_default")]
     pub dl_path: Vec<String>,
+    /// The interval in seconds to store the download counts into the database.
+    #[serde(default = "CrateFilesConfig::downloads_flush_interval_secs_default")]
+    pub downloads_flush_interval_secs: u64,
+    /// The interval in seconds to remove the orphaned crate files, which is disabled if unset.
+    #[serde(default)]
+    pub gc_interval_secs: Option<u64>,
+    /// The age in seconds an orphaned file must reach before it is removed,
+    /// which keeps the files of the publishes in progress.
+    #[serde(default = "CrateFilesConfig::gc_grace_period_secs_default")]
+    pub gc_grace_period_secs: u64,
+    #[cfg(feature = "storage-s3")]
+    #[serde(default)]
+    pub s3_config: S3Config,
 }
 
 impl Default for CrateFilesConfig {
@@ -55,6 +118,12 @@ impl Default for CrateFilesConfig {
             #[cfg(feature = "crates-io-mirroring")]
             cache_dir_path: CrateFilesConfig::cache_dir_path_default(),
             dl_path: CrateFilesConfig::dl_path_default(),
+            downloads_flush_interval_secs: CrateFilesConfig::downloads_flush_interval_secs_default(
+            ),
+            gc_interval_secs: None,
+            gc_grace_period_secs: CrateFilesConfig::gc_grace_period_secs_default(),
+            #[cfg(feature = "storage-s3")]
+            s3_config: Default::default(),
         }
     }
 }
@@ -72,6 +141,56 @@ impl CrateFilesConfig {
     pub fn dl_path_default() -> Vec<String> {
         vec!["dl".to_owned()]
     }
+
+    fn downloads_flush_interval_secs_default() -> u64 {
+        60
+    }
+
+    fn gc_grace_period_secs_default() -> u64 {
+        24 * 60 * 60
+    }
+}
+
+/// The S3-compatible object storage the crate files are stored in instead of `dl_dir_path`.
+#[cfg(feature = "storage-s3")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Config {
+    /// The URL of the storage, e.g. `https://s3.us-east-1.amazonaws.com`.
+    #[serde(default = "S3Config::endpoint_default")]
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "S3Config::region_default")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// The prefix of the object keys, e.g. `crates/`.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+#[cfg(feature = "storage-s3")]
+impl Default for S3Config {
+    fn default() -> S3Config {
+        S3Config {
+            endpoint: S3Config::endpoint_default(),
+            bucket: String::new(),
+            region: S3Config::region_default(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            prefix: String::new(),
+        }
+    }
+}
+
+#[cfg(feature = "storage-s3")]
+impl S3Config {
+    fn endpoint_default() -> String {
+        "http://localhost:9000".to_owned()
+    }
+
+    fn region_default() -> String {
+        "us-east-1".to_owned()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -127,6 +246,126 @@ impl DbConfig {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct PublishConfig {
+    /// The categories crates can be published with. Any category is accepted if not set.
+    pub allowed_categories: Option<Vec<String>>,
+    /// The badges crates can be published with. Any badge is accepted if not set.
+    pub allowed_badges: Option<Vec<String>>,
+    /// The maximum size of a crate file in bytes.
+    #[serde(default = "PublishConfig::max_crate_size_default")]
+    pub max_crate_size: u64,
+    /// The maximum size of the metadata JSON in bytes.
+    #[serde(default = "PublishConfig::max_metadata_size_default")]
+    pub max_metadata_size: u64,
+    /// The maximum total size in bytes of the crate files each user publishes.
+    pub user_quota: Option<u64>,
+    /// The maximum total size in bytes of the crate files of each crate.
+    pub crate_quota: Option<u64>,
+    /// The index URLs of the other registries dependencies can come from.
+    /// Any registry is accepted if not set.
+    pub allowed_registries: Option<Vec<Url>>,
+}
+
+impl Default for PublishConfig {
+    fn default() -> PublishConfig {
+        PublishConfig {
+            allowed_categories: None,
+            allowed_badges: None,
+            max_crate_size: PublishConfig::max_crate_size_default(),
+            max_metadata_size: PublishConfig::max_metadata_size_default(),
+            user_quota: None,
+            crate_quota: None,
+            allowed_registries: None,
+        }
+    }
+}
+
+impl PublishConfig {
+    fn max_crate_size_default() -> u64 {
+        10 * 1024 * 1024
+    }
+
+    fn max_metadata_size_default() -> u64 {
+        1024 * 1024
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PolicyConfig {
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+/// A rule which the metadata of the published crates must satisfy.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolicyRule {
+    pub field: PolicyField,
+    #[serde(flatten)]
+    pub condition: PolicyCondition,
+    #[serde(default)]
+    pub severity: PolicySeverity,
+    /// The message reported instead of the generated one when the rule is violated.
+    pub message: Option<String>,
+    /// The names of the crates the rule is not applied to.
+    #[serde(default)]
+    pub exceptions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyField {
+    Name,
+    Authors,
+    Description,
+    Documentation,
+    Homepage,
+    Readme,
+    Keywords,
+    Categories,
+    License,
+    LicenseFile,
+    Repository,
+    Links,
+}
+
+/// The condition every value of the field must satisfy.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyCondition {
+    /// The field must have a value if `true`.
+    Required(bool),
+    /// The field must not have any values if `true`.
+    Forbidden(bool),
+    /// The values must be one of them.
+    OneOf(Vec<String>),
+    /// The values must match the regular expression.
+    Matches(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicySeverity {
+    /// Rejects the publishing.
+    #[default]
+    Deny,
+    /// Accepts the publishing with a warning.
+    Warn,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct AdminConfig {
+    /// The logins of the users allowed to delete crate versions.
+    #[serde(default)]
+    pub logins: Vec<String>,
+}
+
+impl AdminConfig {
+    pub fn is_admin(&self, login: &str) -> bool {
+        self.logins.iter().any(|l| l == login)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig {
     #[serde(default = "ServerConfig::address_default")]
@@ -183,6 +422,12 @@ pub struct Config {
     pub server_config: ServerConfig,
     #[serde(default)]
     pub openid_config: OpenIdConfig,
+    #[serde(default)]
+    pub publish_config: PublishConfig,
+    #[serde(default)]
+    pub policy_config: PolicyConfig,
+    #[serde(default)]
+    pub admin_config: AdminConfig,
 }
 
 impl Default for Config {
@@ -193,6 +438,9 @@ impl Default for Config {
             index_config: Config::index_config_default(),
             server_config: Default::default(),
             openid_config: Default::default(),
+            publish_config: Default::default(),
+            policy_config: Default::default(),
+            admin_config: Default::default(),
         }
     }
 }
@@ -215,6 +463,15 @@ impl Config {
             local_path: IndexConfig::local_path_default(),
             branch: IndexConfig::branch_default(),
             name: IndexConfig::name_default(),
+            sparse_path: IndexConfig::sparse_path_default(),
+            git_http_path: IndexConfig::git_http_path_default(),
+            push_max_retries: IndexConfig::push_max_retries_default(),
+            push_retry_backoff_ms: IndexConfig::push_retry_backoff_ms_default(),
+            commit_batch_window_ms: None,
+            pull_interval_secs: None,
+            webhook_secret: None,
+            signing_method: None,
+            signing_key: None,
             ..Default::default()
         }
     }
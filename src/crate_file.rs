@@ -0,0 +1,193 @@
+use crate::error::Error;
+use flate2::read::GzDecoder;
+use semver::Version;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::{Component, Path};
+use tar::{Archive, EntryType};
+
+const CARGO_TOML: &str = "Cargo.toml";
+/// The maximum size of `Cargo.toml` in bytes, which is read into memory.
+const MAX_MANIFEST_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    package: ManifestPackage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestPackage {
+    name: String,
+    version: Version,
+}
+
+/// Validates that the crate file is a gzipped tarball `cargo package` produces for `name` and `version`.
+///
+/// Every entry must be a regular file or a directory under the single `{name}-{version}/` directory,
+/// and the `Cargo.toml` in it must declare the same package name and version.
+#[tracing::instrument(skip(crate_file, name, version))]
+pub fn validate(crate_file: impl Read, name: &str, version: &Version) -> Result<(), Error> {
+    let root = format!("{}-{}", name, version);
+    let mut manifest = None;
+
+    let mut archive = Archive::new(GzDecoder::new(crate_file));
+    let entries = archive
+        .entries()
+        .map_err(|e| invalid(format!("not a gzipped tarball: {}", e)))?;
+
+    for entry in entries {
+        let mut entry = entry.map_err(|e| invalid(format!("broken tarball: {}", e)))?;
+
+        let path = entry
+            .path()
+            .map_err(|e| invalid(format!("invalid entry path: {}", e)))?
+            .into_owned();
+        let relative_path = relative_path(&path, &root)?;
+
+        match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {}
+            EntryType::Directory | EntryType::XGlobalHeader => continue,
+            entry_type => {
+                return Err(invalid(format!(
+                    "{} has an unsupported entry type: {:?}",
+                    path.display(),
+                    entry_type
+                )))
+            }
+        }
+
+        if relative_path == Path::new(CARGO_TOML) {
+            let too_large = || {
+                invalid(format!(
+                    "{} exceeds the limit of {} bytes",
+                    path.display(),
+                    MAX_MANIFEST_SIZE
+                ))
+            };
+            if entry
+                .header()
+                .size()
+                .map_or(true, |size| size > MAX_MANIFEST_SIZE)
+            {
+                return Err(too_large());
+            }
+
+            // the entry is read one byte more than the limit to find that it exceeds the limit.
+            let mut content = String::new();
+            entry
+                .by_ref()
+                .take(MAX_MANIFEST_SIZE + 1)
+                .read_to_string(&mut content)
+                .map_err(|e| invalid(format!("failed to read {}: {}", path.display(), e)))?;
+            if content.len() as u64 > MAX_MANIFEST_SIZE {
+                return Err(too_large());
+            }
+            manifest = Some(content);
+        }
+    }
+
+    let manifest =
+        manifest.ok_or_else(|| invalid(format!("{}/{} is missing", root, CARGO_TOML)))?;
+    let manifest: Manifest =
+        toml::from_str(&manifest).map_err(|e| invalid(format!("invalid {}: {}", CARGO_TOML, e)))?;
+
+    if manifest.package.name != name || &manifest.package.version != version {
+        return Err(invalid(format!(
+            "{} declares {} v{} but {} v{} is published",
+            CARGO_TOML, manifest.package.name, manifest.package.version, name, version
+        )));
+    }
+
+    Ok(())
+}
+
+/// Returns the path relative to the `root` directory,
+/// rejecting the absolute paths and the ones escaping from it.
+#[tracing::instrument(skip(path, root))]
+fn relative_path<'a>(path: &'a Path, root: &str) -> Result<&'a Path, Error> {
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(invalid(format!("{} is not a safe path", path.display())));
+    }
+
+    path.strip_prefix(root)
+        .map_err(|_| invalid(format!("{} is outside of {}/", path.display(), root)))
+}
+
+#[tracing::instrument(skip(message))]
+fn invalid(message: String) -> Error {
+    Error::InvalidCrateFile(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, MAX_MANIFEST_SIZE};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use semver::Version;
+    use tar::{Builder, EntryType, Header};
+
+    fn crate_file(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, entry_type, content) in entries {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            // `set_path` refuses `..` so the name is written directly.
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_cksum();
+            builder.append(&header, content.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    const MANIFEST: &str = "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n";
+
+    #[test]
+    fn test_validate_valid_crate_file() {
+        let data = crate_file(&[
+            ("foo-0.1.0/Cargo.toml", EntryType::Regular, MANIFEST),
+            ("foo-0.1.0/src/lib.rs", EntryType::Regular, ""),
+        ]);
+        assert!(validate(&data[..], "foo", &Version::new(0, 1, 0)).is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_mismatched_manifest() {
+        let data = crate_file(&[("foo-0.2.0/Cargo.toml", EntryType::Regular, MANIFEST)]);
+        assert!(validate(&data[..], "foo", &Version::new(0, 2, 0)).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_unsafe_entries() {
+        let manifest = ("foo-0.1.0/Cargo.toml", EntryType::Regular, MANIFEST);
+        for entry in &[
+            ("foo-0.1.0/../evil", EntryType::Regular, ""),
+            ("/foo-0.1.0/evil", EntryType::Regular, ""),
+            ("bar-0.1.0/src/lib.rs", EntryType::Regular, ""),
+            ("foo-0.1.0/link", EntryType::Symlink, ""),
+        ] {
+            let data = crate_file(&[manifest, *entry]);
+            assert!(
+                validate(&data[..], "foo", &Version::new(0, 1, 0)).is_err(),
+                "{:?}",
+                entry.0
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_rejects_too_large_manifest() {
+        let manifest = format!("{}#{}", MANIFEST, " ".repeat(MAX_MANIFEST_SIZE as usize));
+        let data = crate_file(&[("foo-0.1.0/Cargo.toml", EntryType::Regular, &manifest)]);
+        assert!(validate(&data[..], "foo", &Version::new(0, 1, 0)).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_non_tarball() {
+        assert!(validate(&b"not a crate"[..], "foo", &Version::new(0, 1, 0)).is_err());
+    }
+}
@@ -2,10 +2,11 @@
 
 use crate::config::DbConfig;
 use crate::error::Error;
-use crate::models::{Entry, Metadata, Query, Search, User};
+use crate::models::{DeletionRecord, Deprecation, Entry, Metadata, Query, Search, User};
 use argon2::{self, hash_encoded, verify_encoded};
 use async_trait::async_trait;
 use bson::{doc, from_document, to_document, Document};
+use chrono::NaiveDate;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use futures::TryFutureExt;
@@ -18,16 +19,20 @@ use serde::ser::Serialize;
 use serde::{Deserialize as DeserializeTrait, Serialize as SerializeTrait};
 use url::Url;
 
-use crate::db_manager::utils::{argon2_config_and_salt, check_crate_name, normalized_crate_name};
+use crate::db_manager::utils::{
+    argon2_config_and_salt, check_crate_name, normalized_crate_name, storage_usages,
+};
 use crate::db_manager::DbManager;
 
 const SCHEMA_VERSION_KEY: &str = "__SCHEMA_VERSION__";
-const SCHEMA_VERSION: i64 = 1;
+const SCHEMA_VERSION: i64 = 3;
 const ENTRIES_KEY: &str = "__ENTRIES__";
 const USERS_KEY: &str = "__USERS__";
 const PASSWORDS_KEY: &str = "__PASSWORDS__";
 const TOKENS_KEY: &str = "__TOKENS__";
 const OAUTH_NONCES_KEY: &str = "__OAUTH_NONCES__";
+const DELETIONS_KEY: &str = "__DELETIONS__";
+const STORAGE_USAGES_KEY: &str = "__STORAGE_USAGES__";
 
 #[derive(Clone, SerializeTrait, DeserializeTrait)]
 struct TokenMap {
@@ -41,6 +46,12 @@ struct PasswordMap {
     password: String,
 }
 
+#[derive(Clone, SerializeTrait, DeserializeTrait)]
+struct StorageUsageMap {
+    id: u32,
+    usage: u64,
+}
+
 #[derive(Debug, Clone, SerializeTrait, DeserializeTrait)]
 struct EntryMap {
     name: String,
@@ -86,7 +97,9 @@ impl DbManager for MongoDbManager {
             Ok(db_manager)
         };
 
-        initialization.map_err(Error::Db).await
+        let db_manager = initialization.map_err(Error::Db).await?;
+        db_manager.migrate_entries().await?;
+        Ok(db_manager)
     }
 
     async fn get_login_prefix(&self) -> Result<&str, Error> {
@@ -275,6 +288,23 @@ impl DbManager for MongoDbManager {
             .await
     }
 
+    #[tracing::instrument(skip(self, user_id))]
+    async fn user_by_id(&self, user_id: u32) -> Result<User, Error> {
+        let collection = self
+            .client
+            .database(&self.database_name)
+            .collection(USERS_KEY);
+
+        collection
+            .find_one(doc! { "id": user_id }, None)
+            .map_err(Error::Db)
+            .await?
+            .map(from_document::<User>)
+            .transpose()
+            .map_err(Error::BsonDeserialization)?
+            .ok_or(Error::InvalidUser(user_id))
+    }
+
     #[tracing::instrument(skip(self, name))]
     async fn user_by_username(&self, name: &str) -> Result<User, Error> {
         let name = name.to_owned();
@@ -444,8 +474,91 @@ impl DbManager for MongoDbManager {
             return Err(Error::InvalidUser(owner_id));
         }
 
+        let charged_user_id = entry.charged_user_id(&metadata);
+        let crate_size = metadata.crate_size;
         entry.versions_mut().insert(version, metadata);
-        self.insert_entry(&name, entry).await
+        self.insert_entry(&name, entry).await?;
+
+        if let (Some(user_id), Some(crate_size)) = (charged_user_id, crate_size) {
+            self.edit_storage_usage(user_id, |usage| usage.saturating_add(crate_size))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn remove_metadata(&self, name: &str, version: Version) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        let metadata = entry
+            .versions_mut()
+            .remove(&version)
+            .ok_or(Error::VersionNotFoundInDb(version))?;
+        let charged_user_id = entry.charged_user_id(&metadata);
+
+        // the crate is regarded as unpublished if no versions remain.
+        if entry.versions().is_empty() {
+            self.remove_entry(name).await?;
+        } else {
+            self.insert_entry(name, entry).await?;
+        }
+
+        if let (Some(user_id), Some(crate_size)) = (charged_user_id, metadata.crate_size) {
+            self.edit_storage_usage(user_id, |usage| usage.saturating_sub(crate_size))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, record))]
+    async fn add_deletion_record(&self, record: DeletionRecord) -> Result<(), Error> {
+        let document = to_document(&record).map_err(Error::BsonSerialization)?;
+        let collection = self
+            .client
+            .database(&self.database_name)
+            .collection(DELETIONS_KEY);
+
+        collection
+            .insert_one(document, None)
+            .map_ok(drop)
+            .map_err(Error::Db)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, name))]
+    async fn crate_storage_usage(&self, name: &str) -> Result<u64, Error> {
+        self.entry(name).await.map(|entry| entry.storage_usage())
+    }
+
+    #[tracing::instrument(skip(self, user_id))]
+    async fn user_storage_usage(&self, user_id: u32) -> Result<u64, Error> {
+        let usage_map = self.storage_usage_map(user_id).await?;
+        Ok(usage_map.map(|m| m.usage).unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self, name, version, size))]
+    async fn set_crate_size(&self, name: &str, version: Version, size: u64) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        let (charged_user_id, old_size) = entry
+            .versions()
+            .get(&version)
+            .map(|metadata| (entry.charged_user_id(metadata), metadata.crate_size))
+            .ok_or_else(|| Error::VersionNotFoundInDb(version.clone()))?;
+        if let Some(metadata) = entry.package_mut(&version) {
+            metadata.crate_size = Some(size);
+        }
+        self.insert_entry(name, entry).await?;
+
+        if let Some(user_id) = charged_user_id {
+            self.edit_storage_usage(user_id, |usage| {
+                usage
+                    .saturating_sub(old_size.unwrap_or_default())
+                    .saturating_add(size)
+            })
+            .await?;
+        }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, user_id, name, version))]
@@ -475,18 +588,56 @@ impl DbManager for MongoDbManager {
         Ok(can_edit_package)
     }
 
-    #[tracing::instrument(skip(self, name, version))]
-    async fn yank(&self, name: &str, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, true, Error::AlreadyYanked)
+    #[tracing::instrument(skip(self, name, version, reason))]
+    async fn yank(
+        &self,
+        name: &str,
+        version: Version,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        self.change_yanked(name, version, true, reason, Error::AlreadyYanked)
             .await
     }
 
     #[tracing::instrument(skip(self, name, version))]
     async fn unyank(&self, name: &str, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, false, Error::NotYetYanked)
+        self.change_yanked(name, version, false, None, Error::NotYetYanked)
             .await
     }
 
+    #[tracing::instrument(skip(self, name, deprecation))]
+    async fn set_deprecation(
+        &self,
+        name: &str,
+        deprecation: Option<Deprecation>,
+    ) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        if entry.is_empty() {
+            return Err(Error::CrateNotFoundInDb(name.to_owned()));
+        }
+
+        entry.set_deprecation(deprecation);
+        self.insert_entry(name, entry).await
+    }
+
+    #[tracing::instrument(skip(self, name, version, date, count))]
+    async fn add_downloads(
+        &self,
+        name: &str,
+        version: Version,
+        date: NaiveDate,
+        count: u64,
+    ) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+        let metadata = entry
+            .package_mut(&version)
+            .ok_or(Error::VersionNotFoundInDb(version))?;
+
+        metadata.add_downloads(date, count);
+        self.insert_entry(name, entry).await
+    }
+
     #[tracing::instrument(skip(self, query))]
     async fn search(&self, query: &Query) -> Result<Search, Error> {
         let query_string = normalized_crate_name(&query.string);
@@ -527,7 +678,7 @@ impl DbManager for MongoDbManager {
                         .iter()
                         .filter(|(_, metadata)| !metadata.yanked)
                         .max_by_key(|(key, _)| *key)?;
-                    Some(latest_version.to_searched())
+                    Some(entry_map.entry.to_searched(latest_version))
                 })
                 .collect();
 
@@ -540,6 +691,39 @@ impl DbManager for MongoDbManager {
         }
     }
 
+    #[tracing::instrument(skip(self, name))]
+    async fn crate_entry(&self, name: &str) -> Result<Entry, Error> {
+        self.entry(name).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let collection = self
+            .client
+            .database(&self.database_name)
+            .collection(ENTRIES_KEY);
+        let cursor = collection.find(None, None).map_err(Error::Db).await?;
+        let (entries, errors): (Vec<_>, Vec<_>) = cursor
+            .map_err(Error::Db)
+            .and_then(|document| async {
+                from_document::<EntryMap>(document).map_err(Error::BsonDeserialization)
+            })
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .partition(Result::is_ok);
+
+        if errors.is_empty() {
+            Ok(entries
+                .into_iter()
+                .map(Result::unwrap)
+                .map(|entry_map| entry_map.entry)
+                .collect())
+        } else {
+            Err(Error::multiple(errors))
+        }
+    }
+
     #[cfg(feature = "openid")]
     async fn store_nonce_by_csrf(
         &self,
@@ -647,12 +831,13 @@ impl MongoDbManager {
         Ok(entry)
     }
 
-    #[tracing::instrument(skip(self, name, version, yanked, no_changed_error_closure))]
+    #[tracing::instrument(skip(self, name, version, yanked, reason, no_changed_error_closure))]
     async fn change_yanked<F>(
         &self,
         name: &str,
         version: Version,
         yanked: bool,
+        reason: Option<String>,
         no_changed_error_closure: F,
     ) -> Result<(), Error>
     where
@@ -668,7 +853,7 @@ impl MongoDbManager {
                 if package.yanked == yanked {
                     Err(no_changed_error_closure(name.to_owned(), version))
                 } else {
-                    package.yanked = yanked;
+                    package.set_yanked(yanked, reason);
                     Ok(entry)
                 }
             })
@@ -700,6 +885,55 @@ impl MongoDbManager {
         insertion.map_err(Error::Db).await
     }
 
+    #[tracing::instrument(skip(self, user_id))]
+    async fn storage_usage_map(&self, user_id: u32) -> Result<Option<StorageUsageMap>, Error> {
+        let collection = self
+            .client
+            .database(&self.database_name)
+            .collection(STORAGE_USAGES_KEY);
+        collection
+            .find_one(doc! { "id": user_id }, None)
+            .map_err(Error::Db)
+            .await?
+            .map(from_document::<StorageUsageMap>)
+            .transpose()
+            .map_err(Error::BsonDeserialization)
+    }
+
+    #[tracing::instrument(skip(self, user_id, editor))]
+    async fn edit_storage_usage<E>(&self, user_id: u32, editor: E) -> Result<(), Error>
+    where
+        E: FnOnce(u64) -> u64 + Send,
+    {
+        let usage = self
+            .storage_usage_map(user_id)
+            .await?
+            .map(|m| m.usage)
+            .unwrap_or_default();
+        let usage_map = StorageUsageMap {
+            id: user_id,
+            usage: editor(usage),
+        };
+        self.update_or_insert_one(STORAGE_USAGES_KEY, doc! { "id": user_id }, usage_map)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, name))]
+    async fn remove_entry(&self, name: &str) -> Result<(), Error> {
+        let normalized_crate_name = normalized_crate_name(name);
+
+        let removal = async {
+            let db = self.client.database(&self.database_name);
+            let collection = db.collection(ENTRIES_KEY);
+            collection
+                .delete_one(doc! { "name": normalized_crate_name }, None)
+                .map_ok(drop)
+                .await
+        };
+
+        removal.map_err(Error::Db).await
+    }
+
     #[tracing::instrument(skip(self, collection_name, query, value))]
     async fn update_or_insert_one(
         &self,
@@ -721,4 +955,60 @@ impl MongoDbManager {
 
         insertion.map_err(Error::Db).await
     }
+
+    /// Rewrites the entries stored by the older schema versions so that every version
+    /// has the publish records, which are left empty because they are unknown,
+    /// and sums up the storage usages of the users.
+    #[tracing::instrument(skip(self))]
+    async fn migrate_entries(&self) -> Result<(), Error> {
+        let db = self.client.database(&self.database_name);
+        let collection = db.collection(SCHEMA_VERSION_KEY);
+
+        let schema_version_on_disk = collection
+            .find_one(None, None)
+            .map_err(Error::Db)
+            .await?
+            .and_then(|d| d.get_i64("version").ok());
+        match schema_version_on_disk {
+            Some(schema_version_on_disk) if schema_version_on_disk < SCHEMA_VERSION => {}
+            _ => return Ok(()),
+        }
+
+        tracing::info!(
+            "current schema version will migrate to {:?}.",
+            SCHEMA_VERSION
+        );
+
+        let entry_maps: Vec<EntryMap> = db
+            .collection(ENTRIES_KEY)
+            .find(None, None)
+            .map_err(Error::Db)
+            .await?
+            .map_err(Error::Db)
+            .and_then(|document| async {
+                from_document::<EntryMap>(document).map_err(Error::BsonDeserialization)
+            })
+            .try_collect()
+            .await?;
+        for entry_map in &entry_maps {
+            self.insert_entry(&entry_map.name, entry_map.entry.clone())
+                .await?;
+        }
+        let usages = storage_usages(entry_maps.iter().map(|entry_map| &entry_map.entry));
+        for (user_id, usage) in usages {
+            let usage_map = StorageUsageMap { id: user_id, usage };
+            self.update_or_insert_one(STORAGE_USAGES_KEY, doc! { "id": user_id }, usage_map)
+                .await?;
+        }
+
+        collection
+            .update_one(
+                doc! {},
+                doc! { "$set": { "version": SCHEMA_VERSION } },
+                None,
+            )
+            .map_ok(drop)
+            .map_err(Error::Db)
+            .await
+    }
 }
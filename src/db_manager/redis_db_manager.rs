@@ -2,28 +2,34 @@
 
 use crate::config::DbConfig;
 use crate::error::Error;
-use crate::models::{Entry, Metadata, Query, Search, User};
+use crate::models::{DeletionRecord, Deprecation, Entry, Metadata, Query, Search, User};
 use argon2::{self, hash_encoded, verify_encoded};
 use async_trait::async_trait;
+use chrono::NaiveDate;
 use futures::TryFutureExt;
-use redis::{AsyncCommands, Client};
+use redis::{AsyncCommands, Client, RedisError};
 use semver::Version;
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use std::collections::HashMap;
 
-use crate::db_manager::utils::{argon2_config_and_salt, check_crate_name, normalized_crate_name};
+use crate::db_manager::utils::{
+    argon2_config_and_salt, check_crate_name, normalized_crate_name, storage_usages,
+};
 use crate::db_manager::DbManager;
 
 type TokenMap = HashMap<u32, String>;
+type StorageUsageMap = HashMap<u32, u64>;
 
 const SCHEMA_VERSION_KEY: &str = "ktra:__SCHEMA_VERSION__";
-const SCHEMA_VERSION: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+const SCHEMA_VERSION: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 3];
 const ENTRIES_KEY: &str = "ktra:__ENTRIES__";
 const USERS_KEY: &str = "ktra:__USERS__";
 const PASSWORDS_KEY: &str = "ktra:__PASSWORDS__";
 const TOKENS_KEY: &str = "ktra:__TOKENS__";
 const OAUTH_NONCES_KEY: &str = "ktra:__OAUTH_NONCES__";
+const DELETIONS_KEY: &str = "ktra:__DELETIONS__";
+const STORAGE_USAGES_KEY: &str = "ktra:__STORAGE_USAGES__";
 
 pub struct RedisDbManager {
     client: Client,
@@ -41,7 +47,9 @@ impl DbManager for RedisDbManager {
             let mut connection = client.get_async_connection().await?;
 
             if !connection.exists(SCHEMA_VERSION_KEY).await? {
-                connection.set(SCHEMA_VERSION_KEY, &SCHEMA_VERSION).await?;
+                connection
+                    .set::<_, _, ()>(SCHEMA_VERSION_KEY, &SCHEMA_VERSION)
+                    .await?;
             }
 
             let db_manager = RedisDbManager {
@@ -51,7 +59,9 @@ impl DbManager for RedisDbManager {
             Ok(db_manager)
         };
 
-        initialization.map_err(Error::Db).await
+        let db_manager = initialization.map_err(Error::Db).await?;
+        db_manager.migrate_entries().await?;
+        Ok(db_manager)
     }
 
     async fn get_login_prefix(&self) -> Result<&str, Error> {
@@ -178,6 +188,15 @@ impl DbManager for RedisDbManager {
         self.insert(TOKENS_KEY, tokens).await
     }
 
+    #[tracing::instrument(skip(self, user_id))]
+    async fn user_by_id(&self, user_id: u32) -> Result<User, Error> {
+        let users: Vec<User> = self.deserialize(USERS_KEY).await?.unwrap_or_default();
+        users
+            .into_iter()
+            .find(|u| u.id == user_id)
+            .ok_or(Error::InvalidUser(user_id))
+    }
+
     #[tracing::instrument(skip(self, name))]
     async fn user_by_username(&self, name: &str) -> Result<User, Error> {
         let login = format!("{}{}", self.login_prefix, name);
@@ -311,8 +330,87 @@ impl DbManager for RedisDbManager {
             return Err(Error::InvalidUser(owner_id));
         }
 
+        let charged_user_id = entry.charged_user_id(&metadata);
+        let crate_size = metadata.crate_size;
         entry.versions_mut().insert(version, metadata);
-        self.insert_entry(&name, entry).await
+        self.insert_entry(&name, entry).await?;
+
+        if let (Some(user_id), Some(crate_size)) = (charged_user_id, crate_size) {
+            self.edit_storage_usage(user_id, |usage| usage.saturating_add(crate_size))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn remove_metadata(&self, name: &str, version: Version) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        let metadata = entry
+            .versions_mut()
+            .remove(&version)
+            .ok_or(Error::VersionNotFoundInDb(version))?;
+        let charged_user_id = entry.charged_user_id(&metadata);
+
+        // the crate is regarded as unpublished if no versions remain.
+        if entry.versions().is_empty() {
+            self.remove_entry(name).await?;
+        } else {
+            self.insert_entry(name, entry).await?;
+        }
+
+        if let (Some(user_id), Some(crate_size)) = (charged_user_id, metadata.crate_size) {
+            self.edit_storage_usage(user_id, |usage| usage.saturating_sub(crate_size))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, record))]
+    async fn add_deletion_record(&self, record: DeletionRecord) -> Result<(), Error> {
+        let mut records: Vec<DeletionRecord> =
+            self.deserialize(DELETIONS_KEY).await?.unwrap_or_default();
+        records.push(record);
+        self.insert(DELETIONS_KEY, records).await
+    }
+
+    #[tracing::instrument(skip(self, name))]
+    async fn crate_storage_usage(&self, name: &str) -> Result<u64, Error> {
+        self.entry(name).await.map(|entry| entry.storage_usage())
+    }
+
+    #[tracing::instrument(skip(self, user_id))]
+    async fn user_storage_usage(&self, user_id: u32) -> Result<u64, Error> {
+        let usages: StorageUsageMap = self
+            .deserialize(STORAGE_USAGES_KEY)
+            .await?
+            .unwrap_or_default();
+        Ok(usages.get(&user_id).copied().unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self, name, version, size))]
+    async fn set_crate_size(&self, name: &str, version: Version, size: u64) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        let (charged_user_id, old_size) = entry
+            .versions()
+            .get(&version)
+            .map(|metadata| (entry.charged_user_id(metadata), metadata.crate_size))
+            .ok_or_else(|| Error::VersionNotFoundInDb(version.clone()))?;
+        if let Some(metadata) = entry.package_mut(&version) {
+            metadata.crate_size = Some(size);
+        }
+        self.insert_entry(name, entry).await?;
+
+        if let Some(user_id) = charged_user_id {
+            self.edit_storage_usage(user_id, |usage| {
+                usage
+                    .saturating_sub(old_size.unwrap_or_default())
+                    .saturating_add(size)
+            })
+            .await?;
+        }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, user_id, name, version))]
@@ -342,18 +440,56 @@ impl DbManager for RedisDbManager {
         Ok(can_edit_package)
     }
 
-    #[tracing::instrument(skip(self, name, version))]
-    async fn yank(&self, name: &str, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, true, Error::AlreadyYanked)
+    #[tracing::instrument(skip(self, name, version, reason))]
+    async fn yank(
+        &self,
+        name: &str,
+        version: Version,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        self.change_yanked(name, version, true, reason, Error::AlreadyYanked)
             .await
     }
 
     #[tracing::instrument(skip(self, name, version))]
     async fn unyank(&self, name: &str, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, false, Error::NotYetYanked)
+        self.change_yanked(name, version, false, None, Error::NotYetYanked)
             .await
     }
 
+    #[tracing::instrument(skip(self, name, deprecation))]
+    async fn set_deprecation(
+        &self,
+        name: &str,
+        deprecation: Option<Deprecation>,
+    ) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        if entry.is_empty() {
+            return Err(Error::CrateNotFoundInDb(name.to_owned()));
+        }
+
+        entry.set_deprecation(deprecation);
+        self.insert_entry(name, entry).await
+    }
+
+    #[tracing::instrument(skip(self, name, version, date, count))]
+    async fn add_downloads(
+        &self,
+        name: &str,
+        version: Version,
+        date: NaiveDate,
+        count: u64,
+    ) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+        let metadata = entry
+            .package_mut(&version)
+            .ok_or(Error::VersionNotFoundInDb(version))?;
+
+        metadata.add_downloads(date, count);
+        self.insert_entry(name, entry).await
+    }
+
     #[tracing::instrument(skip(self, query))]
     async fn search(&self, query: &Query) -> Result<Search, Error> {
         let mut connection = self
@@ -383,7 +519,7 @@ impl DbManager for RedisDbManager {
                             .iter()
                             .filter(|(_, metadata)| !metadata.yanked)
                             .max_by_key(|(key, _)| *key)?;
-                        Some(latest_version.to_searched())
+                        Some(entry.to_searched(latest_version))
                     } else {
                         None
                     }
@@ -400,6 +536,33 @@ impl DbManager for RedisDbManager {
         }
     }
 
+    #[tracing::instrument(skip(self, name))]
+    async fn crate_entry(&self, name: &str) -> Result<Entry, Error> {
+        self.entry(name).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let mut connection = self
+            .client
+            .get_async_connection()
+            .map_err(Error::Db)
+            .await?;
+        let entries: Vec<String> = connection.hvals(ENTRIES_KEY).map_err(Error::Db).await?;
+        let (entries, errors): (Vec<_>, Vec<_>) = entries
+            .iter()
+            .map(|json_string| {
+                serde_json::from_str::<Entry>(json_string).map_err(Error::InvalidJson)
+            })
+            .partition(Result::is_ok);
+
+        if errors.is_empty() {
+            Ok(entries.into_iter().map(Result::unwrap).collect())
+        } else {
+            Err(Error::multiple(errors))
+        }
+    }
+
     #[cfg(feature = "openid")]
     async fn store_nonce_by_csrf(
         &self,
@@ -489,12 +652,13 @@ impl RedisDbManager {
         Ok(entry.unwrap_or_default())
     }
 
-    #[tracing::instrument(skip(self, name, version, yanked, no_changed_error_closure))]
+    #[tracing::instrument(skip(self, name, version, yanked, reason, no_changed_error_closure))]
     async fn change_yanked<F>(
         &self,
         name: &str,
         version: Version,
         yanked: bool,
+        reason: Option<String>,
         no_changed_error_closure: F,
     ) -> Result<(), Error>
     where
@@ -510,7 +674,7 @@ impl RedisDbManager {
                 if package.yanked == yanked {
                     Err(no_changed_error_closure(name.to_owned(), version))
                 } else {
-                    package.yanked = yanked;
+                    package.set_yanked(yanked, reason);
                     Ok(entry)
                 }
             })
@@ -544,24 +708,95 @@ impl RedisDbManager {
         let insertion = async {
             let mut connection = self.client.get_async_connection().await?;
             connection
-                .hset(ENTRIES_KEY, normalized_crate_name, json_string)
+                .hset::<_, _, _, ()>(ENTRIES_KEY, normalized_crate_name, json_string)
                 .await?;
-            Ok(())
+            Ok::<(), RedisError>(())
         };
 
         insertion.map_err(Error::Db).await
     }
 
+    #[tracing::instrument(skip(self, user_id, editor))]
+    async fn edit_storage_usage<E>(&self, user_id: u32, editor: E) -> Result<(), Error>
+    where
+        E: FnOnce(u64) -> u64 + Send,
+    {
+        let mut usages: StorageUsageMap = self
+            .deserialize(STORAGE_USAGES_KEY)
+            .await?
+            .unwrap_or_default();
+        let usage = usages.entry(user_id).or_default();
+        *usage = editor(*usage);
+        self.insert(STORAGE_USAGES_KEY, usages).await
+    }
+
+    #[tracing::instrument(skip(self, name))]
+    async fn remove_entry(&self, name: &str) -> Result<(), Error> {
+        let normalized_crate_name = normalized_crate_name(name);
+
+        let removal = async {
+            let mut connection = self.client.get_async_connection().await?;
+            connection
+                .hdel::<_, _, ()>(ENTRIES_KEY, normalized_crate_name)
+                .await?;
+            Ok::<(), RedisError>(())
+        };
+
+        removal.map_err(Error::Db).await
+    }
+
     #[tracing::instrument(skip(self, key, value))]
     async fn insert(&self, key: &str, value: impl Serialize) -> Result<(), Error> {
         let json_string = serde_json::to_string(&value).map_err(Error::Serialization)?;
 
         let insertion = async {
             let mut connection = self.client.get_async_connection().await?;
-            connection.set(key, json_string).await?;
-            Ok(())
+            connection.set::<_, _, ()>(key, json_string).await?;
+            Ok::<(), RedisError>(())
         };
 
         insertion.map_err(Error::Db).await
     }
+
+    /// Rewrites the entries stored by the older schema versions so that every version
+    /// has the publish records, which are left empty because they are unknown,
+    /// and sums up the storage usages of the users.
+    #[tracing::instrument(skip(self))]
+    async fn migrate_entries(&self) -> Result<(), Error> {
+        let mut connection = self
+            .client
+            .get_async_connection()
+            .map_err(Error::Db)
+            .await?;
+
+        let schema_version_on_disk: Option<Vec<u8>> = connection
+            .get(SCHEMA_VERSION_KEY)
+            .map_err(Error::Db)
+            .await?;
+        match schema_version_on_disk {
+            Some(schema_version_on_disk) if schema_version_on_disk[..] < SCHEMA_VERSION[..] => {}
+            _ => return Ok(()),
+        }
+
+        tracing::info!(
+            "current schema version will migrate to {:?}.",
+            SCHEMA_VERSION
+        );
+
+        let entries: HashMap<String, String> =
+            connection.hgetall(ENTRIES_KEY).map_err(Error::Db).await?;
+        let mut migrated_entries = Vec::new();
+        for (name, json_string) in entries {
+            let entry: Entry = serde_json::from_str(&json_string).map_err(Error::InvalidJson)?;
+            self.insert_entry(&name, entry.clone()).await?;
+            migrated_entries.push(entry);
+        }
+        self.insert(STORAGE_USAGES_KEY, storage_usages(&migrated_entries))
+            .await?;
+
+        connection
+            .set(SCHEMA_VERSION_KEY, &SCHEMA_VERSION)
+            .map_err(Error::Db)
+            .await
+    }
 }
@@ -2,9 +2,10 @@
 
 use crate::config::DbConfig;
 use crate::error::Error;
-use crate::models::{Entry, Metadata, Query, Search, User};
+use crate::models::{DeletionRecord, Deprecation, Entry, Metadata, Query, Search, User};
 use argon2::{self, hash_encoded, verify_encoded};
 use async_trait::async_trait;
+use chrono::NaiveDate;
 use futures::TryFutureExt;
 use semver::Version;
 use serde::de::DeserializeOwned;
@@ -12,17 +13,22 @@ use serde::ser::Serialize;
 use sled::{self, Db};
 use std::collections::HashMap;
 
-use crate::db_manager::utils::{argon2_config_and_salt, check_crate_name, normalized_crate_name};
+use crate::db_manager::utils::{
+    argon2_config_and_salt, check_crate_name, normalized_crate_name, storage_usages,
+};
 use crate::db_manager::DbManager;
 
 type TokenMap = HashMap<u32, String>;
+type StorageUsageMap = HashMap<u32, u64>;
 
 const SCHEMA_VERSION_KEY: &str = "__SCHEMA_VERSION__";
-const SCHEMA_VERSION: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 3];
+const SCHEMA_VERSION: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 5];
 const USERS_KEY: &str = "__USERS__";
 const PASSWORDS_KEY: &str = "__PASSWORDS__";
 const TOKENS_KEY: &str = "__TOKENS__";
 const OAUTH_NONCES_KEY: &str = "__OAUTH_NONCES__";
+const DELETIONS_KEY: &str = "__DELETIONS__";
+const STORAGE_USAGES_KEY: &str = "__STORAGE_USAGES__";
 
 const OLD_TOKENS_KEY: &str = "tokens";
 
@@ -42,6 +48,7 @@ impl DbManager for SledDbManager {
             .map_err(Error::Join)
             .await??;
         Self::migrate_tokens(&tree).await?;
+        Self::migrate_entries(&tree).await?;
 
         if !tree.contains_key(SCHEMA_VERSION_KEY).map_err(Error::Db)? {
             tree.insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION)
@@ -186,6 +193,15 @@ impl DbManager for SledDbManager {
         Ok(users.remove(index))
     }
 
+    #[tracing::instrument(skip(self, user_id))]
+    async fn user_by_id(&self, user_id: u32) -> Result<User, Error> {
+        let users: Vec<User> = self.deserialize(USERS_KEY)?.unwrap_or_default();
+        users
+            .into_iter()
+            .find(|u| u.id == user_id)
+            .ok_or(Error::InvalidUser(user_id))
+    }
+
     #[tracing::instrument(skip(self, name))]
     async fn user_by_username(&self, name: &str) -> Result<User, Error> {
         let login = format!("{}{}", self.login_prefix, name);
@@ -306,8 +322,83 @@ impl DbManager for SledDbManager {
             return Err(Error::InvalidUser(owner_id));
         }
 
+        let charged_user_id = entry.charged_user_id(&metadata);
+        let crate_size = metadata.crate_size;
         entry.versions_mut().insert(version, metadata);
-        self.insert_entry(&name, entry).await
+        self.insert_entry(&name, entry).await?;
+
+        if let (Some(user_id), Some(crate_size)) = (charged_user_id, crate_size) {
+            self.edit_storage_usage(user_id, |usage| usage.saturating_add(crate_size))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn remove_metadata(&self, name: &str, version: Version) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        let metadata = entry
+            .versions_mut()
+            .remove(&version)
+            .ok_or(Error::VersionNotFoundInDb(version))?;
+        let charged_user_id = entry.charged_user_id(&metadata);
+
+        // the crate is regarded as unpublished if no versions remain.
+        if entry.versions().is_empty() {
+            self.remove_entry(name).await?;
+        } else {
+            self.insert_entry(name, entry).await?;
+        }
+
+        if let (Some(user_id), Some(crate_size)) = (charged_user_id, metadata.crate_size) {
+            self.edit_storage_usage(user_id, |usage| usage.saturating_sub(crate_size))
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, record))]
+    async fn add_deletion_record(&self, record: DeletionRecord) -> Result<(), Error> {
+        let mut records: Vec<DeletionRecord> = self.deserialize(DELETIONS_KEY)?.unwrap_or_default();
+        records.push(record);
+        self.insert(DELETIONS_KEY, records).await
+    }
+
+    #[tracing::instrument(skip(self, name))]
+    async fn crate_storage_usage(&self, name: &str) -> Result<u64, Error> {
+        self.entry(name).await.map(|entry| entry.storage_usage())
+    }
+
+    #[tracing::instrument(skip(self, user_id))]
+    async fn user_storage_usage(&self, user_id: u32) -> Result<u64, Error> {
+        let usages: StorageUsageMap = self.deserialize(STORAGE_USAGES_KEY)?.unwrap_or_default();
+        Ok(usages.get(&user_id).copied().unwrap_or_default())
+    }
+
+    #[tracing::instrument(skip(self, name, version, size))]
+    async fn set_crate_size(&self, name: &str, version: Version, size: u64) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        let (charged_user_id, old_size) = entry
+            .versions()
+            .get(&version)
+            .map(|metadata| (entry.charged_user_id(metadata), metadata.crate_size))
+            .ok_or_else(|| Error::VersionNotFoundInDb(version.clone()))?;
+        if let Some(metadata) = entry.package_mut(&version) {
+            metadata.crate_size = Some(size);
+        }
+        self.insert_entry(name, entry).await?;
+
+        if let Some(user_id) = charged_user_id {
+            self.edit_storage_usage(user_id, |usage| {
+                usage
+                    .saturating_sub(old_size.unwrap_or_default())
+                    .saturating_add(size)
+            })
+            .await?;
+        }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, user_id, name, version))]
@@ -337,18 +428,56 @@ impl DbManager for SledDbManager {
         Ok(can_edit_package)
     }
 
-    #[tracing::instrument(skip(self, name, version))]
-    async fn yank(&self, name: &str, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, true, Error::AlreadyYanked)
+    #[tracing::instrument(skip(self, name, version, reason))]
+    async fn yank(
+        &self,
+        name: &str,
+        version: Version,
+        reason: Option<String>,
+    ) -> Result<(), Error> {
+        self.change_yanked(name, version, true, reason, Error::AlreadyYanked)
             .await
     }
 
     #[tracing::instrument(skip(self, name, version))]
     async fn unyank(&self, name: &str, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, false, Error::NotYetYanked)
+        self.change_yanked(name, version, false, None, Error::NotYetYanked)
             .await
     }
 
+    #[tracing::instrument(skip(self, name, deprecation))]
+    async fn set_deprecation(
+        &self,
+        name: &str,
+        deprecation: Option<Deprecation>,
+    ) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+
+        if entry.is_empty() {
+            return Err(Error::CrateNotFoundInDb(name.to_owned()));
+        }
+
+        entry.set_deprecation(deprecation);
+        self.insert_entry(name, entry).await
+    }
+
+    #[tracing::instrument(skip(self, name, version, date, count))]
+    async fn add_downloads(
+        &self,
+        name: &str,
+        version: Version,
+        date: NaiveDate,
+        count: u64,
+    ) -> Result<(), Error> {
+        let mut entry = self.entry(name).await?;
+        let metadata = entry
+            .package_mut(&version)
+            .ok_or(Error::VersionNotFoundInDb(version))?;
+
+        metadata.add_downloads(date, count);
+        self.insert_entry(name, entry).await
+    }
+
     #[tracing::instrument(skip(self, query))]
     async fn search(&self, query: &Query) -> Result<Search, Error> {
         let query_string = normalized_crate_name(&query.string);
@@ -362,10 +491,9 @@ impl DbManager for SledDbManager {
                         // the keys in ktra db must be valid UTF-8 string so ignore any validation errors.
                         let key = std::str::from_utf8(&key).ok()?;
 
-                        let condition = key != USERS_KEY
-                            && key != SCHEMA_VERSION_KEY
-                            && key != PASSWORDS_KEY
-                            && key != TOKENS_KEY
+                        // the other keys than crate names are surrounded with double underscores.
+                        let condition = !key.starts_with("__")
+                            && key != OLD_TOKENS_KEY
                             && key.contains(&query_string);
 
                         if condition {
@@ -378,7 +506,7 @@ impl DbManager for SledDbManager {
                                         .iter()
                                         .filter(|(_, metadata)| !metadata.yanked)
                                         .max_by_key(|(key, _)| *key)?;
-                                    Some(Ok(latest_version.to_searched()))
+                                    Some(Ok(entry.to_searched(latest_version)))
                                 }
                                 Err(e) => Some(Err(e)),
                             }
@@ -405,6 +533,39 @@ impl DbManager for SledDbManager {
         }
     }
 
+    #[tracing::instrument(skip(self, name))]
+    async fn crate_entry(&self, name: &str) -> Result<Entry, Error> {
+        self.entry(name).await
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let (entries, errors): (Vec<_>, Vec<_>) = self
+            .tree
+            .iter()
+            .filter_map(|result| match result {
+                Ok((key, value)) => {
+                    // the keys in ktra db must be valid UTF-8 string so ignore any validation errors.
+                    let key = std::str::from_utf8(&key).ok()?;
+
+                    // the other keys than crate names are surrounded with double underscores.
+                    if key.starts_with("__") || key == OLD_TOKENS_KEY {
+                        None
+                    } else {
+                        Some(serde_json::from_slice::<Entry>(&value).map_err(Error::InvalidJson))
+                    }
+                }
+                Err(e) => Some(Err(Error::Db(e))),
+            })
+            .partition(Result::is_ok);
+
+        if errors.is_empty() {
+            Ok(entries.into_iter().map(Result::unwrap).collect())
+        } else {
+            Err(Error::multiple(errors))
+        }
+    }
+
     #[cfg(feature = "openid")]
     async fn store_nonce_by_csrf(
         &self,
@@ -476,12 +637,13 @@ impl SledDbManager {
         self.deserialize(&name).map(Option::unwrap_or_default)
     }
 
-    #[tracing::instrument(skip(self, name, version, yanked, no_changed_error_closure))]
+    #[tracing::instrument(skip(self, name, version, yanked, reason, no_changed_error_closure))]
     async fn change_yanked<F>(
         &self,
         name: &str,
         version: Version,
         yanked: bool,
+        reason: Option<String>,
         no_changed_error_closure: F,
     ) -> Result<(), Error>
     where
@@ -497,7 +659,7 @@ impl SledDbManager {
                 if package.yanked == yanked {
                     Err(no_changed_error_closure(name.to_owned(), version))
                 } else {
-                    package.yanked = yanked;
+                    package.set_yanked(yanked, reason);
                     Ok(entry)
                 }
             })
@@ -528,6 +690,30 @@ impl SledDbManager {
         self.insert(normalized_crate_name(&name), entry).await
     }
 
+    #[tracing::instrument(skip(self, user_id, editor))]
+    async fn edit_storage_usage<E>(&self, user_id: u32, editor: E) -> Result<(), Error>
+    where
+        E: FnOnce(u64) -> u64 + Send,
+    {
+        let mut usages: StorageUsageMap = self.deserialize(STORAGE_USAGES_KEY)?.unwrap_or_default();
+        let usage = usages.entry(user_id).or_default();
+        *usage = editor(*usage);
+        self.insert(STORAGE_USAGES_KEY, usages).await
+    }
+
+    #[tracing::instrument(skip(self, name))]
+    async fn remove_entry(&self, name: &str) -> Result<(), Error> {
+        self.tree
+            .remove(normalized_crate_name(name))
+            .map(drop)
+            .map_err(Error::Db)?;
+        self.tree
+            .flush_async()
+            .map_ok(drop)
+            .map_err(Error::Db)
+            .await
+    }
+
     #[tracing::instrument(skip(self, key, value))]
     async fn insert(&self, key: impl AsRef<[u8]>, value: impl Serialize) -> Result<(), Error> {
         let json_string = serde_json::to_string(&value).map_err(Error::Serialization)?;
@@ -544,12 +730,7 @@ impl SledDbManager {
 
     #[tracing::instrument(skip(tree))]
     async fn migrate_tokens(tree: &Db) -> Result<(), Error> {
-        let schema_version_on_disk: Option<[u8; 8]> =
-            tree.get(SCHEMA_VERSION_KEY).map_err(Error::Db)?.map(|v| {
-                let mut buf: [u8; 8] = [0u8; 8];
-                buf.clone_from_slice(&v);
-                buf
-            });
+        let schema_version_on_disk = Self::schema_version_on_disk(tree)?;
         let tokens = tree.get(OLD_TOKENS_KEY).map_err(Error::Db)?;
 
         if schema_version_on_disk.is_none() && tokens.is_some() {
@@ -576,4 +757,62 @@ impl SledDbManager {
             Ok(())
         }
     }
+
+    #[tracing::instrument(skip(tree))]
+    fn schema_version_on_disk(tree: &Db) -> Result<Option<[u8; 8]>, Error> {
+        let schema_version_on_disk = tree.get(SCHEMA_VERSION_KEY).map_err(Error::Db)?.map(|v| {
+            let mut buf: [u8; 8] = [0u8; 8];
+            buf.clone_from_slice(&v);
+            buf
+        });
+        Ok(schema_version_on_disk)
+    }
+
+    /// Rewrites the entries stored by the older schema versions so that every version
+    /// has the publish records, which are left empty because they are unknown,
+    /// and sums up the storage usages of the users.
+    #[tracing::instrument(skip(tree))]
+    async fn migrate_entries(tree: &Db) -> Result<(), Error> {
+        match Self::schema_version_on_disk(tree)? {
+            Some(schema_version_on_disk) if schema_version_on_disk < SCHEMA_VERSION => {}
+            _ => return Ok(()),
+        }
+
+        tracing::info!(
+            "current schema version will migrate to {:?}.",
+            SCHEMA_VERSION
+        );
+
+        let keys = tree
+            .iter()
+            .keys()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::Db)?;
+        let mut entries = Vec::new();
+        for key in keys {
+            match std::str::from_utf8(&key) {
+                Ok(key) if !key.starts_with("__") && key != OLD_TOKENS_KEY => {}
+                _ => continue,
+            }
+
+            if let Some(value) = tree.get(&key).map_err(Error::Db)? {
+                let entry: Entry = serde_json::from_slice(&value).map_err(Error::InvalidJson)?;
+                let json_string = serde_json::to_string(&entry).map_err(Error::Serialization)?;
+                tree.insert(key, json_string.as_str())
+                    .map(drop)
+                    .map_err(Error::Db)?;
+                entries.push(entry);
+            }
+        }
+
+        let usages =
+            serde_json::to_string(&storage_usages(&entries)).map_err(Error::Serialization)?;
+        tree.insert(STORAGE_USAGES_KEY, usages.as_str())
+            .map(drop)
+            .map_err(Error::Db)?;
+        tree.insert(SCHEMA_VERSION_KEY, &SCHEMA_VERSION)
+            .map(drop)
+            .map_err(Error::Db)?;
+        tree.flush_async().map_ok(drop).map_err(Error::Db).await
+    }
 }
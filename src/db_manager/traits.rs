@@ -1,7 +1,8 @@
 use crate::config::DbConfig;
 use crate::error::Error;
-use crate::models::{Metadata, Query, Search, User};
+use crate::models::{DeletionRecord, Deprecation, Entry, Metadata, Query, Search, User};
 use async_trait::async_trait;
+use chrono::NaiveDate;
 use semver::Version;
 
 #[async_trait]
@@ -19,6 +20,7 @@ pub trait DbManager: Send + Sync + Sized {
     async fn token_by_login(&self, login: &str) -> Result<Option<String>, Error>;
     async fn token_by_username(&self, name: &str) -> Result<Option<String>, Error>;
     async fn set_token(&self, user_id: u32, token: &str) -> Result<(), Error>;
+    async fn user_by_id(&self, user_id: u32) -> Result<User, Error>;
     async fn user_by_username(&self, name: &str) -> Result<User, Error>;
     async fn user_by_login(&self, login: &str) -> Result<User, Error>;
     async fn add_new_user(&self, user: User, password: &str) -> Result<(), Error>;
@@ -37,6 +39,15 @@ pub trait DbManager: Send + Sync + Sized {
         version: Version,
     ) -> Result<bool, Error>;
     async fn add_new_metadata(&self, owner_id: u32, metadata: Metadata) -> Result<(), Error>;
+    async fn remove_metadata(&self, name: &str, version: Version) -> Result<(), Error>;
+    async fn add_deletion_record(&self, record: DeletionRecord) -> Result<(), Error>;
+
+    /// Returns the total size of the crate files of the crate.
+    async fn crate_storage_usage(&self, name: &str) -> Result<u64, Error>;
+    /// Returns the total size of the crate files the user publishes.
+    async fn user_storage_usage(&self, user_id: u32) -> Result<u64, Error>;
+    /// Records the size of the crate file of the version published before the sizes are recorded.
+    async fn set_crate_size(&self, name: &str, version: Version, size: u64) -> Result<(), Error>;
 
     async fn can_edit_package(
         &self,
@@ -44,10 +55,28 @@ pub trait DbManager: Send + Sync + Sized {
         name: &str,
         version: Version,
     ) -> Result<bool, Error>;
-    async fn yank(&self, name: &str, version: Version) -> Result<(), Error>;
+    async fn yank(&self, name: &str, version: Version, reason: Option<String>)
+        -> Result<(), Error>;
     async fn unyank(&self, name: &str, version: Version) -> Result<(), Error>;
+    /// Marks the crate as deprecated in favor of the successor if any, or clears the mark.
+    async fn set_deprecation(
+        &self,
+        name: &str,
+        deprecation: Option<Deprecation>,
+    ) -> Result<(), Error>;
+    /// Adds the downloads of the version on the date.
+    async fn add_downloads(
+        &self,
+        name: &str,
+        version: Version,
+        date: NaiveDate,
+        count: u64,
+    ) -> Result<(), Error>;
 
     async fn search(&self, query: &Query) -> Result<Search, Error>;
+    /// Returns the entry of the crate, which is empty if the crate is not published.
+    async fn crate_entry(&self, name: &str) -> Result<Entry, Error>;
+    async fn entries(&self) -> Result<Vec<Entry>, Error>;
 
     /// Store a nonce associated to a CsrfToken. A single entry is allowed per CsrfToken
     #[cfg(feature = "openid")]
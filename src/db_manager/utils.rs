@@ -1,6 +1,8 @@
 use crate::error::Error;
+use crate::models::Entry;
 use crate::utils::random_alphanumeric_string;
 use argon2::{self, ThreadMode, Variant};
+use std::collections::HashMap;
 
 const WINDOWS_NG_FILENAMES: &[&str] = &[
     "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
@@ -34,6 +36,22 @@ pub fn check_crate_name(name: &str) -> Result<(), Error> {
     }
 }
 
+/// Sums up the sizes of the crate files each user publishes.
+#[tracing::instrument(skip(entries))]
+pub fn storage_usages<'a>(entries: impl IntoIterator<Item = &'a Entry>) -> HashMap<u32, u64> {
+    let mut usages = HashMap::new();
+    for entry in entries {
+        for metadata in entry.versions().values() {
+            if let (Some(user_id), Some(crate_size)) =
+                (entry.charged_user_id(metadata), metadata.crate_size)
+            {
+                *usages.entry(user_id).or_default() += crate_size;
+            }
+        }
+    }
+    usages
+}
+
 #[tracing::instrument]
 pub async fn argon2_config_and_salt<'a>() -> Result<(argon2::Config<'a>, String), Error> {
     let config = argon2::Config {
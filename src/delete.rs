@@ -1,10 +1,12 @@
+use crate::config::AdminConfig;
 use crate::db_manager::DbManager;
 use crate::error::Error;
 use crate::index_manager::IndexManager;
-use crate::models::Owners;
+use crate::models::{Deletion, DeletionRecord, Metadata, Owners, YankQuery};
+use crate::storage_manager::StorageManager;
 use crate::utils::{
-    authorization_header, ok_json_message, ok_with_msg_json_message, with_db_manager,
-    with_index_manager,
+    authorization_header, check_yanked_changeable, ok_json_message, ok_with_msg_json_message,
+    with_admin_config, with_db_manager, with_index_manager, with_storage_manager,
 };
 use futures::TryFutureExt;
 use semver::Version;
@@ -12,12 +14,22 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 use warp::{Filter, Rejection, Reply};
 
-#[tracing::instrument(skip(db_manager, index_manager))]
+#[tracing::instrument(skip(db_manager, index_manager, storage_manager, admin_config))]
 pub fn apis(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
+    admin_config: Arc<AdminConfig>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    yank(db_manager.clone(), index_manager).or(owners(db_manager))
+    yank(db_manager.clone(), index_manager.clone())
+        .or(owners(db_manager.clone()))
+        .or(deprecation(db_manager.clone()))
+        .or(version(
+            db_manager,
+            index_manager,
+            storage_manager,
+            admin_config,
+        ))
 }
 
 #[tracing::instrument(skip(db_manager, index_manager))]
@@ -32,26 +44,31 @@ fn yank(
         .and(warp::path!(
             "api" / "v1" / "crates" / String / Version / "yank"
         ))
+        // cargo sends no reason, which is only given by the other clients.
+        .and(warp::query::<YankQuery>())
         .and_then(handle_yank)
 }
 
-#[tracing::instrument(skip(db_manager, index_manager, token, crate_name, version))]
+#[tracing::instrument(skip(db_manager, index_manager, token, crate_name, version, query))]
 async fn handle_yank(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
     token: String,
     crate_name: String,
     version: Version,
+    query: YankQuery,
 ) -> Result<impl Reply, Rejection> {
-    let db_manager = db_manager.write().await;
-
     let user_id = db_manager
+        .read()
+        .await
         .user_id_for_token(&token)
         .map_err(warp::reject::custom)
         .await?;
 
     let crate_name_cloned = crate_name.clone();
     db_manager
+        .read()
+        .await
         .can_edit_package(user_id, &crate_name, version.clone())
         .and_then(|editable| async move {
             if editable {
@@ -63,13 +80,21 @@ async fn handle_yank(
         .map_err(warp::reject::custom)
         .await?;
 
+    // the crate lock, instead of the database lock, keeps the concurrent yanks and unyanks
+    // of the crate in order so that the index edits of the other crates can be committed together.
+    let _crate_lock = index_manager.lock_crate(&crate_name).await;
+    check_yanked_changeable(&*db_manager.read().await, &crate_name, &version, true)
+        .map_err(warp::reject::custom)
+        .await?;
     index_manager
         .yank(&crate_name, version.clone())
         .map_err(warp::reject::custom)
         .await?;
 
     db_manager
-        .yank(&crate_name, version)
+        .write()
+        .await
+        .yank(&crate_name, version, query.reason)
         .map_ok(ok_json_message)
         .map_err(warp::reject::custom)
         .await
@@ -116,3 +141,291 @@ async fn handle_owners(
         .map_err(warp::reject::custom)
         .await
 }
+
+#[tracing::instrument(skip(db_manager))]
+fn deprecation(
+    db_manager: Arc<RwLock<impl DbManager>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::delete()
+        .and(with_db_manager(db_manager))
+        .and(authorization_header())
+        .and(warp::path!("api" / "v1" / "crates" / String / "deprecate"))
+        .and_then(handle_deprecation)
+}
+
+#[tracing::instrument(skip(db_manager, token, name))]
+async fn handle_deprecation(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    token: String,
+    name: String,
+) -> Result<impl Reply, Rejection> {
+    let db_manager = db_manager.write().await;
+
+    let user_id = db_manager
+        .user_id_for_token(&token)
+        .map_err(warp::reject::custom)
+        .await?;
+    db_manager
+        .can_edit_owners(user_id, &name)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    db_manager
+        .set_deprecation(&name, None)
+        .map_ok(ok_json_message)
+        .map_err(warp::reject::custom)
+        .await
+}
+
+#[tracing::instrument(skip(db_manager, index_manager, storage_manager, admin_config))]
+fn version(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
+    admin_config: Arc<AdminConfig>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::delete()
+        .and(with_db_manager(db_manager))
+        .and(with_index_manager(index_manager))
+        .and(with_storage_manager(storage_manager))
+        .and(with_admin_config(admin_config))
+        .and(authorization_header())
+        .and(warp::path!("api" / "v1" / "crates" / String / Version))
+        .and(warp::body::json::<Deletion>())
+        .and_then(handle_version)
+}
+
+#[tracing::instrument(skip(
+    db_manager,
+    index_manager,
+    storage_manager,
+    admin_config,
+    token,
+    crate_name,
+    version,
+    deletion
+))]
+#[allow(clippy::too_many_arguments)]
+async fn handle_version(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
+    admin_config: Arc<AdminConfig>,
+    token: String,
+    crate_name: String,
+    version: Version,
+    deletion: Deletion,
+) -> Result<impl Reply, Rejection> {
+    let db_manager = db_manager.write().await;
+
+    let user = db_manager
+        .user_id_for_token(&token)
+        .and_then(|user_id| db_manager.user_by_id(user_id))
+        .map_err(warp::reject::custom)
+        .await?;
+
+    if !admin_config.is_admin(&user.login) {
+        return Err(warp::reject::custom(Error::NotAdmin(user.login)));
+    }
+
+    let record = DeletionRecord::new(crate_name, version, user.login, deletion.reason);
+    delete_version(&*db_manager, &index_manager, &*storage_manager, record)
+        .map_ok(ok_json_message)
+        .map_err(warp::reject::custom)
+        .await
+}
+
+/// Removes the version from the index, stores the deletion record in the database,
+/// and then removes the version from the database and the crate files.
+///
+/// Unlike yanking, the version cannot be restored, so this is only for the versions
+/// which must not be distributed at all, e.g. the ones containing secrets.
+#[tracing::instrument(skip(db_manager, index_manager, storage_manager, record))]
+pub async fn delete_version(
+    db_manager: &impl DbManager,
+    index_manager: &IndexManager,
+    storage_manager: &impl StorageManager,
+    mut record: DeletionRecord,
+) -> Result<(), Error> {
+    let entry = db_manager.crate_entry(&record.name).await?;
+    if entry.is_empty() {
+        return Err(Error::CrateNotFoundInDb(record.name));
+    }
+    let metadata = entry
+        .versions()
+        .get(&record.vers)
+        .cloned()
+        .ok_or_else(|| Error::VersionNotFoundInDb(record.vers.clone()))?;
+
+    // the crate files are stored by the name the crate is published with.
+    record.name = metadata.name.clone();
+    let name = record.name.clone();
+    let version = record.vers.clone();
+
+    // the index is updated first so that cargo stops resolving the version as soon as possible,
+    // and the deletion record is stored before the metadata is removed
+    // so that no version disappears from the database without its record.
+    index_manager.remove_package(record.clone()).await?;
+    if let Err(e) = db_manager.add_deletion_record(record).await {
+        restore_package(index_manager, storage_manager, &metadata).await;
+        return Err(e);
+    }
+    if let Err(e) = db_manager.remove_metadata(&name, version.clone()).await {
+        tracing::error!(
+            "the deletion record of {}#{} is left although the version is not deleted",
+            name,
+            version
+        );
+        restore_package(index_manager, storage_manager, &metadata).await;
+        return Err(e);
+    }
+
+    if storage_manager.exists(&name, &version).await? {
+        storage_manager.delete(&name, &version).await
+    } else {
+        tracing::warn!("the crate file of {}#{} is already removed", name, version);
+        Ok(())
+    }
+}
+
+/// Adds the version removed from the index back to roll back the deletion.
+/// Failures are only logged because this is called while rolling back the other error.
+#[tracing::instrument(skip(index_manager, storage_manager, metadata))]
+async fn restore_package(
+    index_manager: &IndexManager,
+    storage_manager: &impl StorageManager,
+    metadata: &Metadata,
+) {
+    tracing::error!(
+        "failed to update the database so roll back the deletion of {}#{}",
+        metadata.name,
+        metadata.vers
+    );
+
+    // the versions published by the older versions have no checksum in the database.
+    let checksum = match &metadata.cksum {
+        Some(checksum) => Ok(Some(checksum.clone())),
+        None => {
+            storage_manager
+                .checksum(&metadata.name, &metadata.vers)
+                .await
+        }
+    };
+    let result = match checksum {
+        Ok(Some(checksum)) => {
+            index_manager
+                .add_package(metadata.to_package(checksum))
+                .await
+        }
+        Ok(None) => {
+            tracing::error!(
+                "failed to add {}#{} back to the index because the crate file does not exist",
+                metadata.name,
+                metadata.vers
+            );
+            return;
+        }
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        tracing::error!(
+            "failed to add {}#{} back to the index: {}",
+            metadata.name,
+            metadata.vers,
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_yanks_keep_the_index_and_the_database_consistent() {
+        use super::yank;
+        use crate::config::{CrateFilesConfig, PolicyConfig, PublishConfig};
+        use crate::db_manager::DbManager;
+        use crate::index_manager::IndexManager;
+        use crate::policy::Policy;
+        use crate::storage_manager::{FsStorageManager, StorageManager};
+        use crate::test_utils;
+        use semver::Version;
+        use std::sync::Arc;
+        use warp::Filter;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (user_id, token) = test_utils::add_user(&db_manager, "alice").await;
+        let index_manager = IndexManager::new(test_utils::index_config(dir.path()), &[])
+            .await
+            .unwrap();
+        let index_manager = Arc::new(index_manager);
+        let metadata = test_utils::metadata("foo", "0.1.0");
+        index_manager
+            .add_package(metadata.to_package("abc"))
+            .await
+            .unwrap();
+        db_manager
+            .write()
+            .await
+            .add_new_metadata(user_id, metadata)
+            .await
+            .unwrap();
+
+        let crate_files_config = CrateFilesConfig {
+            dl_dir_path: dir.path().join("crates"),
+            ..Default::default()
+        };
+        let storage_manager = FsStorageManager::new(&crate_files_config).await.unwrap();
+        let routes = yank(db_manager.clone(), index_manager.clone())
+            .or(crate::put::apis(
+                db_manager.clone(),
+                index_manager.clone(),
+                Arc::new(storage_manager),
+                Arc::new(crate_files_config.dl_dir_path),
+                Arc::new(PublishConfig::default()),
+                Arc::new(Policy::new(&PolicyConfig::default()).unwrap()),
+            ))
+            .recover(crate::handle_rejection);
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Authorization", &token)
+                .reply(&routes)
+        };
+
+        for _ in 0..5 {
+            futures::join!(
+                request("DELETE", "/api/v1/crates/foo/0.1.0/yank"),
+                request("PUT", "/api/v1/crates/foo/0.1.0/unyank")
+            );
+
+            let entry = db_manager.read().await.crate_entry("foo").await.unwrap();
+            let packages = index_manager.packages().await.unwrap();
+            assert_eq!(
+                entry.versions()[&Version::new(0, 1, 0)].yanked,
+                packages[0].yanked
+            );
+        }
+
+        // the repeated yank is rejected before the index is committed.
+        request("DELETE", "/api/v1/crates/foo/0.1.0/yank").await;
+        let head = || {
+            git2::Repository::open_bare(dir.path().join("index.git"))
+                .unwrap()
+                .head()
+                .unwrap()
+                .target()
+        };
+        let pushed = head();
+        let response = request("DELETE", "/api/v1/crates/foo/0.1.0/yank").await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["errors"][0]["detail"]
+            .as_str()
+            .unwrap()
+            .contains("already been marked as yanked"));
+        assert_eq!(head(), pushed);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::db_manager::DbManager;
+use chrono::{NaiveDate, Utc};
+use semver::Version;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+
+/// Counts the downloads in memory and stores them into the database in batches
+/// so that a download does not wait for the write lock of the database.
+///
+/// The counts which are not stored yet are lost when the server stops.
+#[derive(Debug, Default)]
+pub struct DownloadCounter {
+    counts: Mutex<HashMap<(String, Version, NaiveDate), u64>>,
+}
+
+impl DownloadCounter {
+    #[tracing::instrument]
+    pub fn new() -> DownloadCounter {
+        Default::default()
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    pub async fn increment(&self, name: &str, version: &Version) {
+        let key = (name.to_owned(), version.clone(), Utc::now().date_naive());
+        *self.counts.lock().await.entry(key).or_default() += 1;
+    }
+
+    /// Stores the counts into the database and resets them.
+    ///
+    /// The counts which cannot be stored, e.g. the ones of the deleted versions, are dropped
+    /// because they are only statistics.
+    #[tracing::instrument(skip(self, db_manager))]
+    pub async fn flush(&self, db_manager: &RwLock<impl DbManager>) {
+        let counts = std::mem::take(&mut *self.counts.lock().await);
+        if counts.is_empty() {
+            return;
+        }
+
+        let db_manager = db_manager.write().await;
+        for ((name, version, date), count) in counts {
+            if let Err(e) = db_manager
+                .add_downloads(&name, version.clone(), date, count)
+                .await
+            {
+                tracing::warn!(
+                    "failed to store {} download(s) of {}#{}: {}",
+                    count,
+                    name,
+                    version,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Flushes the counts every `period` until the server stops.
+    #[tracing::instrument(skip(self, db_manager, period))]
+    pub async fn flush_periodically(
+        self: Arc<Self>,
+        db_manager: Arc<RwLock<impl DbManager>>,
+        period: Duration,
+    ) {
+        let mut interval = tokio::time::interval(period);
+        // the first tick completes immediately but nothing is counted yet.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            self.flush(&db_manager).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DownloadCounter;
+    use semver::Version;
+
+    #[tokio::test]
+    async fn test_increment_counts_per_version() {
+        let counter = DownloadCounter::new();
+        let version = Version::new(0, 1, 0);
+
+        counter.increment("foo", &version).await;
+        counter.increment("foo", &version).await;
+        counter.increment("foo", &Version::new(0, 2, 0)).await;
+
+        let counts = counter.counts.lock().await;
+        let count = counts
+            .iter()
+            .find(|((name, v, _), _)| name == "foo" && v == &version)
+            .map(|(_, count)| *count);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(count, Some(2));
+    }
+}
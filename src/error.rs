@@ -1,6 +1,7 @@
 use semver::Version;
 use serde::Serialize;
 use std::io;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Debug, Clone, Serialize)]
@@ -101,6 +102,59 @@ pub enum Error {
     InvalidUtf8Bytes(std::string::FromUtf8Error),
     #[error("invalid body length: {}", _0)]
     InvalidBodyLength(usize),
+    #[error("request body error: {}", _0)]
+    Body(warp::Error),
+    #[error("invalid crate file: {}", _0)]
+    InvalidCrateFile(String),
+    #[error(
+        "the request body is {} bytes which exceeds the limit of {} bytes",
+        _0,
+        _1
+    )]
+    BodyTooLarge(u64, u64),
+    #[error("the metadata is {} bytes which exceeds the limit of {} bytes", _0, _1)]
+    MetadataTooLarge(u64, u64),
+    #[error(
+        "the crate file is {} bytes which exceeds the limit of {} bytes",
+        _0,
+        _1
+    )]
+    CrateTooLarge(u64, u64),
+    #[error(
+        "the storage quota of the user is exceeded: {} bytes are used and {} bytes are published but the quota is {} bytes",
+        _0,
+        _1,
+        _2
+    )]
+    UserQuotaExceeded(u64, u64, u64),
+    #[error(
+        "the storage quota of the crate, {}, is exceeded: {} bytes are used and {} bytes are published but the quota is {} bytes",
+        _0,
+        _1,
+        _2,
+        _3
+    )]
+    CrateQuotaExceeded(String, u64, u64, u64),
+    #[error(
+        "the dependency, {} {}, is not found in the registry or all the matching versions are yanked",
+        _0,
+        _1
+    )]
+    DependencyNotFound(String, semver::VersionReq),
+    #[error(
+        "the dependency, {}, comes from the registry which is not allowed: {}",
+        _0,
+        _1
+    )]
+    RegistryNotAllowed(String, url::Url),
+    #[error("invalid policy rule for {}", _0)]
+    InvalidPolicy(String),
+    #[error("the crate violates the publish policy: {}", _0.join("; "))]
+    PolicyViolation(Vec<String>),
+    #[error("the user is not an administrator: {}", _0)]
+    NotAdmin(String),
+    #[error("the registry requires a valid token in the `Authorization` header")]
+    AuthRequired,
     #[error("crate not found in the database which is named {}", _0)]
     CrateNotFoundInDb(String),
     #[error(
@@ -132,14 +186,34 @@ pub enum Error {
     ))]
     #[error("error by database: {}", _0)]
     Db(mongodb::error::Error),
+    #[error(
+        "the index push is rejected {} time(s) because the remote index is updated concurrently",
+        _0
+    )]
+    PushRejected(usize),
+    #[error("invalid webhook token or signature")]
+    InvalidWebhookSignature,
+    #[error("the index commit queue is closed")]
+    CommitQueueClosed,
+    #[error("failed to commit the batched index edits: {}", _0)]
+    BatchCommit(Arc<Error>),
+    #[error(
+        "{} version(s) would be dropped from the rebuilt index because their crate files cannot be read",
+        _0
+    )]
+    VersionsWouldBeDropped(usize),
+    #[error("git process error: {}", _0)]
+    GitProcess(String),
     #[error("multiple errors: {:?}", _0)]
     Multiple(Vec<Error>),
     #[error("task joinning error: {}", _0)]
     Join(tokio::task::JoinError),
-    #[cfg(feature = "crates-io-mirroring")]
+    #[cfg(any(feature = "crates-io-mirroring", feature = "storage-s3"))]
     #[error("HTTP request error: {}", _0)]
     HttpRequest(reqwest::Error),
-    #[cfg(feature = "crates-io-mirroring")]
+    #[cfg(feature = "storage-s3")]
+    #[error("crate file storage error: {}", _0)]
+    Storage(String),
     #[error("HTTP response building error: {}", _0)]
     HttpResponseBuilding(warp::http::Error),
     #[cfg(feature = "crates-io-mirroring")]
@@ -150,11 +224,25 @@ pub enum Error {
 impl Error {
     #[tracing::instrument(skip(self))]
     pub fn to_reply(&self) -> (warp::reply::Json, warp::http::StatusCode) {
+        // the edits committed together fail for the same reason.
+        if let Error::BatchCommit(e) = self {
+            return e.to_reply();
+        }
+
         let status_code = match self {
             Error::CrateNotFoundInDb(_) | Error::VersionNotFoundInDb(_) => {
                 warp::http::StatusCode::NOT_FOUND
             }
-            Error::InvalidToken(_) | Error::InvalidUser(_) => warp::http::StatusCode::FORBIDDEN,
+            Error::InvalidToken(_)
+            | Error::InvalidUser(_)
+            | Error::NotAdmin(_)
+            | Error::InvalidWebhookSignature => warp::http::StatusCode::FORBIDDEN,
+            Error::AuthRequired => warp::http::StatusCode::UNAUTHORIZED,
+            Error::BodyTooLarge(..)
+            | Error::MetadataTooLarge(..)
+            | Error::CrateTooLarge(..)
+            | Error::UserQuotaExceeded(..)
+            | Error::CrateQuotaExceeded(..) => warp::http::StatusCode::PAYLOAD_TOO_LARGE,
             _ => warp::http::StatusCode::OK,
         };
         let json = warp::reply::json(&ErrorMessage::new(&[ApiError::from_error(&self)]));
@@ -0,0 +1,348 @@
+use crate::db_manager::DbManager;
+use crate::error::Error;
+use crate::index_manager::IndexManager;
+use crate::models::{Metadata, Package};
+use crate::storage_manager::StorageManager;
+use semver::Version;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A version of a crate found in at least one of the database, the index and the crate files.
+#[derive(Debug, Default)]
+struct Record {
+    name: String,
+    metadata: Option<Metadata>,
+    package: Option<Package>,
+    crate_file_checksum: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    MissingInDb,
+    MissingInIndex,
+    MissingCrateFile,
+    ChecksumMismatch { index: String, crate_file: String },
+    YankedMismatch { db: bool, index: bool },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingInDb => write!(f, "missing in the database"),
+            Issue::MissingInIndex => write!(f, "missing in the index"),
+            Issue::MissingCrateFile => write!(f, "missing the crate file"),
+            Issue::ChecksumMismatch { index, crate_file } => write!(
+                f,
+                "checksum mismatch (index: {}, crate file: {})",
+                index, crate_file
+            ),
+            Issue::YankedMismatch { db, index } => write!(
+                f,
+                "yanked state mismatch (database: {}, index: {})",
+                db, index
+            ),
+        }
+    }
+}
+
+/// An issue found in a version.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub name: String,
+    pub version: Version,
+    pub issue: Issue,
+    /// Whether the issue is repaired, which is false unless `repair` is set.
+    pub repaired: bool,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}: {}", self.name, self.version, self.issue)?;
+        if self.repaired {
+            write!(f, " (repaired)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Record {
+    #[tracing::instrument(skip(self))]
+    fn issues(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+
+        if self.metadata.is_none() {
+            issues.push(Issue::MissingInDb);
+        }
+        if self.package.is_none() {
+            issues.push(Issue::MissingInIndex);
+        }
+        if self.crate_file_checksum.is_none() {
+            issues.push(Issue::MissingCrateFile);
+        }
+
+        if let (Some(package), Some(crate_file_checksum)) =
+            (&self.package, &self.crate_file_checksum)
+        {
+            if &package.cksum != crate_file_checksum {
+                issues.push(Issue::ChecksumMismatch {
+                    index: package.cksum.clone(),
+                    crate_file: crate_file_checksum.clone(),
+                });
+            }
+        }
+
+        if let (Some(metadata), Some(package)) = (&self.metadata, &self.package) {
+            if metadata.yanked != package.yanked {
+                issues.push(Issue::YankedMismatch {
+                    db: metadata.yanked,
+                    index: package.yanked,
+                });
+            }
+        }
+
+        issues
+    }
+}
+
+/// Checks the consistency between the database, the index and the crate files
+/// and returns the issues found.
+///
+/// The database is regarded as the authoritative source of the versions and their yanked states,
+/// so `repair` restores the index entries from it. The other issues are only reported
+/// because they cannot be fixed without the lost data.
+#[tracing::instrument(skip(db_manager, index_manager, storage_manager))]
+pub async fn fsck(
+    db_manager: &impl DbManager,
+    index_manager: &IndexManager,
+    storage_manager: &impl StorageManager,
+    repair: bool,
+) -> Result<Vec<Finding>, Error> {
+    let mut records: BTreeMap<(String, Version), Record> = BTreeMap::new();
+
+    for metadata in db_manager
+        .entries()
+        .await?
+        .into_iter()
+        .flat_map(|entry| entry.versions().values().cloned().collect::<Vec<_>>())
+    {
+        let record = record_mut(&mut records, &metadata.name, &metadata.vers);
+        record.metadata = Some(metadata);
+    }
+
+    for package in index_manager.packages().await? {
+        let record = record_mut(&mut records, &package.name, &package.vers);
+        record.package = Some(package);
+    }
+
+    for crate_file in storage_manager.list().await? {
+        let (name, version) = (crate_file.name, crate_file.version);
+        // the crate file may be removed after it is listed.
+        if let Some(checksum) = storage_manager.checksum(&name, &version).await? {
+            let record = record_mut(&mut records, &name, &version);
+            record.crate_file_checksum = Some(checksum);
+        }
+    }
+
+    let mut findings = Vec::new();
+    for ((_, version), record) in records {
+        for issue in record.issues() {
+            let repaired = if repair {
+                repair_issue(index_manager, &record, &issue).await?
+            } else {
+                false
+            };
+
+            findings.push(Finding {
+                name: record.name.clone(),
+                version: version.clone(),
+                issue,
+                repaired,
+            });
+        }
+    }
+
+    Ok(findings)
+}
+
+#[tracing::instrument(skip(records, name, version))]
+fn record_mut<'a>(
+    records: &'a mut BTreeMap<(String, Version), Record>,
+    name: &str,
+    version: &Version,
+) -> &'a mut Record {
+    records
+        .entry((name.to_ascii_lowercase(), version.clone()))
+        .or_insert_with(|| Record {
+            name: name.to_owned(),
+            ..Default::default()
+        })
+}
+
+/// Repairs the issue from the database and returns whether it is repaired or not.
+#[tracing::instrument(skip(index_manager, record, issue))]
+async fn repair_issue(
+    index_manager: &IndexManager,
+    record: &Record,
+    issue: &Issue,
+) -> Result<bool, Error> {
+    match (issue, &record.metadata, &record.crate_file_checksum) {
+        (Issue::MissingInIndex, Some(metadata), Some(crate_file_checksum)) => {
+            let package = metadata.to_package(crate_file_checksum);
+            index_manager.add_package(package).await?;
+            Ok(true)
+        }
+        (Issue::YankedMismatch { db: true, .. }, Some(metadata), _) => {
+            index_manager
+                .yank(&metadata.name, metadata.vers.clone())
+                .await?;
+            Ok(true)
+        }
+        (Issue::YankedMismatch { db: false, .. }, Some(metadata), _) => {
+            index_manager
+                .unyank(&metadata.name, metadata.vers.clone())
+                .await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Finding, Issue, Record};
+    use crate::models::Package;
+    use semver::Version;
+
+    #[test]
+    fn test_issues_of_index_only_version() {
+        let record = Record {
+            name: "foo".to_owned(),
+            metadata: None,
+            package: Some(Package {
+                name: "foo".to_owned(),
+                vers: Version::new(0, 1, 0),
+                deps: Vec::new(),
+                cksum: "abc".to_owned(),
+                features: Default::default(),
+                yanked: false,
+                links: None,
+                features2: None,
+                v: None,
+                rust_version: None,
+            }),
+            crate_file_checksum: Some("def".to_owned()),
+        };
+
+        assert_eq!(
+            record.issues(),
+            vec![
+                Issue::MissingInDb,
+                Issue::ChecksumMismatch {
+                    index: "abc".to_owned(),
+                    crate_file: "def".to_owned()
+                }
+            ]
+        );
+    }
+
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fsck_and_repair() {
+        use super::fsck;
+        use crate::config::CrateFilesConfig;
+        use crate::db_manager::DbManager;
+        use crate::index_manager::IndexManager;
+        use crate::storage_manager::{FsStorageManager, StorageManager};
+        use crate::test_utils;
+        use crate::utils::checksum;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (user_id, _) = test_utils::add_user(&db_manager, "alice").await;
+        let db_manager = db_manager.write().await;
+        let index_manager = IndexManager::new(test_utils::index_config(dir.path()), &[])
+            .await
+            .unwrap();
+        let storage_manager = FsStorageManager::new(&CrateFilesConfig {
+            dl_dir_path: dir.path().join("crates"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        for version in &["0.1.0", "0.2.0"] {
+            let metadata = test_utils::metadata("foo", version);
+            let crate_file = test_utils::crate_file("foo", version);
+            let crate_file_path = dir.path().join(format!("foo-{}.crate", version));
+            std::fs::write(&crate_file_path, &crate_file).unwrap();
+            storage_manager
+                .put("foo", &metadata.vers, &crate_file_path)
+                .await
+                .unwrap();
+            // 0.1.0 is missing in the index.
+            if metadata.vers == Version::new(0, 2, 0) {
+                index_manager
+                    .add_package(metadata.to_package(checksum(&crate_file)))
+                    .await
+                    .unwrap();
+            }
+            db_manager
+                .add_new_metadata(user_id, metadata)
+                .await
+                .unwrap();
+        }
+        // 0.2.0 is yanked only in the database.
+        db_manager
+            .yank("foo", Version::new(0, 2, 0), None)
+            .await
+            .unwrap();
+
+        let expected = |repaired| {
+            vec![
+                Finding {
+                    name: "foo".to_owned(),
+                    version: Version::new(0, 1, 0),
+                    issue: Issue::MissingInIndex,
+                    repaired,
+                },
+                Finding {
+                    name: "foo".to_owned(),
+                    version: Version::new(0, 2, 0),
+                    issue: Issue::YankedMismatch {
+                        db: true,
+                        index: false,
+                    },
+                    repaired,
+                },
+            ]
+        };
+        let check = |repair| fsck(&*db_manager, &index_manager, &storage_manager, repair);
+        assert_eq!(check(false).await.unwrap(), expected(false));
+        assert_eq!(check(true).await.unwrap(), expected(true));
+        assert_eq!(check(false).await.unwrap(), Vec::new());
+
+        let mut packages = index_manager.packages().await.unwrap();
+        packages.sort_by(|a, b| a.vers.cmp(&b.vers));
+        assert_eq!(packages.len(), 2);
+        assert!(packages[1].yanked);
+    }
+
+    #[test]
+    fn test_display_finding() {
+        let finding = |repaired| Finding {
+            name: "foo".to_owned(),
+            version: Version::new(0, 1, 0),
+            issue: Issue::MissingInIndex,
+            repaired,
+        };
+
+        assert_eq!(
+            finding(false).to_string(),
+            "foo#0.1.0: missing in the index"
+        );
+        assert_eq!(
+            finding(true).to_string(),
+            "foo#0.1.0: missing in the index (repaired)"
+        );
+    }
+}
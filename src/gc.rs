@@ -0,0 +1,233 @@
+use crate::config::CrateFilesConfig;
+use crate::db_manager::DbManager;
+use crate::error::Error;
+use crate::put::UPLOADS_DIR;
+use crate::storage_manager::StorageManager;
+use futures::TryFutureExt;
+use semver::Version;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+/// Where an orphaned file is found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    /// A crate file in the storage which no version in the database refers to.
+    CrateFile(String, Version),
+    /// A local file, e.g. an upload left by a publish interrupted by the server stopping.
+    LocalFile(PathBuf),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::CrateFile(name, version) => write!(f, "{}#{}", name, version),
+            Location::LocalFile(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Orphan {
+    pub location: Location,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// Whether the file is removed, which is false while it is in the grace period or on dry run.
+    pub removed: bool,
+}
+
+/// Finds the files nothing refers to and removes the ones older than `grace_period`
+/// unless `dry_run` is set.
+///
+/// The grace period keeps the files of the publishes in progress
+/// and of the manual edits on the database which are not finished yet.
+#[tracing::instrument(skip(db_manager, storage_manager, config, grace_period, dry_run))]
+pub async fn gc(
+    db_manager: &impl DbManager,
+    storage_manager: &impl StorageManager,
+    config: &CrateFilesConfig,
+    grace_period: Duration,
+    dry_run: bool,
+) -> Result<Vec<Orphan>, Error> {
+    let versions: HashSet<(String, Version)> = db_manager
+        .entries()
+        .await?
+        .iter()
+        .flat_map(|entry| {
+            entry
+                .versions()
+                .values()
+                .map(|metadata| (metadata.name.to_ascii_lowercase(), metadata.vers.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    let now = SystemTime::now();
+    let mut orphans = Vec::new();
+
+    for crate_file in storage_manager.list().await? {
+        let key = (crate_file.name.to_ascii_lowercase(), crate_file.version);
+        if versions.contains(&key) {
+            continue;
+        }
+
+        let removed = !dry_run && is_expired(crate_file.modified, now, grace_period);
+        if removed {
+            storage_manager.delete(&crate_file.name, &key.1).await?;
+        }
+        orphans.push(Orphan {
+            location: Location::CrateFile(crate_file.name, key.1),
+            size: crate_file.size,
+            removed,
+        });
+    }
+
+    let mut uploads_dir_path = config.dl_dir_path.clone();
+    uploads_dir_path.push(UPLOADS_DIR);
+    let local_orphans = local_files(&uploads_dir_path).await?;
+
+    // the failed downloads from crates.io leave the empty cache files.
+    #[cfg(feature = "crates-io-mirroring")]
+    let local_orphans: Vec<_> = local_orphans
+        .into_iter()
+        .chain(
+            local_files(&config.cache_dir_path)
+                .await?
+                .into_iter()
+                .filter(|(_, metadata)| metadata.len() == 0),
+        )
+        .collect();
+
+    for (path, metadata) in local_orphans {
+        let removed = !dry_run && is_expired(metadata.modified()?, now, grace_period);
+        if removed {
+            tokio::fs::remove_file(&path).map_err(Error::Io).await?;
+        }
+        orphans.push(Orphan {
+            location: Location::LocalFile(path),
+            size: metadata.len(),
+            removed,
+        });
+    }
+
+    Ok(orphans)
+}
+
+/// Collects the garbage every `period` until the server stops.
+#[tracing::instrument(skip(db_manager, storage_manager, config, grace_period, period))]
+pub async fn gc_periodically(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    storage_manager: Arc<impl StorageManager>,
+    config: CrateFilesConfig,
+    grace_period: Duration,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval(period);
+    // the first tick completes immediately, so the garbage is collected after a period.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        // the read lock keeps the publishes from storing the crate files while collecting.
+        let db_manager = db_manager.read().await;
+        match gc(
+            &*db_manager,
+            &*storage_manager,
+            &config,
+            grace_period,
+            false,
+        )
+        .await
+        {
+            Ok(orphans) => tracing::info!("garbage collection: {}", summary(&orphans)),
+            Err(e) => tracing::error!("failed to collect the garbage: {}", e),
+        }
+    }
+}
+
+/// Summarizes the number and the total size of the orphaned files.
+#[tracing::instrument(skip(orphans))]
+pub fn summary(orphans: &[Orphan]) -> String {
+    let removed: Vec<_> = orphans.iter().filter(|orphan| orphan.removed).collect();
+    format!(
+        "{} orphaned file(s) of {} bytes found, {} file(s) of {} bytes removed",
+        orphans.len(),
+        orphans.iter().map(|orphan| orphan.size).sum::<u64>(),
+        removed.len(),
+        removed.iter().map(|orphan| orphan.size).sum::<u64>()
+    )
+}
+
+#[tracing::instrument(skip(modified, now, grace_period))]
+fn is_expired(modified: SystemTime, now: SystemTime, grace_period: Duration) -> bool {
+    // the files modified in the future are kept because the clock may be skewed.
+    match now.duration_since(modified) {
+        Ok(age) => age >= grace_period,
+        Err(_) => false,
+    }
+}
+
+/// Lists the files in the directory and its subdirectories, or nothing if it does not exist.
+#[tracing::instrument(skip(dir_path))]
+async fn local_files(dir_path: &Path) -> Result<Vec<(PathBuf, std::fs::Metadata)>, Error> {
+    let mut files = Vec::new();
+    let mut dir_paths = vec![dir_path.to_path_buf()];
+
+    while let Some(dir_path) = dir_paths.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir_path).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        while let Some(entry) = entries.next_entry().map_err(Error::Io).await? {
+            let metadata = entry.metadata().map_err(Error::Io).await?;
+            if metadata.is_dir() {
+                dir_paths.push(entry.path());
+            } else if metadata.is_file() {
+                files.push((entry.path(), metadata));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_expired, summary, Location, Orphan};
+    use semver::Version;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_is_expired() {
+        let now = SystemTime::now();
+        let grace_period = Duration::from_secs(60);
+
+        assert!(is_expired(now - grace_period, now, grace_period));
+        assert!(!is_expired(
+            now - Duration::from_secs(59),
+            now,
+            grace_period
+        ));
+        assert!(!is_expired(now + grace_period, now, grace_period));
+    }
+
+    #[test]
+    fn test_summary() {
+        let orphan = |size, removed| Orphan {
+            location: Location::CrateFile("foo".to_owned(), Version::new(0, 1, 0)),
+            size,
+            removed,
+        };
+
+        assert_eq!(
+            summary(&[orphan(10, true), orphan(20, false), orphan(30, true)]),
+            "3 orphaned file(s) of 60 bytes found, 2 file(s) of 40 bytes removed"
+        );
+    }
+}
@@ -1,13 +1,14 @@
 use crate::db_manager::DbManager;
-#[cfg(feature = "crates-io-mirroring")]
+use crate::download_counter::DownloadCounter;
 use crate::error::Error;
-use crate::models::{Query, User};
+use crate::models::{CrateInfo, Query, User, VersionResponse};
+use crate::storage_manager::StorageManager;
 use crate::utils::*;
 use futures::TryFutureExt;
 #[cfg(feature = "crates-io-mirroring")]
 use reqwest::Client;
-#[cfg(feature = "crates-io-mirroring")]
 use semver::Version;
+#[cfg(feature = "crates-io-mirroring")]
 use std::path::PathBuf;
 use std::sync::Arc;
 #[cfg(feature = "crates-io-mirroring")]
@@ -17,22 +18,37 @@ use tokio::io::{AsyncWriteExt, BufWriter};
 use tokio::{io::AsyncReadExt, sync::RwLock};
 #[cfg(feature = "crates-io-mirroring")]
 use url::Url;
-#[cfg(feature = "crates-io-mirroring")]
-use warp::http::Response;
-#[cfg(feature = "crates-io-mirroring")]
+use warp::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+};
+use warp::http::{Response, StatusCode};
 use warp::hyper::body::Bytes;
 use warp::{filters::BoxedFilter, Filter, Rejection, Reply};
 
+/// The crate files never change once published, so they can be cached as long as possible.
+const CRATE_FILE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
 #[cfg(not(feature = "crates-io-mirroring"))]
-#[tracing::instrument(skip(db_manager, dl_dir_path, path))]
+#[tracing::instrument(skip(db_manager, storage_manager, download_counter, auth_required, path))]
 pub fn apis(
     db_manager: Arc<RwLock<impl DbManager>>,
-    dl_dir_path: Arc<PathBuf>,
+    storage_manager: Arc<impl StorageManager>,
+    download_counter: Arc<DownloadCounter>,
+    auth_required: bool,
     path: Vec<String>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let routes = download(dl_dir_path, path)
-        .or(owners(db_manager.clone()))
-        .or(search(db_manager));
+    let routes = download(
+        db_manager.clone(),
+        storage_manager,
+        download_counter,
+        auth_required,
+        path,
+    )
+    .or(owners(db_manager.clone(), auth_required))
+    .or(downloads(db_manager.clone(), auth_required))
+    .or(version_info(db_manager.clone(), auth_required))
+    .or(crate_info(db_manager.clone(), auth_required))
+    .or(search(db_manager, auth_required));
 
     // With openid enabled, the `/me` route is handled in src/openid.rs
     #[cfg(not(feature = "openid"))]
@@ -42,18 +58,42 @@ pub fn apis(
 }
 
 #[cfg(feature = "crates-io-mirroring")]
-#[tracing::instrument(skip(db_manager, dl_dir_path, http_client, cache_dir_path, path))]
+#[tracing::instrument(skip(
+    db_manager,
+    storage_manager,
+    download_counter,
+    auth_required,
+    http_client,
+    cache_dir_path,
+    path
+))]
 pub fn apis(
     db_manager: Arc<RwLock<impl DbManager>>,
-    dl_dir_path: Arc<PathBuf>,
+    storage_manager: Arc<impl StorageManager>,
+    download_counter: Arc<DownloadCounter>,
+    auth_required: bool,
     http_client: Client,
     cache_dir_path: Arc<PathBuf>,
     path: Vec<String>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let routes = download(dl_dir_path, path)
-        .or(download_crates_io(http_client, cache_dir_path))
-        .or(owners(db_manager.clone()))
-        .or(search(db_manager));
+    let routes = download(
+        db_manager.clone(),
+        storage_manager,
+        download_counter,
+        auth_required,
+        path,
+    )
+    .or(download_crates_io(
+        db_manager.clone(),
+        auth_required,
+        http_client,
+        cache_dir_path,
+    ))
+    .or(owners(db_manager.clone(), auth_required))
+    .or(downloads(db_manager.clone(), auth_required))
+    .or(version_info(db_manager.clone(), auth_required))
+    .or(crate_info(db_manager.clone(), auth_required))
+    .or(search(db_manager, auth_required));
     // With openid enabled, the `/me` route is handled in src/openid.rs
     #[cfg(not(feature = "openid"))]
     let routes = routes.or(me());
@@ -68,12 +108,219 @@ pub(crate) fn into_boxed_filters(path: Vec<String>) -> BoxedFilter<()> {
     })
 }
 
-#[tracing::instrument(skip(path, dl_dir_path))]
+/// The request headers of the conditional and range requests on the crate files.
+#[derive(Debug, Clone, Default)]
+struct CacheHeaders {
+    if_none_match: Option<String>,
+    range: Option<String>,
+    if_range: Option<String>,
+}
+
+/// The part of the crate file to reply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteRange {
+    Whole,
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+impl CacheHeaders {
+    /// Returns whether the client already has the crate file of the entity tag.
+    #[tracing::instrument(skip(self, etag))]
+    fn not_modified(&self, etag: &str) -> bool {
+        // `If-None-Match` uses the weak comparison.
+        match &self.if_none_match {
+            Some(tags) => tags
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag),
+            None => false,
+        }
+    }
+
+    /// Returns the part of the crate file of `length` bytes to reply.
+    ///
+    /// The whole file is replied for the invalid or multiple ranges as RFC 7233 allows.
+    #[tracing::instrument(skip(self, etag, length))]
+    fn byte_range(&self, etag: &str, length: u64) -> ByteRange {
+        let range = match &self.range {
+            Some(range) => range,
+            None => return ByteRange::Whole,
+        };
+        // `If-Range` uses the strong comparison, and the dates never match the entity tag.
+        if matches!(self.if_range.as_deref(), Some(if_range) if if_range != etag) {
+            return ByteRange::Whole;
+        }
+
+        let spec = match range.trim().strip_prefix("bytes=") {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return ByteRange::Whole,
+        };
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return ByteRange::Whole,
+        };
+
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+            (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 {
+                    return ByteRange::Unsatisfiable;
+                }
+                (length.saturating_sub(suffix), length.saturating_sub(1))
+            }
+            _ => return ByteRange::Whole,
+        };
+
+        if start >= length {
+            ByteRange::Unsatisfiable
+        } else {
+            ByteRange::Partial { start, end }
+        }
+    }
+}
+
+#[tracing::instrument]
+fn cache_headers() -> impl Filter<Extract = (CacheHeaders,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("if-none-match")
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .map(|if_none_match, range, if_range| CacheHeaders {
+            if_none_match,
+            range,
+            if_range,
+        })
+}
+
+/// Builds the response of the crate file for the conditional and range requests.
+#[tracing::instrument(skip(data, etag, headers))]
+fn crate_file_response(
+    data: Bytes,
+    etag: &str,
+    headers: &CacheHeaders,
+) -> Result<Response<Bytes>, Error> {
+    let builder = Response::builder()
+        .header(ETAG, etag)
+        .header(CACHE_CONTROL, CRATE_FILE_CACHE_CONTROL)
+        .header(ACCEPT_RANGES, "bytes");
+
+    if headers.not_modified(etag) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Bytes::new())
+            .map_err(Error::HttpResponseBuilding);
+    }
+
+    let length = data.len() as u64;
+    match headers.byte_range(etag, length) {
+        ByteRange::Whole => builder
+            .header(CONTENT_TYPE, "application/gzip")
+            .header(CONTENT_LENGTH, length)
+            .body(data),
+        ByteRange::Partial { start, end } => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_TYPE, "application/gzip")
+            .header(CONTENT_LENGTH, end - start + 1)
+            .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, length))
+            .body(data.slice(start as usize..=end as usize)),
+        ByteRange::Unsatisfiable => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CONTENT_RANGE, format!("bytes */{}", length))
+            .body(Bytes::new()),
+    }
+    .map_err(Error::HttpResponseBuilding)
+}
+
+/// Returns the strong entity tag of the crate file of the checksum.
+#[tracing::instrument(skip(checksum))]
+fn etag(checksum: &str) -> String {
+    format!("\"{}\"", checksum)
+}
+
+#[tracing::instrument(skip(db_manager, storage_manager, download_counter, auth_required, path))]
 fn download(
-    dl_dir_path: Arc<PathBuf>,
+    db_manager: Arc<RwLock<impl DbManager>>,
+    storage_manager: Arc<impl StorageManager>,
+    download_counter: Arc<DownloadCounter>,
+    auth_required: bool,
     path: Vec<String>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    into_boxed_filters(path).and(warp::fs::dir(dl_dir_path.to_path_buf()))
+    warp::get()
+        .and(into_boxed_filters(path))
+        .and(with_db_manager(db_manager.clone()))
+        .and(with_storage_manager(storage_manager))
+        .and(with_download_counter(download_counter))
+        .and(warp::path!(String / Version / "download"))
+        .and(authorization_if_required(db_manager, auth_required))
+        .and(cache_headers())
+        .and_then(handle_download)
+}
+
+#[tracing::instrument(skip(
+    db_manager,
+    storage_manager,
+    download_counter,
+    crate_name,
+    version,
+    headers
+))]
+async fn handle_download(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    storage_manager: Arc<impl StorageManager>,
+    download_counter: Arc<DownloadCounter>,
+    crate_name: String,
+    version: Version,
+    headers: CacheHeaders,
+) -> Result<impl Reply, Rejection> {
+    let stored_checksum = db_manager
+        .read()
+        .await
+        .crate_entry(&crate_name)
+        .map_err(warp::reject::custom)
+        .await?
+        .versions()
+        .get(&version)
+        .and_then(|metadata| metadata.cksum.clone());
+
+    // the revalidation with the stored checksum does not need to read the crate file.
+    if let Some(stored_checksum) = &stored_checksum {
+        let etag = etag(stored_checksum);
+        if headers.not_modified(&etag) {
+            return crate_file_response(Bytes::new(), &etag, &headers)
+                .map_err(warp::reject::custom);
+        }
+    }
+
+    let crate_file_data = storage_manager
+        .get(&crate_name, &version)
+        .map_err(warp::reject::custom)
+        .await?
+        .ok_or_else(warp::reject::not_found)?;
+    // the versions published before the checksum is stored are tagged with the computed one.
+    let etag = etag(&stored_checksum.unwrap_or_else(|| checksum(&crate_file_data)));
+
+    let response =
+        crate_file_response(crate_file_data, &etag, &headers).map_err(warp::reject::custom)?;
+    if is_new_download(&response) {
+        download_counter.increment(&crate_name, &version).await;
+    }
+
+    Ok(response)
+}
+
+/// Returns whether the response starts a download,
+/// i.e. neither a revalidation nor a resumption of the interrupted download.
+#[tracing::instrument(skip(response))]
+fn is_new_download(response: &Response<Bytes>) -> bool {
+    match response.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT => matches!(
+            response.headers().get(CONTENT_RANGE).map(|value| value.to_str()),
+            Some(Ok(value)) if value.starts_with("bytes 0-")
+        ),
+        _ => false,
+    }
 }
 
 #[cfg(feature = "crates-io-mirroring")]
@@ -149,8 +396,10 @@ async fn cache_crate_file(
 }
 
 #[cfg(feature = "crates-io-mirroring")]
-#[tracing::instrument(skip(cache_dir_path))]
+#[tracing::instrument(skip(db_manager, auth_required, http_client, cache_dir_path))]
 fn download_crates_io(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
     http_client: Client,
     cache_dir_path: Arc<PathBuf>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
@@ -160,29 +409,35 @@ fn download_crates_io(
         .and(warp::path!(
             "ktra" / "api" / "v1" / "mirror" / String / Version / "download"
         ))
+        // the authorization is checked before the crate file is fetched from crates.io.
+        .and(authorization_if_required(db_manager, auth_required))
         .and_then(cache_crate_file)
+        .and(cache_headers())
         .and_then(handle_download_crates_io)
 }
 
 #[cfg(feature = "crates-io-mirroring")]
-#[tracing::instrument(skip(crate_file_data))]
-async fn handle_download_crates_io(crate_file_data: Bytes) -> Result<impl Reply, Rejection> {
-    let response = Response::builder()
-        .header("Content-Type", "application/x-tar")
-        .body(crate_file_data)
-        .map_err(Error::HttpResponseBuilding)?;
+#[tracing::instrument(skip(crate_file_data, headers))]
+async fn handle_download_crates_io(
+    crate_file_data: Bytes,
+    headers: CacheHeaders,
+) -> Result<impl Reply, Rejection> {
+    let etag = etag(&checksum(&crate_file_data));
+    let response = crate_file_response(crate_file_data, &etag, &headers)?;
 
     Ok(response)
 }
 
-#[tracing::instrument(skip(db_manager))]
+#[tracing::instrument(skip(db_manager, auth_required))]
 fn owners(
     db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::get()
-        .and(with_db_manager(db_manager))
+        .and(with_db_manager(db_manager.clone()))
         .and(authorization_header())
         .and(warp::path!("api" / "v1" / "crates" / String / "owners"))
+        .and(authorization_if_required(db_manager, auth_required))
         .and_then(handle_owners)
 }
 
@@ -202,13 +457,128 @@ async fn handle_owners(
     Ok(owners_json(owners))
 }
 
-#[tracing::instrument(skip(db_manager))]
+#[tracing::instrument(skip(db_manager, auth_required))]
+fn crate_info(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(with_db_manager(db_manager.clone()))
+        .and(warp::path!("api" / "v1" / "crates" / String))
+        .and(authorization_if_required(db_manager, auth_required))
+        .and_then(handle_crate_info)
+}
+
+#[tracing::instrument(skip(db_manager, name))]
+async fn handle_crate_info(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    name: String,
+) -> Result<impl Reply, Rejection> {
+    let db_manager = db_manager.read().await;
+    let entry = db_manager
+        .crate_entry(&name)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    let latest = entry
+        .latest_version()
+        .and_then(|v| entry.versions().get(v))
+        .ok_or_else(|| warp::reject::custom(Error::CrateNotFoundInDb(name.clone())))?;
+
+    let mut versions = Vec::new();
+    for metadata in entry.versions().values() {
+        // the publisher is omitted if the user no longer exists.
+        let published_by = match metadata.published_by {
+            Some(user_id) => db_manager.user_by_id(user_id).await.ok(),
+            None => None,
+        };
+        versions.push(metadata.to_version_info(published_by));
+    }
+    versions.sort_by(|a, b| b.num.cmp(&a.num));
+
+    Ok(warp::reply::json(&CrateInfo {
+        krate: entry.to_searched(latest),
+        versions,
+    }))
+}
+
+#[tracing::instrument(skip(db_manager, auth_required))]
+fn version_info(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(with_db_manager(db_manager.clone()))
+        .and(warp::path!("api" / "v1" / "crates" / String / Version))
+        .and(authorization_if_required(db_manager, auth_required))
+        .and_then(handle_version_info)
+}
+
+#[tracing::instrument(skip(db_manager, name, version))]
+async fn handle_version_info(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    name: String,
+    version: Version,
+) -> Result<impl Reply, Rejection> {
+    let db_manager = db_manager.read().await;
+    let entry = db_manager
+        .crate_entry(&name)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    let metadata = entry
+        .versions()
+        .get(&version)
+        .ok_or_else(|| warp::reject::custom(Error::VersionNotFoundInDb(version)))?;
+    let published_by = match metadata.published_by {
+        Some(user_id) => db_manager.user_by_id(user_id).await.ok(),
+        None => None,
+    };
+
+    Ok(warp::reply::json(&VersionResponse {
+        version: metadata.to_version_info(published_by),
+    }))
+}
+
+#[tracing::instrument(skip(db_manager, auth_required))]
+fn downloads(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(with_db_manager(db_manager.clone()))
+        .and(warp::path!("api" / "v1" / "crates" / String / "downloads"))
+        .and(authorization_if_required(db_manager, auth_required))
+        .and_then(handle_downloads)
+}
+
+#[tracing::instrument(skip(db_manager, name))]
+async fn handle_downloads(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    name: String,
+) -> Result<impl Reply, Rejection> {
+    let db_manager = db_manager.read().await;
+    let entry = db_manager
+        .crate_entry(&name)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    if entry.is_empty() {
+        return Err(warp::reject::custom(Error::CrateNotFoundInDb(name)));
+    }
+
+    Ok(warp::reply::json(&entry.to_downloads()))
+}
+
+#[tracing::instrument(skip(db_manager, auth_required))]
 fn search(
     db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::get()
-        .and(with_db_manager(db_manager))
+        .and(with_db_manager(db_manager.clone()))
         .and(warp::path!("api" / "v1" / "crates"))
+        .and(authorization_if_required(db_manager, auth_required))
         .and(warp::query::<Query>())
         .and_then(handle_search)
 }
@@ -237,3 +607,256 @@ fn me() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
 fn owners_json(owners: Vec<User>) -> impl Reply {
     warp::reply::json(&serde_json::json!({ "users": owners }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{crate_file_response, ByteRange, CacheHeaders};
+    use warp::http::header::CONTENT_RANGE;
+    use warp::http::StatusCode;
+    use warp::hyper::body::Bytes;
+
+    const ETAG: &str = "\"abc\"";
+
+    #[cfg(all(feature = "crates-io-mirroring", feature = "db-sled"))]
+    #[tokio::test]
+    async fn test_download_crates_io_requires_authorization() {
+        use super::download_crates_io;
+        use crate::test_utils;
+        use std::sync::Arc;
+        use warp::Filter;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let cache_dir_path = Arc::new(dir.path().join("cache"));
+        let apis = download_crates_io(db_manager, true, reqwest::Client::new(), cache_dir_path)
+            .recover(crate::handle_rejection);
+
+        let response = warp::test::request()
+            .path("/ktra/api/v1/mirror/serde/1.0.0/download")
+            .reply(&apis)
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // nothing is fetched from crates.io.
+        assert!(!dir.path().join("cache").exists());
+    }
+
+    fn range(range: &str) -> CacheHeaders {
+        CacheHeaders {
+            range: Some(range.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_byte_range() {
+        let partial = |start, end| ByteRange::Partial { start, end };
+
+        assert_eq!(range("bytes=0-9").byte_range(ETAG, 100), partial(0, 9));
+        assert_eq!(range("bytes=90-").byte_range(ETAG, 100), partial(90, 99));
+        assert_eq!(range("bytes=-10").byte_range(ETAG, 100), partial(90, 99));
+        assert_eq!(range("bytes=50-200").byte_range(ETAG, 100), partial(50, 99));
+        assert_eq!(range("bytes=-200").byte_range(ETAG, 100), partial(0, 99));
+        assert_eq!(
+            range("bytes=100-").byte_range(ETAG, 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(
+            range("bytes=-0").byte_range(ETAG, 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(range("bytes=9-0").byte_range(ETAG, 100), ByteRange::Whole);
+        assert_eq!(
+            range("bytes=0-1,5-6").byte_range(ETAG, 100),
+            ByteRange::Whole
+        );
+        assert_eq!(range("items=0-1").byte_range(ETAG, 100), ByteRange::Whole);
+        assert_eq!(
+            CacheHeaders::default().byte_range(ETAG, 100),
+            ByteRange::Whole
+        );
+    }
+
+    #[test]
+    fn test_byte_range_with_if_range() {
+        let mut headers = range("bytes=10-");
+        headers.if_range = Some(ETAG.to_owned());
+        assert_eq!(
+            headers.byte_range(ETAG, 100),
+            ByteRange::Partial { start: 10, end: 99 }
+        );
+
+        headers.if_range = Some("\"def\"".to_owned());
+        assert_eq!(headers.byte_range(ETAG, 100), ByteRange::Whole);
+    }
+
+    #[test]
+    fn test_crate_file_response() {
+        let data = Bytes::from_static(b"0123456789");
+
+        let headers = CacheHeaders {
+            if_none_match: Some(format!("\"def\", W/{}", ETAG)),
+            ..Default::default()
+        };
+        let response = crate_file_response(data.clone(), ETAG, &headers).unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(response.body().is_empty());
+
+        let response = crate_file_response(data, ETAG, &range("bytes=2-4")).unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.body(), &Bytes::from_static(b"234"));
+    }
+
+    /// Returns the database and the routes to publish, yank and deprecate crates and to read them back.
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    async fn crate_routes(
+        dir_path: &std::path::Path,
+    ) -> (
+        std::sync::Arc<tokio::sync::RwLock<crate::db_manager::SledDbManager>>,
+        impl warp::Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone,
+    ) {
+        use super::{crate_info, version_info};
+        use crate::config::{AdminConfig, CrateFilesConfig, PolicyConfig, PublishConfig};
+        use crate::index_manager::IndexManager;
+        use crate::policy::Policy;
+        use crate::storage_manager::{FsStorageManager, StorageManager};
+        use crate::test_utils;
+        use std::sync::Arc;
+        use warp::Filter;
+
+        let db_manager = test_utils::db_manager(dir_path).await;
+        let index_manager = IndexManager::new(test_utils::index_config(dir_path), &[])
+            .await
+            .unwrap();
+        let index_manager = Arc::new(index_manager);
+        let crate_files_config = CrateFilesConfig {
+            dl_dir_path: dir_path.join("crates"),
+            ..Default::default()
+        };
+        let storage_manager = Arc::new(FsStorageManager::new(&crate_files_config).await.unwrap());
+
+        let routes = version_info(db_manager.clone(), false)
+            .or(crate_info(db_manager.clone(), false))
+            .or(crate::put::apis(
+                db_manager.clone(),
+                index_manager.clone(),
+                storage_manager.clone(),
+                Arc::new(crate_files_config.dl_dir_path),
+                Arc::new(PublishConfig::default()),
+                Arc::new(Policy::new(&PolicyConfig::default()).unwrap()),
+            ))
+            .or(crate::delete::apis(
+                db_manager.clone(),
+                index_manager,
+                storage_manager,
+                Arc::new(AdminConfig::default()),
+            ))
+            .recover(crate::handle_rejection);
+        (db_manager, routes)
+    }
+
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_publish_records_are_returned() {
+        use crate::test_utils;
+        use chrono::{DateTime, Utc};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (db_manager, routes) = crate_routes(dir.path()).await;
+        let (user_id, token) = test_utils::add_user(&db_manager, "alice").await;
+
+        // the client cannot make up the records.
+        let mut metadata = test_utils::metadata("foo", "0.1.0");
+        metadata.created_at = Some(DateTime::<Utc>::from(std::time::UNIX_EPOCH));
+        metadata.published_by = Some(user_id + 1);
+        let published_after = Utc::now();
+        warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", token)
+            .body(test_utils::publish_body(&metadata))
+            .reply(&routes)
+            .await;
+        let published_before = Utc::now();
+
+        let routes = &routes;
+        let get = |path: &'static str| async move {
+            let response = warp::test::request().path(path).reply(routes).await;
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+        };
+        let crate_info = get("/api/v1/crates/foo").await;
+        let version_info = get("/api/v1/crates/foo/0.1.0").await;
+        for version in &[&crate_info["versions"][0], &version_info["version"]] {
+            let created_at: DateTime<Utc> =
+                version["created_at"].as_str().unwrap().parse().unwrap();
+            assert!(published_after <= created_at && created_at <= published_before);
+            assert_eq!(version["published_by"]["id"], user_id);
+            assert_eq!(version["published_by"]["name"], "alice");
+            assert!(version["yanked_at"].is_null());
+        }
+    }
+
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_yank_reasons_and_deprecations_are_returned() {
+        use crate::test_utils;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (db_manager, routes) = crate_routes(dir.path()).await;
+        let (_, alice) = test_utils::add_user(&db_manager, "alice").await;
+        let (_, bob) = test_utils::add_user(&db_manager, "bob").await;
+
+        let routes = &routes;
+        let request = |method: &'static str, path: &'static str, token: &str| {
+            let request = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Authorization", token);
+            async move {
+                let response = request.reply(routes).await;
+                serde_json::from_slice::<serde_json::Value>(response.body()).unwrap()
+            }
+        };
+        let metadata = test_utils::metadata("foo", "0.1.0");
+        warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", &alice)
+            .body(test_utils::publish_body(&metadata))
+            .reply(routes)
+            .await;
+
+        let response = request(
+            "DELETE",
+            "/api/v1/crates/foo/0.1.0/yank?reason=broken%20build",
+            &alice,
+        )
+        .await;
+        assert_eq!(response["ok"], true);
+        let version = request("GET", "/api/v1/crates/foo/0.1.0", &alice).await;
+        assert_eq!(version["version"]["yanked"], true);
+        assert_eq!(version["version"]["yank_reason"], "broken build");
+
+        // the reason is cleared when the version is unyanked.
+        request("PUT", "/api/v1/crates/foo/0.1.0/unyank", &alice).await;
+        let version = request("GET", "/api/v1/crates/foo/0.1.0", &alice).await;
+        assert_eq!(version["version"]["yanked"], false);
+        assert!(version["version"]["yank_reason"].is_null());
+
+        // only the owners can deprecate the crate.
+        let response = request("PUT", "/api/v1/crates/foo/deprecate?successor=bar", &bob).await;
+        assert!(response.get("errors").is_some(), "{}", response);
+        let info = request("GET", "/api/v1/crates/foo", &alice).await;
+        assert_eq!(info["crate"]["deprecated"], false);
+
+        request("PUT", "/api/v1/crates/foo/deprecate?successor=bar", &alice).await;
+        let info = request("GET", "/api/v1/crates/foo", &alice).await;
+        assert_eq!(info["crate"]["deprecated"], true);
+        assert_eq!(info["crate"]["successor"], "bar");
+
+        request("DELETE", "/api/v1/crates/foo/deprecate", &alice).await;
+        let info = request("GET", "/api/v1/crates/foo", &alice).await;
+        assert_eq!(info["crate"]["deprecated"], false);
+        assert!(info["crate"]["successor"].is_null());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::db_manager::DbManager;
+use crate::error::Error;
+use crate::get::into_boxed_filters;
+use crate::utils::authorization_if_required;
+use bytes::{Buf, Bytes};
+use flate2::read::GzDecoder;
+use futures::stream::{Stream, TryStreamExt};
+use futures::TryFutureExt;
+use serde::Deserialize;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::RwLock;
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+const UPLOAD_PACK: &str = "git-upload-pack";
+/// The maximum size of the `git-upload-pack` request body in bytes, both compressed and decompressed.
+const MAX_REQUEST_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+struct InfoRefsQuery {
+    service: Option<String>,
+}
+
+#[tracing::instrument(skip(db_manager, auth_required, bare_path, path))]
+pub fn apis(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+    bare_path: Option<Arc<PathBuf>>,
+    path: Vec<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    info_refs(
+        db_manager.clone(),
+        auth_required,
+        bare_path.clone(),
+        path.clone(),
+    )
+    .or(upload_pack(db_manager, auth_required, bare_path, path))
+}
+
+#[tracing::instrument(skip(bare_path))]
+fn with_bare_path(
+    bare_path: Option<Arc<PathBuf>>,
+) -> impl Filter<Extract = (Arc<PathBuf>,), Error = Rejection> + Clone {
+    warp::any().and_then(move || {
+        let bare_path = bare_path.clone();
+        // the endpoints do not exist unless ktra hosts the index repository itself.
+        async move { bare_path.ok_or_else(warp::reject::not_found) }
+    })
+}
+
+#[tracing::instrument(skip(db_manager, auth_required, bare_path, path))]
+fn info_refs(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+    bare_path: Option<Arc<PathBuf>>,
+    path: Vec<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(into_boxed_filters(path))
+        .and(warp::path!("info" / "refs"))
+        .and(authorization_if_required(db_manager, auth_required))
+        .and(with_bare_path(bare_path))
+        .and(warp::query::<InfoRefsQuery>())
+        .and(warp::header::optional::<String>("Git-Protocol"))
+        .and_then(handle_info_refs)
+}
+
+#[tracing::instrument(skip(bare_path, query, git_protocol))]
+async fn handle_info_refs(
+    bare_path: Arc<PathBuf>,
+    query: InfoRefsQuery,
+    git_protocol: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    // the "dumb" HTTP protocol is not supported.
+    if query.service.as_deref() != Some(UPLOAD_PACK) {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Vec::new())
+            .map_err(Error::HttpResponseBuilding)
+            .map_err(warp::reject::custom);
+    }
+
+    let advertisement = run_upload_pack(bare_path, true, Bytes::new(), git_protocol)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    let mut body = pkt_line(&format!("# service={}\n", UPLOAD_PACK));
+    body.extend_from_slice(b"0000");
+    body.extend_from_slice(&advertisement);
+
+    Response::builder()
+        .header(
+            "Content-Type",
+            format!("application/x-{}-advertisement", UPLOAD_PACK),
+        )
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .map_err(Error::HttpResponseBuilding)
+        .map_err(warp::reject::custom)
+}
+
+#[tracing::instrument(skip(db_manager, auth_required, bare_path, path))]
+fn upload_pack(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+    bare_path: Option<Arc<PathBuf>>,
+    path: Vec<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(into_boxed_filters(path))
+        .and(warp::path(UPLOAD_PACK))
+        .and(warp::path::end())
+        .and(authorization_if_required(db_manager, auth_required))
+        .and(with_bare_path(bare_path))
+        .and(warp::header::optional::<String>("Content-Encoding"))
+        .and(warp::header::optional::<String>("Git-Protocol"))
+        .and(body_with_limit())
+        .and_then(handle_upload_pack)
+}
+
+/// Buffers the request body up to `MAX_REQUEST_SIZE` bytes.
+/// The chunked requests git clients send for large negotiations have no `Content-Length`
+/// so they are limited while the body is streamed.
+#[tracing::instrument]
+fn body_with_limit() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("Content-Length")
+        .and_then(|length: Option<u64>| async move {
+            match length {
+                Some(length) if length > MAX_REQUEST_SIZE => Err(warp::reject::custom(
+                    Error::BodyTooLarge(length, MAX_REQUEST_SIZE),
+                )),
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+        .and(warp::body::stream())
+        .and_then(|stream| read_body(stream).map_err(warp::reject::custom))
+}
+
+#[tracing::instrument(skip(stream))]
+async fn read_body(
+    stream: impl Stream<Item = Result<impl Buf, warp::Error>>,
+) -> Result<Bytes, Error> {
+    futures::pin_mut!(stream);
+
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.try_next().map_err(Error::Body).await? {
+        let length = (body.len() + chunk.remaining()) as u64;
+        if length > MAX_REQUEST_SIZE {
+            return Err(Error::BodyTooLarge(length, MAX_REQUEST_SIZE));
+        }
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(Bytes::from(body))
+}
+
+#[tracing::instrument(skip(bare_path, content_encoding, git_protocol, body))]
+async fn handle_upload_pack(
+    bare_path: Arc<PathBuf>,
+    content_encoding: Option<String>,
+    git_protocol: Option<String>,
+    body: Bytes,
+) -> Result<impl Reply, Rejection> {
+    // git clients compress large requests.
+    let body = if content_encoding.as_deref() == Some("gzip") {
+        // the body is decompressed one byte more than the limit to find that it exceeds the limit.
+        let mut decoded = Vec::new();
+        GzDecoder::new(&body[..])
+            .take(MAX_REQUEST_SIZE + 1)
+            .read_to_end(&mut decoded)
+            .map_err(Error::Io)
+            .map_err(warp::reject::custom)?;
+        if decoded.len() as u64 > MAX_REQUEST_SIZE {
+            return Err(warp::reject::custom(Error::BodyTooLarge(
+                decoded.len() as u64,
+                MAX_REQUEST_SIZE,
+            )));
+        }
+        Bytes::from(decoded)
+    } else {
+        body
+    };
+
+    let result = run_upload_pack(bare_path, false, body, git_protocol)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    Response::builder()
+        .header(
+            "Content-Type",
+            format!("application/x-{}-result", UPLOAD_PACK),
+        )
+        .header("Cache-Control", "no-cache")
+        .body(result)
+        .map_err(Error::HttpResponseBuilding)
+        .map_err(warp::reject::custom)
+}
+
+/// Runs `git upload-pack` in the stateless RPC mode against the bare index repository.
+#[tracing::instrument(skip(bare_path, input, git_protocol))]
+async fn run_upload_pack(
+    bare_path: Arc<PathBuf>,
+    advertise_refs: bool,
+    input: Bytes,
+    git_protocol: Option<String>,
+) -> Result<Vec<u8>, Error> {
+    let mut command = Command::new("git");
+    command.arg("upload-pack").arg("--stateless-rpc");
+    if advertise_refs {
+        command.arg("--advertise-refs");
+    }
+    command
+        .arg(bare_path.as_path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(git_protocol) = git_protocol {
+        command.env("GIT_PROTOCOL", git_protocol);
+    }
+
+    let mut child = command.spawn().map_err(Error::Io)?;
+    let mut stdin = child.stdin.take().expect("stdin must be piped");
+    let writer = tokio::spawn(async move {
+        stdin.write_all(&input).await?;
+        stdin.shutdown().await
+    });
+
+    let output = child.wait_with_output().map_err(Error::Io).await?;
+    writer.map_err(Error::Join).await?.map_err(Error::Io)?;
+
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(Error::GitProcess(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ))
+    }
+}
+
+#[tracing::instrument(skip(line))]
+fn pkt_line(line: &str) -> Vec<u8> {
+    format!("{:04x}{}", line.len() + 4, line).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "db-sled")]
+    use super::apis;
+    use super::pkt_line;
+    #[cfg(feature = "db-sled")]
+    use crate::test_utils;
+    #[cfg(feature = "db-sled")]
+    use warp::http::StatusCode;
+    #[cfg(feature = "db-sled")]
+    use warp::Filter;
+
+    #[cfg(feature = "db-sled")]
+    #[tokio::test]
+    async fn test_auth_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (_, token) = test_utils::add_user(&db_manager, "alice").await;
+        let apis =
+            apis(db_manager, true, None, vec!["git".to_owned()]).recover(crate::handle_rejection);
+
+        let unauthorized_requests = vec![
+            warp::test::request().path("/git/info/refs?service=git-upload-pack"),
+            warp::test::request()
+                .method("POST")
+                .path("/git/git-upload-pack"),
+            warp::test::request()
+                .path("/git/info/refs?service=git-upload-pack")
+                .header("Authorization", "invalid"),
+        ];
+        for request in unauthorized_requests {
+            let response = request.reply(&apis).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()["WWW-Authenticate"], "Cargo");
+        }
+
+        // the authorized request reaches the repository, which is not hosted here.
+        let response = warp::test::request()
+            .path("/git/info/refs?service=git-upload-pack")
+            .header("Authorization", token)
+            .reply(&apis)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[cfg(feature = "db-sled")]
+    #[tokio::test]
+    async fn test_request_size_limit() {
+        use super::MAX_REQUEST_SIZE;
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        use std::sync::Arc;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let bare_path = Some(Arc::new(dir.path().join("index.git")));
+        let apis = apis(db_manager, false, bare_path, vec!["git".to_owned()])
+            .recover(crate::handle_rejection);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/git/git-upload-pack")
+            .header("Content-Length", MAX_REQUEST_SIZE + 1)
+            .reply(&apis)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // the small body which is decompressed into a large one.
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder
+            .write_all(&vec![0u8; MAX_REQUEST_SIZE as usize + 1])
+            .unwrap();
+        let response = warp::test::request()
+            .method("POST")
+            .path("/git/git-upload-pack")
+            .header("Content-Encoding", "gzip")
+            .body(encoder.finish().unwrap())
+            .reply(&apis)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_pkt_line() {
+        assert_eq!(
+            pkt_line("# service=git-upload-pack\n"),
+            b"001e# service=git-upload-pack\n".to_vec()
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use crate::index_manager::IndexManager;
+use crate::utils::with_index_manager;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+#[tracing::instrument(skip(index_manager))]
+pub fn apis(
+    index_manager: Arc<IndexManager>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("ktra" / "api" / "v1" / "health"))
+        .and(with_index_manager(index_manager))
+        .and_then(handle_health)
+}
+
+#[tracing::instrument(skip(index_manager))]
+async fn handle_health(index_manager: Arc<IndexManager>) -> Result<impl Reply, Rejection> {
+    let status = index_manager.status().await;
+
+    // the registry cannot update the index until the conflicts are resolved by hand.
+    let status_code = if status.conflicted {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "index": status })),
+        status_code,
+    ))
+}
@@ -1,174 +1,752 @@
-use crate::config::IndexConfig;
+use crate::config::{IndexConfig, SigningMethod};
 use crate::error::Error;
-use crate::models::Package;
+use crate::models::{DeletionRecord, IndexConfigJson, Package};
 use crate::utils::package_dir_path;
 use futures::TryFutureExt;
 use git2::{
-    self, AnnotatedCommit, Commit, Cred, CredentialType, ObjectType, PushOptions, Reference,
-    Repository, Signature,
+    self, AnnotatedCommit, Commit, Cred, CredentialType, ErrorClass, ErrorCode, ObjectType, Oid,
+    PushOptions, Reference, Repository, ResetType, Signature,
 };
 use semver::Version;
-use std::io::SeekFrom;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
-use tokio::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot, Mutex, OwnedMutexGuard, RwLock};
+
+type QueuedEdit = (IndexEdit, oneshot::Sender<Result<(), Error>>);
+
+/// The longest interval between the push retries however many times the push is rejected.
+const MAX_PUSH_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The state of the local index repository reported by the health check.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexStatus {
+    /// Unix time in seconds at which the index was pulled successfully last.
+    pub last_pulled_at: Option<u64>,
+    /// The error of the last pull if it failed.
+    pub pull_error: Option<String>,
+    /// Whether the last pull failed because the remote changes conflict with the local ones.
+    pub conflicted: bool,
+}
+
+impl IndexStatus {
+    /// Records the result of fetching and merging the remote index.
+    #[tracing::instrument(skip(self, result))]
+    fn record_pull(&mut self, result: &Result<(), git2::Error>) {
+        match result {
+            Ok(()) => {
+                self.last_pulled_at = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .ok();
+                self.pull_error = None;
+                self.conflicted = false;
+            }
+            Err(e) => {
+                tracing::error!("failed to pull the index: {}", e);
+                self.pull_error = Some(e.to_string());
+                self.conflicted = e.code() == ErrorCode::Conflict;
+            }
+        }
+    }
+}
 
 pub struct IndexManager {
     config: IndexConfig,
     repository: Arc<Mutex<Repository>>,
+    queue: Option<mpsc::UnboundedSender<QueuedEdit>>,
+    status: Arc<RwLock<IndexStatus>>,
+    crate_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
 }
 
 impl IndexManager {
-    #[tracing::instrument(skip(config))]
-    pub async fn new(config: IndexConfig) -> Result<IndexManager, Error> {
+    #[tracing::instrument(skip(config, dl_path))]
+    pub async fn new(config: IndexConfig, dl_path: &[String]) -> Result<IndexManager, Error> {
         let repository = tokio::task::block_in_place(|| clone_or_open_repository(&config))
             .map(Mutex::new)
             .map(Arc::new)
             .map_err(Error::Git)?;
-        let manager = IndexManager { config, repository };
+        let mut manager = IndexManager {
+            config,
+            repository,
+            queue: None,
+            status: Default::default(),
+            crate_locks: Default::default(),
+        };
+        // the server starts even if the index conflicts with the remote one
+        // so that the health check reports the conflicts to be resolved by hand.
+        let conflicted = match manager.pull().await {
+            Ok(()) => false,
+            Err(Error::Git(e)) if e.code() == ErrorCode::Conflict => true,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(window) = manager.config.commit_batch_window_ms {
+            tracing::info!("batch index edits arriving within {} ms", window);
+
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(run_commit_queue(
+                manager.config.clone(),
+                manager.repository.clone(),
+                manager.status.clone(),
+                receiver,
+                Duration::from_millis(window),
+            ));
+            manager.queue = Some(sender);
+        }
+
+        if conflicted {
+            tracing::warn!("`config.json` is left untouched until the conflicts are resolved");
+        } else if let Some(config_json) = manager.config_json(dl_path) {
+            manager.reconcile_config_json(config_json).await?;
+        } else {
+            tracing::warn!("`public_url` is not set so `config.json` is left untouched");
+        }
+
         Ok(manager)
     }
 
+    /// Builds the expected `config.json` from the public URL and the crate files download path.
+    #[tracing::instrument(skip(self, dl_path))]
+    fn config_json(&self, dl_path: &[String]) -> Option<IndexConfigJson> {
+        let public_url = self.config.public_url.as_deref()?.trim_end_matches('/');
+
+        Some(IndexConfigJson {
+            dl: format!("{}/{}", public_url, dl_path.join("/")),
+            api: Some(public_url.to_owned()),
+            auth_required: self.config.auth_required,
+            others: Default::default(),
+        })
+    }
+
+    #[tracing::instrument(skip(self, expected))]
+    async fn reconcile_config_json(&self, mut expected: IndexConfigJson) -> Result<(), Error> {
+        let mut config_json_path = self.config.local_path.clone();
+        config_json_path.push("config.json");
+        let config_json_path = config_json_path;
+
+        let current = match tokio::fs::read_to_string(&config_json_path).await {
+            Ok(content) => match serde_json::from_str::<IndexConfigJson>(&content) {
+                Ok(current) => Some(current),
+                Err(e) => {
+                    tracing::warn!("existing `config.json` is invalid so overwrite it: {}", e);
+                    None
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        if let Some(current) = current {
+            expected.others = current.others.clone();
+
+            if current == expected {
+                tracing::info!("`config.json` is up to date");
+                return Ok(());
+            }
+        }
+
+        tracing::info!("update `config.json`: {:?}", expected);
+        self.update_index(IndexEdit::WriteConfigJson(expected))
+            .await
+    }
+
+    /// Fetches and merges the remote index, and records the result in the status.
     #[tracing::instrument(skip(self))]
     pub async fn pull(&self) -> Result<(), Error> {
         let repository = self.repository.lock().await;
-        tokio::task::block_in_place(|| {
+        let result = tokio::task::block_in_place(|| {
             let fetch_commit = fetch(&repository, &self.config)?;
             merge(&repository, &self.config, fetch_commit)?;
             repository.checkout_head(None)
+        });
+
+        self.status.write().await.record_pull(&result);
+        result.map_err(Error::Git)
+    }
+
+    /// Pulls the index every `period` until the manager is dropped.
+    ///
+    /// Only a weak reference is held between the pulls so that the task does not keep the manager alive.
+    #[tracing::instrument(skip(self, period))]
+    pub async fn pull_periodically(self: Arc<Self>, period: Duration) {
+        let manager = Arc::downgrade(&self);
+        drop(self);
+        let mut interval = tokio::time::interval(period);
+        // the first tick completes immediately but the index has been pulled on startup.
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            let manager = match manager.upgrade() {
+                Some(manager) => manager,
+                None => break,
+            };
+            // the error is logged and recorded in the status by `pull`.
+            drop(manager.pull().await);
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn status(&self) -> IndexStatus {
+        self.status.read().await.clone()
+    }
+
+    /// Reads the file in the local index with its modified time.
+    ///
+    /// The file is read under the repository lock so that neither the file being written
+    /// nor the edits which are reset because their push fails are served.
+    #[tracing::instrument(skip(self, path))]
+    pub async fn read_file(&self, path: impl AsRef<Path>) -> Result<(Vec<u8>, SystemTime), Error> {
+        let _repository = self.repository.lock().await;
+        let mut file_path = self.config.local_path.clone();
+        file_path.push(path);
+        let file_path = file_path;
+
+        let content = tokio::fs::read(&file_path).map_err(Error::Io).await?;
+        let modified = tokio::fs::metadata(&file_path)
+            .map_err(Error::Io)
+            .await?
+            .modified()
+            .map_err(Error::Io)?;
+        Ok((content, modified))
+    }
+
+    /// Reads all packages registered in the index.
+    #[tracing::instrument(skip(self))]
+    pub async fn packages(&self) -> Result<Vec<Package>, Error> {
+        let _repository = self.repository.lock().await;
+        tokio::task::block_in_place(|| {
+            let mut packages = Vec::new();
+            collect_packages(
+                &self.config.local_path,
+                &self.config.local_path,
+                &mut packages,
+            )?;
+            Ok(packages)
         })
-        .map_err(Error::Git)
     }
 
     #[tracing::instrument(skip(self, package))]
     pub async fn add_package(&self, package: Package) -> Result<(), Error> {
-        let name = package.name.to_ascii_lowercase();
+        self.update_index(IndexEdit::AddPackage(package)).await
+    }
 
-        let mut index_path = self.config.local_path.clone();
-        index_path.push(package_dir_path(&name)?);
-        tokio::fs::create_dir_all(&index_path)
-            .map_err(Error::Io)
-            .await?;
-
-        index_path.push(&name);
-        let package_path = index_path;
-        let package_json_string = package.to_json_string().map_err(Error::Serialization)?;
-
-        tracing::debug!("try to open or create index file");
-
-        let mut file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(package_path)
-            .await?;
-
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).await?;
-        let content = buf
-            .lines()
-            .chain(std::iter::once(package_json_string.as_str()))
-            .collect::<Vec<_>>()
-            .join("\n");
-
-        file.set_len(0).await?;
-        file.seek(SeekFrom::Start(0)).await?;
-        file.write_all(content.as_bytes()).await?;
-        file.sync_all().await?;
-
-        let message = format!("Updating crate `{}#{}`", package.name, package.vers);
-        let repository = self.repository.lock().await;
-        tokio::task::block_in_place(|| {
-            add_all(&repository)?;
-            commit(&repository, &self.config, message)?;
-            push_to_origin(&repository, &self.config)
+    /// Replaces all packages in the index with `packages` in a single commit.
+    #[tracing::instrument(skip(self, packages))]
+    pub async fn rebuild(&self, packages: Vec<Package>) -> Result<(), Error> {
+        self.update_index(IndexEdit::Rebuild(packages)).await
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    pub async fn yank(&self, name: impl Into<String>, version: Version) -> Result<(), Error> {
+        self.update_index(IndexEdit::ChangeYanked {
+            name: name.into(),
+            version,
+            yanked: true,
         })
-        .map_err(Error::Git)
+        .await
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    pub async fn unyank(&self, name: impl Into<String>, version: Version) -> Result<(), Error> {
+        self.update_index(IndexEdit::ChangeYanked {
+            name: name.into(),
+            version,
+            yanked: false,
+        })
+        .await
+    }
+
+    /// Locks the crate so that the concurrent changes of its yanked states are applied
+    /// to the index and the database in the same order.
+    #[tracing::instrument(skip(self, name))]
+    pub async fn lock_crate(&self, name: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .crate_locks
+            .lock()
+            .await
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Removes the version from the index with the deletion record in the commit message.
+    #[tracing::instrument(skip(self, record))]
+    pub async fn remove_package(&self, record: DeletionRecord) -> Result<(), Error> {
+        self.update_index(IndexEdit::RemovePackage(record)).await
+    }
+
+    /// Commits the edit and returns after the commit is pushed to the origin.
+    ///
+    /// The edit is passed to the commit queue to be committed with the others if batching is enabled.
+    #[tracing::instrument(skip(self, edit))]
+    async fn update_index(&self, edit: IndexEdit) -> Result<(), Error> {
+        if let Some(queue) = &self.queue {
+            let (sender, receiver) = oneshot::channel();
+            queue
+                .send((edit, sender))
+                .map_err(|_| Error::CommitQueueClosed)?;
+            receiver.await.map_err(|_| Error::CommitQueueClosed)?
+        } else {
+            commit_edits(&self.config, &self.repository, &self.status, &[edit])
+                .await?
+                .pop()
+                .expect("the result of the edit must exist")
+        }
+    }
+}
+
+/// Receives the edits from the queue and commits the ones arriving within `window` at once.
+#[tracing::instrument(skip(config, repository, status, receiver, window))]
+async fn run_commit_queue(
+    config: IndexConfig,
+    repository: Arc<Mutex<Repository>>,
+    status: Arc<RwLock<IndexStatus>>,
+    mut receiver: mpsc::UnboundedReceiver<QueuedEdit>,
+    window: Duration,
+) {
+    while let Some(queued_edit) = receiver.recv().await {
+        let mut queued_edits = vec![queued_edit];
+        let deadline = tokio::time::Instant::now() + window;
+        while let Ok(Some(queued_edit)) = tokio::time::timeout_at(deadline, receiver.recv()).await {
+            queued_edits.push(queued_edit);
+        }
+
+        let (edits, senders): (Vec<_>, Vec<_>) = queued_edits.into_iter().unzip();
+        tracing::info!("commit {} edits in a batch", edits.len());
+
+        // the receivers may have been dropped when the requests are cancelled so ignore send errors.
+        match commit_edits(&config, &repository, &status, &edits).await {
+            Ok(results) => senders
+                .into_iter()
+                .zip(results)
+                .for_each(|(sender, result)| drop(sender.send(result))),
+            Err(e) if senders.len() == 1 => {
+                if let Some(sender) = senders.into_iter().next() {
+                    drop(sender.send(Err(e)));
+                }
+            }
+            Err(e) => {
+                let e = Arc::new(e);
+                senders
+                    .into_iter()
+                    .for_each(|sender| drop(sender.send(Err(Error::BatchCommit(e.clone())))));
+            }
+        }
+    }
+}
+
+/// Applies the edits, commits them in a single commit and pushes it to the origin.
+///
+/// Returns the results of applying each edit. The edits failed to be applied are left out of
+/// the commit, and an error is returned only if committing or pushing the others fails.
+/// The files an edit has changed before it fails are restored before the next edit is applied.
+/// If any step fails, the working tree and the branch are reset to the state before the edits
+/// so that no unpushed commits are left behind in the local repository.
+/// If the push is rejected because the remote branch has moved on, the latest commit is
+/// fetched and merged, and then the edits are applied again. The result of the merge is
+/// recorded in `status` as the pull is.
+#[tracing::instrument(skip(config, repository, status, edits))]
+async fn commit_edits(
+    config: &IndexConfig,
+    repository: &Mutex<Repository>,
+    status: &RwLock<IndexStatus>,
+    edits: &[IndexEdit],
+) -> Result<Vec<Result<(), Error>>, Error> {
+    let repository = repository.lock().await;
+    let mut retries = 0;
+
+    loop {
+        let head = tokio::task::block_in_place(|| find_last_commit(&repository))
+            .map(|c| c.id())
+            .map_err(Error::Git)?;
+
+        let results = match tokio::task::block_in_place(|| apply_edits(&repository, config, edits))
+        {
+            Ok(results) => results,
+            Err(e) => {
+                tokio::task::block_in_place(|| reset_to(&repository, head)).map_err(Error::Git)?;
+                return Err(Error::Git(e));
+            }
+        };
+        let messages: Vec<_> = edits
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_ok())
+            .map(|(edit, _)| edit.message())
+            .collect();
+
+        let result = if messages.is_empty() {
+            Err(None)
+        } else {
+            tokio::task::block_in_place(|| {
+                add_all(&repository)?;
+                // e.g. yanking the version yanked in the index already changes nothing.
+                if !has_staged_changes(&repository)? {
+                    tracing::info!("nothing to commit");
+                    return Ok(());
+                }
+                commit(&repository, config, commit_message(&messages))?;
+                push_to_origin(&repository, config)
+            })
+            .map_err(Some)
+        };
+
+        let error = match result {
+            Ok(()) => return Ok(results),
+            Err(e) => e,
+        };
+
+        tokio::task::block_in_place(|| reset_to(&repository, head)).map_err(Error::Git)?;
+
+        match error {
+            // no edits are applied.
+            None => return Ok(results),
+            Some(e) if e.code() == ErrorCode::NotFastForward => {
+                if retries >= config.push_max_retries {
+                    tracing::error!("push is rejected {} times: {}", retries + 1, e);
+                    return Err(Error::PushRejected(retries + 1));
+                }
+
+                let backoff = push_retry_backoff(config.push_retry_backoff_ms, retries);
+                retries += 1;
+                tracing::warn!(
+                    "push is rejected so retry after {} ms ({}/{}): {}",
+                    backoff.as_millis(),
+                    retries,
+                    config.push_max_retries,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+
+                let result = tokio::task::block_in_place(|| {
+                    let fetch_commit = fetch(&repository, config)?;
+                    merge(&repository, config, fetch_commit)
+                });
+                status.write().await.record_pull(&result);
+                result.map_err(Error::Git)?;
+            }
+            Some(e) => return Err(Error::Git(e)),
+        }
     }
+}
 
-    #[tracing::instrument(skip(self, name, version, yanked))]
-    async fn change_yanked(
-        &self,
-        name: impl Into<String>,
+/// Applies the edits one by one and stages the changes of each edit applied successfully,
+/// so that the changes of the failed edit can be discarded by checking out the index.
+#[tracing::instrument(skip(repository, config, edits))]
+fn apply_edits(
+    repository: &Repository,
+    config: &IndexConfig,
+    edits: &[IndexEdit],
+) -> Result<Vec<Result<(), Error>>, git2::Error> {
+    let mut results = Vec::with_capacity(edits.len());
+    for edit in edits {
+        match edit.apply(&config.local_path) {
+            Ok(()) => {
+                add_all(repository)?;
+                results.push(Ok(()));
+            }
+            Err(e) => {
+                tracing::warn!("failed to apply the edit so discard its changes: {}", e);
+                let mut checkout_builder = git2::build::CheckoutBuilder::default();
+                checkout_builder.force().remove_untracked(true);
+                repository.checkout_index(None, Some(&mut checkout_builder))?;
+                results.push(Err(e));
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Returns the exponential backoff before the retry after `retries` retries,
+/// which is capped at `MAX_PUSH_RETRY_BACKOFF`.
+#[tracing::instrument]
+fn push_retry_backoff(backoff_ms: u64, retries: usize) -> Duration {
+    let factor = u32::try_from(retries)
+        .ok()
+        .and_then(|retries| 1u64.checked_shl(retries))
+        .unwrap_or(u64::MAX);
+    Duration::from_millis(backoff_ms.saturating_mul(factor)).min(MAX_PUSH_RETRY_BACKOFF)
+}
+
+#[tracing::instrument(skip(messages))]
+fn commit_message(messages: &[String]) -> String {
+    match messages {
+        [message] => message.clone(),
+        _ => format!(
+            "Updating {} index entries\n\n{}",
+            messages.len(),
+            messages.join("\n")
+        ),
+    }
+}
+
+/// A change to the index files.
+///
+/// Edits are applied to the working tree again whenever the push is retried,
+/// so they must not depend on the state the first attempt has left.
+#[derive(Debug, Clone)]
+enum IndexEdit {
+    AddPackage(Package),
+    ChangeYanked {
+        name: String,
         version: Version,
         yanked: bool,
-    ) -> Result<(), Error> {
-        let name = name.into();
-        let mut index_path = self.config.local_path.clone();
-        index_path.push(package_dir_path(&name)?);
-        index_path.push(&name);
-        let package_path = index_path;
-
-        tracing::debug!("try to open index file");
-
-        let version_cloned = version.clone();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(package_path)
-            .await?;
-
-        let mut buf = String::new();
-        file.read_to_string(&mut buf).map_err(Error::Io).await?;
-        let (oks, errors): (Vec<_>, Vec<_>) = buf
-            .lines()
-            .map(|l| serde_json::from_str::<Package>(l).map_err(Error::InvalidJson))
-            .partition(Result::is_ok);
-
-        if !errors.is_empty() {
-            return Err(Error::multiple(errors));
+    },
+    RemovePackage(DeletionRecord),
+    WriteConfigJson(IndexConfigJson),
+    Rebuild(Vec<Package>),
+}
+
+impl IndexEdit {
+    #[tracing::instrument(skip(self))]
+    fn message(&self) -> String {
+        match self {
+            IndexEdit::AddPackage(package) => {
+                format!("Updating crate `{}#{}`", package.name, package.vers)
+            }
+            IndexEdit::ChangeYanked {
+                name,
+                version,
+                yanked: true,
+            } => format!("Yanking crate `{}#{}`", name, version),
+            IndexEdit::ChangeYanked {
+                name,
+                version,
+                yanked: false,
+            } => format!("Unyanking crate `{}#{}`", name, version),
+            IndexEdit::RemovePackage(record) => format!(
+                "Deleting crate `{}#{}` by {}: {}",
+                record.name, record.vers, record.deleted_by, record.reason
+            ),
+            IndexEdit::WriteConfigJson(_) => "Updating config.json".to_owned(),
+            IndexEdit::Rebuild(packages) => {
+                format!("Rebuilding the index with {} packages", packages.len())
+            }
         }
+    }
 
-        let (oks, errors): (Vec<_>, Vec<_>) = oks
-            .into_iter()
-            .map(Result::unwrap)
-            .map(|mut p| {
-                if p.vers == version_cloned {
-                    p.yanked = yanked;
+    #[tracing::instrument(skip(self, local_path))]
+    fn apply(&self, local_path: &Path) -> Result<(), Error> {
+        match self {
+            IndexEdit::AddPackage(package) => {
+                let name = package.name.to_ascii_lowercase();
+                let package_path = package_path(local_path, &name)?;
+                if let Some(dir_path) = package_path.parent() {
+                    std::fs::create_dir_all(dir_path)?;
                 }
-                p.to_json_string().map_err(Error::InvalidJson)
-            })
-            .partition(Result::is_ok);
 
-        if !errors.is_empty() {
-            return Err(Error::multiple(errors));
+                tracing::debug!("try to open or create index file");
+
+                // the existing lines are kept as they are so that the fields
+                // `Package` does not know are not dropped.
+                let mut lines = read_lines(&package_path)?;
+                if lines
+                    .iter()
+                    .any(|line| is_version_line(line, &package.vers))
+                {
+                    return Err(Error::VersionExists(
+                        package.name.clone(),
+                        package.vers.clone(),
+                    ));
+                }
+                lines.push(package.to_json_string().map_err(Error::Serialization)?);
+
+                std::fs::write(&package_path, lines.join("\n")).map_err(Error::Io)
+            }
+            IndexEdit::ChangeYanked {
+                name,
+                version,
+                yanked,
+            } => {
+                let package_path = package_path(local_path, &name.to_ascii_lowercase())?;
+
+                tracing::debug!("try to open index file");
+
+                if !package_path.exists() {
+                    return Err(Error::Io(std::io::ErrorKind::NotFound.into()));
+                }
+
+                // only the `yanked` field of the version is changed so that the other lines
+                // and the fields `Package` does not know are kept as they are.
+                let mut lines = read_lines(&package_path)?;
+                let line = lines
+                    .iter_mut()
+                    .find(|line| is_version_line(line, version))
+                    .ok_or_else(|| Error::Io(std::io::ErrorKind::NotFound.into()))?;
+                let mut fields: serde_json::Map<String, serde_json::Value> =
+                    serde_json::from_str(line).map_err(Error::InvalidJson)?;
+                fields.insert("yanked".to_owned(), serde_json::Value::Bool(*yanked));
+                *line = serde_json::to_string(&fields).map_err(Error::Serialization)?;
+
+                std::fs::write(&package_path, lines.join("\n")).map_err(Error::Io)
+            }
+            IndexEdit::RemovePackage(record) => {
+                let package_path = package_path(local_path, &record.name.to_ascii_lowercase())?;
+
+                tracing::debug!("try to open index file");
+
+                if !package_path.exists() {
+                    return Err(Error::Io(std::io::ErrorKind::NotFound.into()));
+                }
+
+                let mut lines = read_lines(&package_path)?;
+                let len = lines.len();
+                lines.retain(|line| !is_version_line(line, &record.vers));
+                if lines.len() == len {
+                    return Err(Error::Io(std::io::ErrorKind::NotFound.into()));
+                }
+
+                // the index file is removed with the last version like the unpublished crates.
+                if lines.iter().all(|line| line.trim().is_empty()) {
+                    std::fs::remove_file(&package_path).map_err(Error::Io)
+                } else {
+                    std::fs::write(&package_path, lines.join("\n")).map_err(Error::Io)
+                }
+            }
+            IndexEdit::WriteConfigJson(config_json) => {
+                let mut config_json_path = local_path.to_path_buf();
+                config_json_path.push("config.json");
+
+                let content =
+                    serde_json::to_string_pretty(config_json).map_err(Error::Serialization)?;
+                std::fs::write(config_json_path, content).map_err(Error::Io)
+            }
+            IndexEdit::Rebuild(packages) => {
+                // remove everything but `.git` and `config.json`.
+                for dir_entry in std::fs::read_dir(local_path)? {
+                    let dir_entry = dir_entry?;
+                    let file_name = dir_entry.file_name();
+
+                    if file_name.to_string_lossy().starts_with('.') || file_name == "config.json" {
+                        continue;
+                    } else if dir_entry.file_type()?.is_dir() {
+                        std::fs::remove_dir_all(dir_entry.path())?;
+                    } else {
+                        std::fs::remove_file(dir_entry.path())?;
+                    }
+                }
+
+                let mut packages_by_name: BTreeMap<String, Vec<Package>> = BTreeMap::new();
+                for package in packages {
+                    packages_by_name
+                        .entry(package.name.to_ascii_lowercase())
+                        .or_default()
+                        .push(package.clone());
+                }
+
+                for (name, mut packages) in packages_by_name {
+                    let package_path = package_path(local_path, &name)?;
+                    if let Some(dir_path) = package_path.parent() {
+                        std::fs::create_dir_all(dir_path)?;
+                    }
+
+                    packages.sort_by(|a, b| a.vers.cmp(&b.vers));
+                    write_packages(&package_path, &packages)?;
+                }
+
+                Ok(())
+            }
         }
+    }
+}
 
-        let content = oks
-            .into_iter()
-            .map(Result::unwrap)
-            .collect::<Vec<_>>()
-            .join("\n");
+#[tracing::instrument(skip(local_path, name))]
+fn package_path(local_path: &Path, name: &str) -> Result<PathBuf, Error> {
+    let mut package_path = local_path.to_path_buf();
+    package_path.push(package_dir_path(name)?);
+    package_path.push(name);
+    Ok(package_path)
+}
 
-        file.set_len(0).await?;
-        file.seek(SeekFrom::Start(0)).await?;
-        file.write_all(content.as_bytes()).await?;
-        file.sync_all().await?;
+/// The version of a line in the index file, which is read without the other fields
+/// so that the lines of any schema can be kept as they are.
+#[derive(Debug, Deserialize)]
+struct IndexLine {
+    vers: Version,
+}
 
-        let message = if yanked {
-            format!("Yanking crate `{}#{}`", name, version)
-        } else {
-            format!("Unyanking crate `{}#{}`", name, version)
-        };
-        let repository = self.repository.lock().await;
-        tokio::task::block_in_place(|| {
-            add_all(&repository)?;
-            commit(&repository, &self.config, message)?;
-            push_to_origin(&repository, &self.config)
-        })
-        .map_err(Error::Git)
+#[tracing::instrument(skip(line, version))]
+fn is_version_line(line: &str, version: &Version) -> bool {
+    matches!(serde_json::from_str::<IndexLine>(line), Ok(l) if &l.vers == version)
+}
+
+/// Reads the lines in the index file. The file which does not exist is regarded as empty.
+#[tracing::instrument(skip(package_path))]
+fn read_lines(package_path: &Path) -> Result<Vec<String>, Error> {
+    match std::fs::read_to_string(package_path) {
+        Ok(content) => Ok(content.lines().map(ToOwned::to_owned).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::Io(e)),
     }
+}
 
-    #[tracing::instrument(skip(self, name, version))]
-    pub async fn yank(&self, name: impl Into<String>, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, true).await
+/// Reads all packages in the index file. The file which does not exist is regarded as empty.
+#[tracing::instrument(skip(package_path))]
+fn read_packages(package_path: &Path) -> Result<Vec<Package>, Error> {
+    let (oks, errors): (Vec<_>, Vec<_>) = read_lines(package_path)?
+        .iter()
+        .map(|l| serde_json::from_str::<Package>(l).map_err(Error::InvalidJson))
+        .partition(Result::is_ok);
+
+    if errors.is_empty() {
+        Ok(oks.into_iter().map(Result::unwrap).collect())
+    } else {
+        Err(Error::multiple(errors))
     }
+}
 
-    #[tracing::instrument(skip(self, name, version))]
-    pub async fn unyank(&self, name: impl Into<String>, version: Version) -> Result<(), Error> {
-        self.change_yanked(name, version, false).await
+/// Collects packages in the index files under `dir_path` recursively.
+/// The files which are not placed as `package_dir_path` describes (e.g. `config.json`) are ignored.
+#[tracing::instrument(skip(local_path, dir_path, packages))]
+fn collect_packages(
+    local_path: &Path,
+    dir_path: &Path,
+    packages: &mut Vec<Package>,
+) -> Result<(), Error> {
+    for dir_entry in std::fs::read_dir(dir_path)? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let file_name = match dir_entry.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(_) => continue,
+        };
+
+        if file_name.starts_with('.') {
+            continue;
+        } else if dir_entry.file_type()?.is_dir() {
+            collect_packages(local_path, &path, packages)?;
+        } else if matches!(package_path(local_path, &file_name), Ok(p) if p == path) {
+            packages.extend(read_packages(&path)?);
+        }
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(package_path, packages))]
+fn write_packages(package_path: &Path, packages: &[Package]) -> Result<(), Error> {
+    let (oks, errors): (Vec<_>, Vec<_>) = packages
+        .iter()
+        .map(|p| p.to_json_string().map_err(Error::InvalidJson))
+        .partition(Result::is_ok);
+
+    if !errors.is_empty() {
+        return Err(Error::multiple(errors));
     }
+
+    let content = oks
+        .into_iter()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .join("\n");
+    std::fs::write(package_path, content).map_err(Error::Io)
 }
 
 #[tracing::instrument(skip(config))]
@@ -208,7 +786,11 @@ fn clone_or_open_repository(config: &IndexConfig) -> Result<git2::Repository, gi
 
     if path.exists() {
         tracing::info!("open index repository: {:?}", path);
-        git2::Repository::open(path)
+        let repository = git2::Repository::open(path)?;
+        if let Some(bare_path) = config.bare_path.as_deref() {
+            check_cloned_from(&repository, bare_path)?;
+        }
+        Ok(repository)
     } else {
         tracing::info!("try to clone index repository into {:?}", path);
 
@@ -221,10 +803,76 @@ fn clone_or_open_repository(config: &IndexConfig) -> Result<git2::Repository, gi
         builder.branch(&config.branch);
         builder.fetch_options(fetch_options);
 
-        builder.clone(&config.remote_url, path)
+        if let Some(bare_path) = config.bare_path.as_deref() {
+            init_or_open_bare_repository(bare_path, config)?;
+            let bare_path = bare_path
+                .to_str()
+                .ok_or_else(|| git2::Error::from_str("bare repository path is not valid UTF-8"))?;
+            builder.clone(bare_path, path)
+        } else {
+            builder.clone(&config.remote_url, path)
+        }
     }
 }
 
+/// Checks that the existing checkout is cloned from the bare repository,
+/// because the bare repository is only set up when the checkout is cloned.
+#[tracing::instrument(skip(repository, bare_path))]
+fn check_cloned_from(repository: &git2::Repository, bare_path: &Path) -> Result<(), git2::Error> {
+    let remote = repository.find_remote("origin")?;
+    let cloned_from_bare_path = match (remote.url().map(Path::new), bare_path.canonicalize()) {
+        (Some(url), Ok(bare_path)) => url.canonicalize().ok() == Some(bare_path),
+        _ => false,
+    };
+
+    if cloned_from_bare_path {
+        Ok(())
+    } else {
+        Err(git2::Error::from_str(&format!(
+            "the index repository in {:?} is not cloned from the bare repository in {:?}; \
+             move it away to clone the bare repository",
+            repository.workdir().unwrap_or_else(|| repository.path()),
+            bare_path
+        )))
+    }
+}
+
+#[tracing::instrument(skip(bare_path, config))]
+fn init_or_open_bare_repository(
+    bare_path: &Path,
+    config: &IndexConfig,
+) -> Result<git2::Repository, git2::Error> {
+    let repository = if bare_path.exists() {
+        tracing::info!("open bare index repository: {:?}", bare_path);
+        git2::Repository::open_bare(bare_path)?
+    } else {
+        tracing::info!("initialize bare index repository: {:?}", bare_path);
+        let mut options = git2::RepositoryInitOptions::new();
+        options.bare(true).initial_head(&config.branch);
+        git2::Repository::init_opts(bare_path, &options)?
+    };
+
+    let refname = format!("refs/heads/{}", config.branch);
+    if repository.find_reference(&refname).is_err() {
+        tracing::info!("create the initial commit on {}", config.branch);
+
+        let tree_id = repository.treebuilder(None)?.write()?;
+        let tree = repository.find_tree(tree_id)?;
+        let signature = signature(config)?;
+        create_commit(
+            &repository,
+            config,
+            &refname,
+            &signature,
+            "Initial commit",
+            &tree,
+            &[],
+        )?;
+    }
+
+    Ok(repository)
+}
+
 #[tracing::instrument(skip(repository, config))]
 fn fetch<'a>(
     repository: &'a Repository,
@@ -267,9 +915,10 @@ fn fast_forward(
     repository.checkout_head(Some(&mut checkout_builder))
 }
 
-#[tracing::instrument(skip(repository, local_commit, remote_commit))]
+#[tracing::instrument(skip(repository, config, local_commit, remote_commit))]
 fn normal_merge(
     repository: &Repository,
+    config: &IndexConfig,
     local_commit: &AnnotatedCommit,
     remote_commit: &AnnotatedCommit,
 ) -> Result<(), git2::Error> {
@@ -282,28 +931,40 @@ fn normal_merge(
         .tree()?;
     let mut index = repository.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
 
+    // the conflicts must be resolved in the remote repository by hand
+    // so leave the working tree as it is instead of checking out the conflict markers.
     if index.has_conflicts() {
-        return repository.checkout_index(Some(&mut index), None);
+        let paths = index
+            .conflicts()?
+            .filter_map(Result::ok)
+            .filter_map(|c| c.our.or(c.their).or(c.ancestor))
+            .map(|e| String::from_utf8_lossy(&e.path).into_owned())
+            .collect::<Vec<_>>();
+
+        return Err(git2::Error::new(
+            ErrorCode::Conflict,
+            ErrorClass::Merge,
+            format!("merge conflicts in {}", paths.join(", ")),
+        ));
     }
 
     let oid = index.write_tree_to(&repository)?;
     let result_tree = repository.find_tree(oid)?;
 
     let message = format!("Merge: {} into {}", remote_commit.id(), local_commit.id());
-    let signature = repository.signature()?;
+    let signature = signature(config)?;
     let local_commit = repository.find_commit(local_commit.id())?;
     let remote_commit = repository.find_commit(remote_commit.id())?;
 
-    repository
-        .commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            &message,
-            &result_tree,
-            &[&local_commit, &remote_commit],
-        )
-        .map(drop)?;
+    create_commit(
+        repository,
+        config,
+        "HEAD",
+        &signature,
+        &message,
+        &result_tree,
+        &[&local_commit, &remote_commit],
+    )?;
     repository.checkout_head(None)
 }
 
@@ -340,7 +1001,7 @@ fn merge(
         }
     } else if analysis.0.is_normal() {
         let head_commit = repository.reference_to_annotated_commit(&repository.head()?)?;
-        normal_merge(repository, &head_commit, &fetch_commit)
+        normal_merge(repository, config, &head_commit, &fetch_commit)
     } else {
         tracing::info!("nothing to do");
         Ok(())
@@ -353,9 +1014,18 @@ fn add_all(repository: &Repository) -> Result<(), git2::Error> {
 
     let mut index = repository.index()?;
     index.add_all(std::iter::once("."), git2::IndexAddOption::DEFAULT, None)?;
+    // `add_all` does not stage the removed files.
+    index.update_all(std::iter::once("."), None)?;
     index.write()
 }
 
+/// Returns whether the staged tree differs from the tree of the last commit.
+#[tracing::instrument(skip(repository))]
+fn has_staged_changes(repository: &Repository) -> Result<bool, git2::Error> {
+    let tree_id = repository.index()?.write_tree()?;
+    Ok(find_last_commit(repository)?.tree_id() != tree_id)
+}
+
 #[tracing::instrument(skip(repository))]
 fn find_last_commit(repository: &Repository) -> Result<Commit, git2::Error> {
     let obj = repository.head()?.resolve()?.peel(ObjectType::Commit)?;
@@ -375,22 +1045,123 @@ fn commit(
     let oid = index.write_tree_to(repository)?;
     let tree = repository.find_tree(oid)?;
     let last_commit = find_last_commit(repository)?;
-    let signature = Signature::now(
+    let signature = signature(config)?;
+
+    create_commit(
+        repository,
+        config,
+        "HEAD",
+        &signature,
+        message.as_ref(),
+        &tree,
+        &[&last_commit],
+    )?;
+    repository.checkout_head(None)
+}
+
+/// Returns the signature of the commits the server makes, including the merge commits.
+#[tracing::instrument(skip(config))]
+fn signature(config: &IndexConfig) -> Result<Signature<'static>, git2::Error> {
+    Signature::now(
         &config.name,
         config.email.as_deref().unwrap_or("undefined@example.com"),
-    )?;
+    )
+}
 
-    repository
-        .commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            message.as_ref(),
-            &tree,
-            &[&last_commit],
-        )
-        .map(drop)?;
-    repository.checkout_head(None)
+/// Creates a commit, signing it if `signing_method` is configured, and updates `refname` to it.
+#[tracing::instrument(skip(repository, config, refname, signature, message, tree, parents))]
+fn create_commit(
+    repository: &Repository,
+    config: &IndexConfig,
+    refname: &str,
+    signature: &Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&Commit],
+) -> Result<Oid, git2::Error> {
+    let signing_method = match config.signing_method {
+        Some(signing_method) => signing_method,
+        None => {
+            return repository.commit(Some(refname), signature, signature, message, tree, parents)
+        }
+    };
+
+    let buffer = repository.commit_create_buffer(signature, signature, message, tree, parents)?;
+    let content = buffer
+        .as_str()
+        .ok_or_else(|| git2::Error::from_str("commit content is not valid UTF-8"))?;
+    let commit_signature = sign(signing_method, config.signing_key.as_deref(), content)?;
+    let oid = repository.commit_signed(content, &commit_signature, None)?;
+
+    let reflog_message = format!("commit: {}", message.lines().next().unwrap_or_default());
+    match repository.find_reference(refname).and_then(|r| r.resolve()) {
+        Ok(mut reference) => reference.set_target(oid, &reflog_message).map(drop)?,
+        // the branch does not exist yet.
+        Err(_) => repository
+            .reference(refname, oid, true, &reflog_message)
+            .map(drop)?,
+    }
+
+    Ok(oid)
+}
+
+/// Signs the commit content with `gpg` or `ssh-keygen` and returns the armored signature.
+#[tracing::instrument(skip(signing_method, signing_key, content))]
+fn sign(
+    signing_method: SigningMethod,
+    signing_key: Option<&str>,
+    content: &str,
+) -> Result<String, git2::Error> {
+    tracing::debug!("sign the commit with {:?}", signing_method);
+
+    let mut command = match signing_method {
+        SigningMethod::Gpg => {
+            let mut command = std::process::Command::new("gpg");
+            command.args(["--batch", "--detach-sign", "--armor"]);
+            if let Some(signing_key) = signing_key {
+                command.args(["--local-user", signing_key]);
+            }
+            command
+        }
+        SigningMethod::Ssh => {
+            let signing_key = signing_key.ok_or_else(|| {
+                git2::Error::from_str("`signing_key` is required to sign with SSH")
+            })?;
+            let mut command = std::process::Command::new("ssh-keygen");
+            command.args(["-Y", "sign", "-n", "git", "-f", signing_key]);
+            command
+        }
+    };
+
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| git2::Error::from_str(&format!("failed to run the signing program: {}", e)))?;
+
+    // the signature is written after all the content is read so the output cannot block writing.
+    child
+        .stdin
+        .take()
+        .expect("stdin must be piped")
+        .write_all(content.as_bytes())
+        .map_err(|e| {
+            git2::Error::from_str(&format!("failed to write the commit content: {}", e))
+        })?;
+    let output = child
+        .wait_with_output()
+        .map_err(|e| git2::Error::from_str(&format!("failed to run the signing program: {}", e)))?;
+
+    if output.status.success() {
+        String::from_utf8(output.stdout)
+            .map_err(|_| git2::Error::from_str("the signature is not valid UTF-8"))
+    } else {
+        Err(git2::Error::from_str(&format!(
+            "failed to sign the commit: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )))
+    }
 }
 
 #[tracing::instrument(skip(repository, config))]
@@ -398,11 +1169,620 @@ fn push_to_origin(repository: &Repository, config: &IndexConfig) -> Result<(), g
     tracing::debug!("push commits to origin");
     let mut remote = repository.find_remote("origin")?;
 
-    let mut callbacks = git2::RemoteCallbacks::new();
-    callbacks.credentials(credentials_callback(config));
-    let mut push_options = PushOptions::default();
-    push_options.remote_callbacks(callbacks);
+    // only the code of the error returned from the callback reaches the result of the push,
+    // so the status is kept to report the actual cause.
+    let mut rejection = None;
+    let result = {
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(credentials_callback(config));
+        callbacks.push_update_reference(|refname, status| match status {
+            Some(status) => {
+                tracing::warn!("the remote rejected {}: {}", refname, status);
+                rejection = Some((refname.to_owned(), status.to_owned()));
+                Err(push_rejection(refname, status))
+            }
+            None => Ok(()),
+        });
+        let mut push_options = PushOptions::default();
+        push_options.remote_callbacks(callbacks);
+
+        let refs = format!("refs/heads/{0}:refs/heads/{}", config.branch);
+        remote.push(&[refs], Some(&mut push_options))
+    };
+
+    match rejection {
+        Some((refname, status)) => Err(push_rejection(&refname, &status)),
+        None => result,
+    }
+}
+
+/// Returns the error for the reference the remote rejected with the status.
+///
+/// Only the rejections because the remote branch has moved on are `NotFastForward` to be retried,
+/// and the others, e.g. declined by a hook, are not resolved by retrying.
+#[tracing::instrument(skip(refname, status))]
+fn push_rejection(refname: &str, status: &str) -> git2::Error {
+    let code = if status.starts_with("non-fast-forward") || status.starts_with("fetch first") {
+        ErrorCode::NotFastForward
+    } else {
+        ErrorCode::GenericError
+    };
+
+    git2::Error::new(
+        code,
+        ErrorClass::Reference,
+        format!("failed to push {}: {}", refname, status),
+    )
+}
+
+/// Resets the working tree and the current branch to the given commit,
+/// discarding any local changes including untracked files.
+#[tracing::instrument(skip(repository, oid))]
+fn reset_to(repository: &Repository, oid: Oid) -> Result<(), git2::Error> {
+    tracing::info!("reset the index to {}", oid);
+
+    let commit = repository.find_commit(oid)?;
+    let mut checkout_builder = git2::build::CheckoutBuilder::default();
+    checkout_builder.force().remove_untracked(true);
+    repository.reset(
+        commit.as_object(),
+        ResetType::Hard,
+        Some(&mut checkout_builder),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_all, check_cloned_from, clone_or_open_repository, commit, commit_edits, commit_message,
+        find_last_commit, push_rejection, push_retry_backoff, run_commit_queue, IndexEdit,
+        IndexManager, IndexStatus, MAX_PUSH_RETRY_BACKOFF,
+    };
+    use crate::config::{IndexConfig, SigningMethod};
+    use crate::error::Error;
+    use crate::models::{DeletionRecord, Package};
+    use git2::ErrorCode;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
+
+    fn package(name: &str, version: &str) -> Package {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "vers": version,
+            "deps": [],
+            "cksum": "abc",
+            "features": {},
+            "yanked": false,
+            "links": null
+        }))
+        .unwrap()
+    }
+
+    fn index_config(dir: &Path, local_path: &str) -> IndexConfig {
+        IndexConfig {
+            local_path: dir.join(local_path),
+            branch: "master".to_owned(),
+            name: "ktra".to_owned(),
+            bare_path: Some(dir.join("index.git")),
+            push_max_retries: 1,
+            push_retry_backoff_ms: 1,
+            ..Default::default()
+        }
+    }
 
-    let refs = format!("refs/heads/{0}:refs/heads/{}", config.branch);
-    remote.push(&[refs], Some(&mut push_options))
+    /// Lists the files on the branch of the bare repository.
+    fn pushed_files(config: &IndexConfig) -> Vec<String> {
+        let bare_path = config.bare_path.as_deref().unwrap();
+        let repository = git2::Repository::open_bare(bare_path).unwrap();
+        let tree = repository
+            .find_reference(&format!("refs/heads/{}", config.branch))
+            .and_then(|reference| reference.peel_to_tree())
+            .unwrap();
+
+        let mut files = Vec::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(git2::ObjectType::Blob) {
+                files.push(format!("{}{}", dir, entry.name().unwrap()));
+            }
+            git2::TreeWalkResult::Ok
+        })
+        .unwrap();
+        files
+    }
+
+    /// Returns the head of the branch of the bare repository and `config.json` on it.
+    fn pushed_config_json(config: &IndexConfig) -> (git2::Oid, serde_json::Value) {
+        let bare_path = config.bare_path.as_deref().unwrap();
+        let repository = git2::Repository::open_bare(bare_path).unwrap();
+        let commit = repository
+            .find_reference(&format!("refs/heads/{}", config.branch))
+            .and_then(|reference| reference.peel_to_commit())
+            .unwrap();
+        let blob = commit
+            .tree()
+            .and_then(|tree| tree.get_path(Path::new("config.json")))
+            .and_then(|entry| entry.to_object(&repository))
+            .and_then(|object| object.peel_to_blob())
+            .unwrap();
+        (commit.id(), serde_json::from_slice(blob.content()).unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_config_json_is_reconciled() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = index_config(dir.path(), "local");
+        config.public_url = Some("https://registry.example.com/".to_owned());
+        let dl_path = ["dl".to_owned()];
+
+        // `config.json` is created.
+        drop(IndexManager::new(config.clone(), &dl_path).await.unwrap());
+        let (head, config_json) = pushed_config_json(&config);
+        assert_eq!(
+            config_json,
+            serde_json::json!({
+                "dl": "https://registry.example.com/dl",
+                "api": "https://registry.example.com"
+            })
+        );
+
+        // `config.json` is left alone if it is up to date.
+        drop(IndexManager::new(config.clone(), &dl_path).await.unwrap());
+        assert_eq!(pushed_config_json(&config), (head, config_json));
+
+        // `dl` and `api` are updated.
+        config.public_url = Some("https://crates.example.com".to_owned());
+        let dl_path = ["crates".to_owned(), "dl".to_owned()];
+        drop(IndexManager::new(config.clone(), &dl_path).await.unwrap());
+        let (updated_head, config_json) = pushed_config_json(&config);
+        assert_ne!(updated_head, head);
+        assert_eq!(
+            config_json,
+            serde_json::json!({
+                "dl": "https://crates.example.com/crates/dl",
+                "api": "https://crates.example.com"
+            })
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_file_waits_for_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_manager = IndexManager::new(index_config(dir.path(), "local"), &[])
+            .await
+            .unwrap();
+        index_manager
+            .add_package(package("foo", "0.1.0"))
+            .await
+            .unwrap();
+
+        // the lock is held by `commit_edits` while the edits are applied and pushed.
+        let repository = index_manager.repository.lock().await;
+        let read = tokio::time::timeout(
+            Duration::from_millis(50),
+            index_manager.read_file("3/f/foo"),
+        );
+        assert!(read.await.is_err());
+        drop(repository);
+
+        let (content, _) = index_manager.read_file("3/f/foo").await.unwrap();
+        assert!(String::from_utf8(content)
+            .unwrap()
+            .contains(r#""vers":"0.1.0""#));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pull_periodically_ends_when_the_manager_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let index_manager = IndexManager::new(index_config(dir.path(), "local"), &[])
+            .await
+            .unwrap();
+        let index_manager = Arc::new(index_manager);
+        let task = tokio::spawn(
+            index_manager
+                .clone()
+                .pull_periodically(Duration::from_millis(10)),
+        );
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(index_manager);
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_edits_skips_unchanged_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = index_config(dir.path(), "local");
+        let repository = Mutex::new(clone_or_open_repository(&config).unwrap());
+        let yank = IndexEdit::ChangeYanked {
+            name: "foo".to_owned(),
+            version: "0.1.0".parse().unwrap(),
+            yanked: true,
+        };
+        let edits = [IndexEdit::AddPackage(package("foo", "0.1.0")), yank.clone()];
+        commit_edits(&config, &repository, &Default::default(), &edits)
+            .await
+            .unwrap();
+        let head = find_last_commit(&*repository.lock().await).unwrap().id();
+
+        // yanking the yanked version again pushes no empty commit.
+        let results = commit_edits(&config, &repository, &Default::default(), &[yank])
+            .await
+            .unwrap();
+        assert!(results[0].is_ok());
+        assert_eq!(
+            find_last_commit(&*repository.lock().await).unwrap().id(),
+            head
+        );
+        let bare_repository = git2::Repository::open_bare(dir.path().join("index.git")).unwrap();
+        assert_eq!(bare_repository.head().unwrap().target(), Some(head));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_edits_discards_failed_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = index_config(dir.path(), "local");
+        let repository = Mutex::new(clone_or_open_repository(&config).unwrap());
+
+        // the rebuild removes the index files and then fails on the crate without name.
+        let edits = [
+            IndexEdit::AddPackage(package("foo", "0.1.0")),
+            IndexEdit::Rebuild(vec![package("", "0.1.0")]),
+            IndexEdit::AddPackage(package("bar", "0.1.0")),
+        ];
+        let results = commit_edits(&config, &repository, &Default::default(), &edits)
+            .await
+            .unwrap();
+
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(Error::CrateNameNotDefined)));
+        assert!(results[2].is_ok());
+        assert_eq!(pushed_files(&config), vec!["3/b/bar", "3/f/foo"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_edits_retries_rejected_push() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = index_config(dir.path(), "local");
+        let repository = Mutex::new(clone_or_open_repository(&config).unwrap());
+        let other_config = index_config(dir.path(), "other");
+        let other_repository = Mutex::new(clone_or_open_repository(&other_config).unwrap());
+
+        // the other clone moves the branch on so the first push is rejected.
+        commit_edits(
+            &other_config,
+            &other_repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("bar", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+        let results = commit_edits(
+            &config,
+            &repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("foo", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+        assert!(results[0].is_ok());
+        assert_eq!(pushed_files(&config), vec!["3/b/bar", "3/f/foo"]);
+
+        // no commits are left behind when the retries run out.
+        commit_edits(
+            &other_config,
+            &other_repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("baz", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+        let config = IndexConfig {
+            push_max_retries: 0,
+            ..config
+        };
+        let head = find_last_commit(&*repository.lock().await).unwrap().id();
+        assert!(matches!(
+            commit_edits(
+                &config,
+                &repository,
+                &Default::default(),
+                &[IndexEdit::AddPackage(package("qux", "0.1.0"))],
+            )
+            .await,
+            Err(Error::PushRejected(1))
+        ));
+        assert_eq!(
+            find_last_commit(&*repository.lock().await).unwrap().id(),
+            head
+        );
+        assert!(!config.local_path.join("3/q/qux").exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = IndexConfig {
+            push_max_retries: 0,
+            ..index_config(dir.path(), "local")
+        };
+        let repository = Arc::new(Mutex::new(clone_or_open_repository(&config).unwrap()));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_commit_queue(
+            config.clone(),
+            repository,
+            Default::default(),
+            receiver,
+            Duration::from_millis(100),
+        ));
+
+        let queue_edit = |edit| {
+            let (result_sender, result_receiver) = oneshot::channel();
+            sender.send((edit, result_sender)).unwrap();
+            result_receiver
+        };
+
+        // the edits arriving within the window are committed together.
+        let foo = queue_edit(IndexEdit::AddPackage(package("foo", "0.1.0")));
+        let bar = queue_edit(IndexEdit::AddPackage(package("bar", "0.1.0")));
+        assert!(foo.await.unwrap().is_ok());
+        assert!(bar.await.unwrap().is_ok());
+        assert_eq!(pushed_files(&config), vec!["3/b/bar", "3/f/foo"]);
+        let bare_repository = git2::Repository::open_bare(dir.path().join("index.git")).unwrap();
+        let head = bare_repository.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("Updating 2 index entries"));
+
+        // every edit in the batch fails for the same reason if the push fails.
+        let other_config = index_config(dir.path(), "other");
+        let other_repository = Mutex::new(clone_or_open_repository(&other_config).unwrap());
+        commit_edits(
+            &other_config,
+            &other_repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("baz", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+        let foo = queue_edit(IndexEdit::AddPackage(package("foo", "0.2.0")));
+        let bar = queue_edit(IndexEdit::AddPackage(package("bar", "0.2.0")));
+        for result in [foo.await.unwrap(), bar.await.unwrap()] {
+            match result {
+                Err(Error::BatchCommit(e)) => assert!(matches!(*e, Error::PushRejected(1))),
+                result => panic!("unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_conflicts_are_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = index_config(dir.path(), "local");
+        let repository = Mutex::new(clone_or_open_repository(&config).unwrap());
+        let other_config = index_config(dir.path(), "other");
+        let other_repository = Mutex::new(clone_or_open_repository(&other_config).unwrap());
+
+        // the local commit which is not pushed conflicts with the pushed one.
+        {
+            let repository = repository.lock().await;
+            IndexEdit::AddPackage(package("foo", "0.2.0"))
+                .apply(&config.local_path)
+                .unwrap();
+            add_all(&repository).unwrap();
+            commit(&repository, &config, "Updating crate `foo#0.2.0`").unwrap();
+        }
+        commit_edits(
+            &other_config,
+            &other_repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("foo", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+
+        let status = RwLock::new(IndexStatus::default());
+        let result = commit_edits(
+            &config,
+            &repository,
+            &status,
+            &[IndexEdit::AddPackage(package("bar", "0.1.0"))],
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Git(e)) if e.code() == ErrorCode::Conflict));
+        assert!(status.read().await.conflicted);
+
+        // the server starts with the conflicts reported.
+        drop(repository);
+        let index_manager = IndexManager::new(config, &[]).await.unwrap();
+        assert!(index_manager.status().await.conflicted);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signed_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("key");
+        let status = std::process::Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key_path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let signed_config = |local_path| IndexConfig {
+            email: Some("ktra@example.com".to_owned()),
+            signing_method: Some(SigningMethod::Ssh),
+            signing_key: Some(key_path.to_str().unwrap().to_owned()),
+            ..index_config(dir.path(), local_path)
+        };
+        let config = signed_config("local");
+        let repository = Mutex::new(clone_or_open_repository(&config).unwrap());
+        let other_config = signed_config("other");
+        let other_repository = Mutex::new(clone_or_open_repository(&other_config).unwrap());
+
+        // the local commit which is not pushed is merged with the pushed one.
+        {
+            let repository = repository.lock().await;
+            IndexEdit::AddPackage(package("foo", "0.1.0"))
+                .apply(&config.local_path)
+                .unwrap();
+            add_all(&repository).unwrap();
+            commit(&repository, &config, "Updating crate `foo#0.1.0`").unwrap();
+        }
+        commit_edits(
+            &other_config,
+            &other_repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("bar", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+        commit_edits(
+            &config,
+            &repository,
+            &Default::default(),
+            &[IndexEdit::AddPackage(package("baz", "0.1.0"))],
+        )
+        .await
+        .unwrap();
+
+        let bare_repository = git2::Repository::open_bare(dir.path().join("index.git")).unwrap();
+        let mut revwalk = bare_repository.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        let mut merged = false;
+        for oid in revwalk {
+            let oid = oid.unwrap();
+            let commit = bare_repository.find_commit(oid).unwrap();
+            merged |= commit.parent_count() == 2;
+            assert_eq!(commit.author().name(), Some("ktra"));
+            assert_eq!(commit.committer().email(), Some("ktra@example.com"));
+
+            let (signature, _) = bare_repository.extract_signature(&oid, None).unwrap();
+            assert!(signature
+                .as_str()
+                .unwrap()
+                .starts_with("-----BEGIN SSH SIGNATURE-----"));
+        }
+        assert!(merged);
+    }
+
+    #[test]
+    fn test_check_cloned_from() {
+        let dir = tempfile::tempdir().unwrap();
+        let bare_path = dir.path().join("index.git");
+        git2::Repository::init_bare(&bare_path).unwrap();
+
+        let clone =
+            git2::Repository::clone(bare_path.to_str().unwrap(), dir.path().join("clone")).unwrap();
+        assert!(check_cloned_from(&clone, &bare_path).is_ok());
+
+        let other = git2::Repository::init(dir.path().join("other")).unwrap();
+        other
+            .remote("origin", "https://example.com/index.git")
+            .unwrap();
+        assert!(check_cloned_from(&other, &bare_path).is_err());
+        assert!(check_cloned_from(&clone, &dir.path().join("missing.git")).is_err());
+    }
+
+    #[test]
+    fn test_add_and_remove_package_keep_existing_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_path = dir.path().join("3").join("f").join("foo");
+        std::fs::create_dir_all(package_path.parent().unwrap()).unwrap();
+        // a line written by another tool with the fields `Package` does not know.
+        let existing = r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"abc","features":{},"yanked":false,"links":null,"v":3,"future":{"x":1}}"#;
+        std::fs::write(&package_path, existing).unwrap();
+
+        IndexEdit::AddPackage(package("foo", "0.2.0"))
+            .apply(dir.path())
+            .unwrap();
+        let content = std::fs::read_to_string(&package_path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], existing);
+        assert!(lines[1].contains(r#""vers":"0.2.0""#));
+
+        let result = IndexEdit::AddPackage(package("foo", "0.1.0")).apply(dir.path());
+        assert!(matches!(result, Err(Error::VersionExists(..))));
+
+        let record = DeletionRecord::new("foo", "0.2.0".parse().unwrap(), "admin", "secret");
+        IndexEdit::RemovePackage(record).apply(dir.path()).unwrap();
+        assert_eq!(std::fs::read_to_string(&package_path).unwrap(), existing);
+    }
+
+    #[test]
+    fn test_change_yanked_keeps_existing_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_path = dir.path().join("3").join("f").join("foo");
+        std::fs::create_dir_all(package_path.parent().unwrap()).unwrap();
+        let existing = r#"{"name":"foo","vers":"0.1.0","deps":[],"cksum":"abc","features":{},"yanked":false,"links":null,"v":3,"future":{"x":1}}"#;
+        let other =
+            r#"{"name":"foo","vers":"0.2.0","cksum":"def","yanked":false,"rust_version":"1.60"}"#;
+        std::fs::write(&package_path, format!("{}\n{}", existing, other)).unwrap();
+
+        // the name is looked up in lowercase like the other edits.
+        let change_yanked = |yanked| IndexEdit::ChangeYanked {
+            name: "Foo".to_owned(),
+            version: "0.1.0".parse().unwrap(),
+            yanked,
+        };
+        change_yanked(true).apply(dir.path()).unwrap();
+        let content = std::fs::read_to_string(&package_path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(
+            lines[0],
+            existing.replace(r#""yanked":false"#, r#""yanked":true"#)
+        );
+        assert_eq!(lines[1], other);
+
+        change_yanked(false).apply(dir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&package_path).unwrap(),
+            format!("{}\n{}", existing, other)
+        );
+    }
+
+    #[test]
+    fn test_commit_message() {
+        assert_eq!(
+            commit_message(&["Updating crate `a#0.1.0`".to_owned()]),
+            "Updating crate `a#0.1.0`"
+        );
+        assert_eq!(
+            commit_message(&[
+                "Updating crate `a#0.1.0`".to_owned(),
+                "Yanking crate `b#0.2.0`".to_owned()
+            ]),
+            "Updating 2 index entries\n\nUpdating crate `a#0.1.0`\nYanking crate `b#0.2.0`"
+        );
+    }
+
+    #[test]
+    fn test_push_rejection() {
+        for status in &["non-fast-forward", "fetch first"] {
+            let error = push_rejection("refs/heads/main", status);
+            assert_eq!(error.code(), ErrorCode::NotFastForward);
+        }
+
+        for status in &["hook declined", "permission denied", "failed to lock"] {
+            let error = push_rejection("refs/heads/main", status);
+            assert_eq!(error.code(), ErrorCode::GenericError);
+            assert_eq!(
+                error.message(),
+                format!("failed to push refs/heads/main: {}", status)
+            );
+        }
+    }
+
+    #[test]
+    fn test_push_retry_backoff() {
+        assert_eq!(push_retry_backoff(100, 0), Duration::from_millis(100));
+        assert_eq!(push_retry_backoff(100, 3), Duration::from_millis(800));
+        assert_eq!(push_retry_backoff(100, 63), MAX_PUSH_RETRY_BACKOFF);
+        assert_eq!(push_retry_backoff(100, 64), MAX_PUSH_RETRY_BACKOFF);
+        assert_eq!(push_retry_backoff(u64::MAX, 1), MAX_PUSH_RETRY_BACKOFF);
+        assert_eq!(push_retry_backoff(100, usize::MAX), MAX_PUSH_RETRY_BACKOFF);
+    }
 }
@@ -1,26 +1,49 @@
 #![type_length_limit = "2000000"]
 
 mod config;
+mod crate_file;
 mod db_manager;
 mod delete;
+mod download_counter;
 mod error;
+mod fsck;
+mod gc;
 mod get;
+mod git_http;
+mod health;
 mod index_manager;
 mod models;
 mod openid;
+mod policy;
 mod post;
 mod put;
+mod rebuild_index;
+mod sparse_index;
+mod storage_manager;
+mod test_utils;
 mod utils;
+mod webhook;
 
-use crate::config::Config;
+use crate::config::{
+    AdminConfig, Config, CrateFilesConfig, DbConfig, PublishConfig, SigningMethod,
+};
+use crate::download_counter::DownloadCounter;
 use crate::index_manager::IndexManager;
-use clap::{clap_app, crate_authors, crate_version, ArgMatches};
+use crate::models::DeletionRecord;
+use crate::policy::Policy;
+#[cfg(not(feature = "storage-s3"))]
+use crate::storage_manager::FsStorageManager;
+#[cfg(feature = "storage-s3")]
+use crate::storage_manager::S3StorageManager;
+use crate::storage_manager::StorageManager;
+use clap::{clap_app, crate_authors, crate_version, Arg, ArgMatches, SubCommand};
 use db_manager::DbManager;
 #[cfg(feature = "crates-io-mirroring")]
 use reqwest::Client;
 use std::convert::Infallible;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use warp::{Filter, Rejection, Reply};
 
@@ -44,44 +67,151 @@ use db_manager::SledDbManager;
 #[tracing::instrument(skip(
     db_manager,
     index_manager,
+    storage_manager,
+    download_counter,
+    auth_required,
     dl_dir_path,
     http_client,
     cache_dir_path,
-    dl_path
+    dl_path,
+    sparse_path,
+    bare_path,
+    git_http_path,
+    webhook_secret,
+    publish_config,
+    policy,
+    admin_config
 ))]
+#[allow(clippy::too_many_arguments)]
 fn apis(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
+    download_counter: Arc<DownloadCounter>,
+    auth_required: bool,
     dl_dir_path: Arc<PathBuf>,
     http_client: Client,
     cache_dir_path: Arc<PathBuf>,
     dl_path: Vec<String>,
+    sparse_path: Vec<String>,
+    bare_path: Option<Arc<PathBuf>>,
+    git_http_path: Vec<String>,
+    webhook_secret: Option<Arc<String>>,
+    publish_config: Arc<PublishConfig>,
+    policy: Arc<Policy>,
+    admin_config: Arc<AdminConfig>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     let routes = get::apis(
         db_manager.clone(),
-        dl_dir_path.clone(),
+        storage_manager.clone(),
+        download_counter,
+        auth_required,
         http_client,
         cache_dir_path,
         dl_path,
     )
-    .or(delete::apis(db_manager.clone(), index_manager.clone()))
-    .or(put::apis(db_manager.clone(), index_manager, dl_dir_path));
+    .or(sparse_index::apis(
+        index_manager.clone(),
+        db_manager.clone(),
+        auth_required,
+        sparse_path,
+    ))
+    .or(git_http::apis(
+        db_manager.clone(),
+        auth_required,
+        bare_path,
+        git_http_path,
+    ))
+    .or(health::apis(index_manager.clone()))
+    .or(webhook::apis(index_manager.clone(), webhook_secret))
+    .or(delete::apis(
+        db_manager.clone(),
+        index_manager.clone(),
+        storage_manager.clone(),
+        admin_config,
+    ))
+    .or(put::apis(
+        db_manager.clone(),
+        index_manager,
+        storage_manager,
+        dl_dir_path,
+        publish_config,
+        policy,
+    ));
     #[cfg(not(feature = "openid"))]
     let routes = routes.or(post::apis(db_manager.clone()));
     routes
 }
 
 #[cfg(not(feature = "crates-io-mirroring"))]
-#[tracing::instrument(skip(db_manager, index_manager, dl_dir_path, dl_path))]
+#[tracing::instrument(skip(
+    db_manager,
+    index_manager,
+    storage_manager,
+    download_counter,
+    auth_required,
+    dl_dir_path,
+    dl_path,
+    sparse_path,
+    bare_path,
+    git_http_path,
+    webhook_secret,
+    publish_config,
+    policy,
+    admin_config
+))]
+#[allow(clippy::too_many_arguments)]
 fn apis(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
+    download_counter: Arc<DownloadCounter>,
+    auth_required: bool,
     dl_dir_path: Arc<PathBuf>,
     dl_path: Vec<String>,
+    sparse_path: Vec<String>,
+    bare_path: Option<Arc<PathBuf>>,
+    git_http_path: Vec<String>,
+    webhook_secret: Option<Arc<String>>,
+    publish_config: Arc<PublishConfig>,
+    policy: Arc<Policy>,
+    admin_config: Arc<AdminConfig>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    let routes = get::apis(db_manager.clone(), dl_dir_path.clone(), dl_path)
-        .or(delete::apis(db_manager.clone(), index_manager.clone()))
-        .or(put::apis(db_manager.clone(), index_manager, dl_dir_path));
+    let routes = get::apis(
+        db_manager.clone(),
+        storage_manager.clone(),
+        download_counter,
+        auth_required,
+        dl_path,
+    )
+    .or(sparse_index::apis(
+        index_manager.clone(),
+        db_manager.clone(),
+        auth_required,
+        sparse_path,
+    ))
+    .or(git_http::apis(
+        db_manager.clone(),
+        auth_required,
+        bare_path,
+        git_http_path,
+    ))
+    .or(health::apis(index_manager.clone()))
+    .or(webhook::apis(index_manager.clone(), webhook_secret))
+    .or(delete::apis(
+        db_manager.clone(),
+        index_manager.clone(),
+        storage_manager.clone(),
+        admin_config,
+    ))
+    .or(put::apis(
+        db_manager.clone(),
+        index_manager,
+        storage_manager,
+        dl_dir_path,
+        publish_config,
+        policy,
+    ));
     #[cfg(not(feature = "openid"))]
     let routes = routes.or(post::apis(db_manager.clone()));
     routes
@@ -91,7 +221,13 @@ fn apis(
 async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
     if let Some(application_error) = rejection.find::<crate::error::Error>() {
         let (json, status_code) = application_error.to_reply();
-        Ok(warp::reply::with_status(json, status_code))
+        let reply = warp::reply::with_status(json, status_code);
+        // cargo sends the token to the registry requiring the authorization only after it is asked.
+        if status_code == warp::http::StatusCode::UNAUTHORIZED {
+            Ok(warp::reply::with_header(reply, "WWW-Authenticate", "Cargo").into_response())
+        } else {
+            Ok(reply.into_response())
+        }
     } else {
         Ok(warp::reply::with_status(
             warp::reply::json(&serde_json::json!({
@@ -100,10 +236,129 @@ async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible
                 ]
             })),
             warp::http::StatusCode::NOT_FOUND,
-        ))
+        )
+        .into_response())
+    }
+}
+
+#[tracing::instrument(skip(config))]
+async fn db_manager(config: &DbConfig) -> anyhow::Result<impl DbManager> {
+    #[cfg(all(
+        feature = "db-sled",
+        not(all(feature = "db-redis", feature = "db-mongo"))
+    ))]
+    let db_manager = SledDbManager::new(config).await?;
+    #[cfg(all(
+        feature = "db-redis",
+        not(all(feature = "db-sled", feature = "db-mongo"))
+    ))]
+    let db_manager = RedisDbManager::new(config).await?;
+    #[cfg(all(
+        feature = "db-mongo",
+        not(all(feature = "db-sled", feature = "db-redis"))
+    ))]
+    let db_manager = MongoDbManager::new(config).await?;
+    Ok(db_manager)
+}
+
+#[tracing::instrument(skip(config))]
+async fn storage_manager(config: &CrateFilesConfig) -> anyhow::Result<impl StorageManager> {
+    #[cfg(not(feature = "storage-s3"))]
+    let storage_manager = FsStorageManager::new(config).await?;
+    #[cfg(feature = "storage-s3")]
+    let storage_manager = S3StorageManager::new(config).await?;
+    Ok(storage_manager)
+}
+
+#[tracing::instrument(skip(config))]
+async fn run_fsck(config: Config, repair: bool) -> anyhow::Result<()> {
+    let db_manager = db_manager(&config.db_config).await?;
+    let storage_manager = storage_manager(&config.crate_files_config).await?;
+    let index_manager =
+        IndexManager::new(config.index_config, &config.crate_files_config.dl_path).await?;
+
+    let findings = fsck::fsck(&db_manager, &index_manager, &storage_manager, repair).await?;
+
+    for finding in &findings {
+        println!("{}", finding);
+    }
+    let remaining = findings.iter().filter(|finding| !finding.repaired).count();
+    if remaining == 0 {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("{} issue(s) remain", remaining))
     }
 }
 
+#[tracing::instrument(skip(config))]
+async fn run_rebuild_index(config: Config, force: bool) -> anyhow::Result<()> {
+    let db_manager = db_manager(&config.db_config).await?;
+    let storage_manager = storage_manager(&config.crate_files_config).await?;
+
+    let skipped = rebuild_index::rebuild_index(
+        &db_manager,
+        &storage_manager,
+        config.index_config,
+        &config.crate_files_config.dl_path,
+        force,
+    )
+    .await?;
+
+    if skipped != 0 {
+        tracing::warn!("{} version(s) are dropped from the index", skipped);
+    }
+    Ok(())
+}
+
+#[tracing::instrument(skip(config))]
+async fn run_gc(config: Config, dry_run: bool) -> anyhow::Result<()> {
+    let db_manager = db_manager(&config.db_config).await?;
+    let storage_manager = storage_manager(&config.crate_files_config).await?;
+    let grace_period = Duration::from_secs(config.crate_files_config.gc_grace_period_secs);
+
+    let orphans = gc::gc(
+        &db_manager,
+        &storage_manager,
+        &config.crate_files_config,
+        grace_period,
+        dry_run,
+    )
+    .await?;
+
+    for orphan in &orphans {
+        if orphan.removed {
+            println!(
+                "{}: orphaned, {} bytes (removed)",
+                orphan.location, orphan.size
+            );
+        } else {
+            println!("{}: orphaned, {} bytes", orphan.location, orphan.size);
+        }
+    }
+    println!("{}", gc::summary(&orphans));
+    Ok(())
+}
+
+#[tracing::instrument(skip(config, name, version, deleted_by, reason))]
+async fn run_delete(
+    config: Config,
+    name: &str,
+    version: &str,
+    deleted_by: &str,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let db_manager = db_manager(&config.db_config).await?;
+    let storage_manager = storage_manager(&config.crate_files_config).await?;
+    let index_manager =
+        IndexManager::new(config.index_config, &config.crate_files_config.dl_path).await?;
+
+    let record = DeletionRecord::new(name, version.parse()?, deleted_by, reason);
+    delete::delete_version(&db_manager, &index_manager, &storage_manager, record).await?;
+
+    println!("{}#{}: deleted", name, version);
+    Ok(())
+}
+
 #[tracing::instrument(skip(config))]
 async fn run_server(config: Config) -> anyhow::Result<()> {
     tracing::info!(
@@ -118,39 +373,82 @@ async fn run_server(config: Config) -> anyhow::Result<()> {
     #[cfg(feature = "crates-io-mirroring")]
     let cache_dir_path = config.crate_files_config.cache_dir_path.clone();
     let dl_path = config.crate_files_config.dl_path.clone();
+    let sparse_path = config.index_config.sparse_path.clone();
+    let auth_required = config.index_config.auth_required;
+    let bare_path = config.index_config.bare_path.clone().map(Arc::new);
+    let git_http_path = config.index_config.git_http_path.clone();
+    let pull_interval_secs = config.index_config.pull_interval_secs;
+    let downloads_flush_interval_secs = config.crate_files_config.downloads_flush_interval_secs;
+    let gc_interval_secs = config.crate_files_config.gc_interval_secs;
+    let gc_grace_period_secs = config.crate_files_config.gc_grace_period_secs;
+    let crate_files_config = config.crate_files_config.clone();
+    let webhook_secret = config.index_config.webhook_secret.clone().map(Arc::new);
     let server_config = config.server_config.clone();
-
-    #[cfg(all(
-        feature = "db-sled",
-        not(all(feature = "db-redis", feature = "db-mongo"))
-    ))]
-    let db_manager = SledDbManager::new(&config.db_config).await?;
-    #[cfg(all(
-        feature = "db-redis",
-        not(all(feature = "db-sled", feature = "db-mongo"))
-    ))]
-    let db_manager = RedisDbManager::new(&config.db_config).await?;
-    #[cfg(all(
-        feature = "db-mongo",
-        not(all(feature = "db-sled", feature = "db-redis"))
-    ))]
-    let db_manager = MongoDbManager::new(&config.db_config).await?;
-    let index_manager = IndexManager::new(config.index_config).await?;
-    index_manager.pull().await?;
+    let publish_config = config.publish_config.clone();
+    let policy = Policy::new(&config.policy_config)?;
+    let admin_config = config.admin_config.clone();
+
+    let db_manager = db_manager(&config.db_config).await?;
+    let storage_manager = storage_manager(&config.crate_files_config).await?;
+    let backfilled = put::backfill_crate_sizes(&db_manager, &storage_manager).await?;
+    if backfilled > 0 {
+        tracing::info!(
+            "recorded the sizes of {} crate file(s) published before the sizes are recorded",
+            backfilled
+        );
+    }
+    let index_manager =
+        IndexManager::new(config.index_config, &config.crate_files_config.dl_path).await?;
 
     #[cfg(feature = "crates-io-mirroring")]
     let http_client = Client::builder().build()?;
 
+    let index_manager = Arc::new(index_manager);
+    if let Some(pull_interval_secs) = pull_interval_secs {
+        tokio::spawn(
+            index_manager
+                .clone()
+                .pull_periodically(Duration::from_secs(pull_interval_secs)),
+        );
+    }
+
     let db_manager = Arc::new(RwLock::new(db_manager));
+    let download_counter = Arc::new(DownloadCounter::new());
+    tokio::spawn(download_counter.clone().flush_periodically(
+        db_manager.clone(),
+        Duration::from_secs(downloads_flush_interval_secs),
+    ));
+
+    let storage_manager = Arc::new(storage_manager);
+    if let Some(gc_interval_secs) = gc_interval_secs {
+        tokio::spawn(gc::gc_periodically(
+            db_manager.clone(),
+            storage_manager.clone(),
+            crate_files_config,
+            Duration::from_secs(gc_grace_period_secs),
+            Duration::from_secs(gc_interval_secs),
+        ));
+    }
+
     let routes = apis(
         db_manager.clone(),
-        Arc::new(index_manager),
+        index_manager,
+        storage_manager,
+        download_counter,
+        auth_required,
         Arc::new(dl_dir_path),
         #[cfg(feature = "crates-io-mirroring")]
         http_client,
         #[cfg(feature = "crates-io-mirroring")]
         Arc::new(cache_dir_path),
         dl_path,
+        sparse_path,
+        bare_path,
+        git_http_path,
+        webhook_secret,
+        Arc::new(publish_config),
+        Arc::new(policy),
+        Arc::new(admin_config),
     );
 
     #[cfg(feature = "openid")]
@@ -189,12 +487,39 @@ fn matches() -> ArgMatches<'static> {
         (@arg DL_DIR_PATH: --("dl-dir-path") +takes_value "Sets the crate files directory")
         (@arg CACHE_DIR_PATH: --("cache-dir-path") +takes_value "Sets the crates.io cache files directory (needs `crates-io-mirroring` feature)")
         (@arg DL_PATH: --("dl-path") +takes_value ... "Sets a crate files download path")
+        (@arg DOWNLOADS_FLUSH_INTERVAL_SECS: --("downloads-flush-interval-secs") +takes_value "Sets an interval in seconds to store the download counts into the database")
+        (@arg GC_INTERVAL_SECS: --("gc-interval-secs") +takes_value "Sets an interval in seconds to remove the orphaned crate files periodically")
+        (@arg GC_GRACE_PERIOD_SECS: --("gc-grace-period-secs") +takes_value "Sets the age in seconds an orphaned crate file must reach before it is removed")
+        (@arg S3_ENDPOINT: --("s3-endpoint") +takes_value "Sets the endpoint URL of the S3-compatible storage (needs `storage-s3` feature)")
+        (@arg S3_BUCKET: --("s3-bucket") +takes_value "Sets the bucket storing the crate files (needs `storage-s3` feature)")
+        (@arg S3_REGION: --("s3-region") +takes_value "Sets the region of the bucket (needs `storage-s3` feature)")
+        (@arg S3_ACCESS_KEY_ID: --("s3-access-key-id") +takes_value "Sets the access key ID for the storage (needs `storage-s3` feature)")
+        (@arg S3_SECRET_ACCESS_KEY: --("s3-secret-access-key") +takes_value "Sets the secret access key for the storage (needs `storage-s3` feature)")
+        (@arg S3_PREFIX: --("s3-prefix") +takes_value "Sets the prefix of the object keys of the crate files (needs `storage-s3` feature)")
         (@arg LOGIN_PREFIX: --("login-prefix") +takes_value "Sets the prefix to registered users on the registry.")
         (@arg DB_DIR_PATH: --("db-dir-path") +takes_value "Sets a database directory (needs `db-sled` feature)")
         (@arg REDIS_URL: --("redis-url") + takes_value "Sets a Redis URL (needs `db-redis` feature)")
         (@arg MONGODB_URL: --("mongodb-url") + takes_value "Sets a MongoDB URL (needs `db-mongo` feature)")
         (@arg REMOTE_URL: --("remote-url") +takes_value "Sets a URL for the remote index git repository")
         (@arg LOCAL_PATH: --("local-path") +takes_value "Sets a path for local index git repository")
+        (@arg SPARSE_PATH: --("sparse-path") +takes_value ... "Sets a path the sparse index is served on")
+        (@arg PUBLIC_URL: --("public-url") +takes_value "Sets the public URL of the registry which is written to `config.json` in the index")
+        (@arg AUTH_REQUIRED: --("auth-required") "Requires authorization for all requests to the registry")
+        (@arg BARE_PATH: --("bare-path") +takes_value "Sets a path for the bare index git repository hosted by ktra itself instead of the remote one")
+        (@arg GIT_HTTP_PATH: --("git-http-path") +takes_value ... "Sets a path the hosted index git repository is served on")
+        (@arg COMMIT_BATCH_WINDOW_MS: --("commit-batch-window-ms") +takes_value "Sets a window in milliseconds to commit the index edits made within it together")
+        (@arg PULL_INTERVAL_SECS: --("pull-interval-secs") +takes_value "Sets an interval in seconds to pull the index git repository periodically")
+        (@arg WEBHOOK_SECRET: --("webhook-secret") +takes_value "Sets a secret to verify the webhook requests triggering the index pull")
+        (@arg SIGNING_METHOD: --("signing-method") +takes_value possible_values(&["gpg", "ssh"]) "Sets a way to sign the index commits")
+        (@arg SIGNING_KEY: --("signing-key") +takes_value "Sets a GPG key ID or an SSH private key path to sign the index commits")
+        (@arg ALLOWED_CATEGORIES: --("allowed-categories") +takes_value ... "Sets the categories crates can be published with")
+        (@arg ALLOWED_BADGES: --("allowed-badges") +takes_value ... "Sets the badges crates can be published with")
+        (@arg MAX_CRATE_SIZE: --("max-crate-size") +takes_value "Sets the maximum size of a crate file in bytes")
+        (@arg MAX_METADATA_SIZE: --("max-metadata-size") +takes_value "Sets the maximum size of the metadata of a published crate in bytes")
+        (@arg USER_QUOTA: --("user-quota") +takes_value "Sets the maximum total size in bytes of the crate files each user publishes")
+        (@arg CRATE_QUOTA: --("crate-quota") +takes_value "Sets the maximum total size in bytes of the crate files of each crate")
+        (@arg ADMIN_LOGINS: --("admin-logins") +takes_value ... "Sets the logins of the users allowed to delete crate versions")
+        (@arg ALLOWED_REGISTRIES: --("allowed-registries") +takes_value ... "Sets the index URLs of the other registries dependencies can come from")
         (@arg BRANCH: --branch +takes_value "Sets a branch name of the index git repository")
         (@arg HTTPS_USERNAME: --("https-username") +takes_value "Sets a username to use for authentication if the remote index git repository uses HTTPS protocol")
         (@arg HTTPS_PASSWORD: --("https-password") +takes_value "Sets a password to use for authentication if the remote index git repository uses HTTPS protocol")
@@ -212,8 +537,33 @@ fn matches() -> ArgMatches<'static> {
         (@arg OPENID_ADD_SCOPES: --("openid-additional-scopes") +takes_value "Sets the additional scopes queried by the application for OpenId. Usually this value depends on the issuer.")
         (@arg OPENID_GITLAB_GROUPS: --("openid-gitlab-groups") +takes_value "Sets the authorized Gitlab groups whose members are allowed to create an account on the registry and be publishers/owners. Leave empty not to check groups.")
         (@arg OPENID_GITLAB_USERS: --("openid-gitlab-users") +takes_value "Sets the authorized Gitlab users who are allowed to create an account on the registry and be publishers/owners. Leave empty not to check users.")
+        (@subcommand fsck =>
+            (about: "Checks the consistency between the index, the crate files and the database")
+            (@arg REPAIR: --repair "Repairs the index from the database as far as possible")
+        )
+        (@subcommand gc =>
+            (about: "Removes the crate files no version in the database refers to, the uploads left behind and the empty crates.io cache files")
+            (@arg DRY_RUN: --("dry-run") "Only reports the orphaned files without removing them")
+        )
+        (@subcommand delete =>
+            (about: "Deletes a version of a crate from the index, the database and the crate files")
+            (@arg CRATE: +required "Sets the name of the crate")
+            (@arg VERSION: +required "Sets the version to delete")
+            (@arg REASON: --reason +takes_value +required "Sets the reason recorded with the deletion")
+            (@arg DELETED_BY: --("deleted-by") +takes_value +required "Sets the login of the administrator recorded with the deletion")
+        )
     )
-        .get_matches()
+    // `clap_app!` does not accept subcommand names containing hyphens.
+    .subcommand(
+        SubCommand::with_name("rebuild-index")
+            .about("Rebuilds all packages in the index from the database and the crate files in a single commit on a fresh checkout")
+            .arg(
+                Arg::with_name("FORCE")
+                    .long("force")
+                    .help("Pushes the rebuilt index even if the versions whose crate files cannot be read are dropped"),
+            ),
+    )
+    .get_matches()
 }
 
 #[tokio::main]
@@ -241,6 +591,57 @@ async fn main() -> anyhow::Result<()> {
         config.crate_files_config.dl_path = dl_path;
     }
 
+    if let Some(downloads_flush_interval_secs) = matches
+        .value_of("DOWNLOADS_FLUSH_INTERVAL_SECS")
+        .map(|s| s.parse().unwrap())
+    {
+        config.crate_files_config.downloads_flush_interval_secs = downloads_flush_interval_secs;
+    }
+
+    if let Some(gc_interval_secs) = matches
+        .value_of("GC_INTERVAL_SECS")
+        .map(|s| s.parse().unwrap())
+    {
+        config.crate_files_config.gc_interval_secs = Some(gc_interval_secs);
+    }
+
+    if let Some(gc_grace_period_secs) = matches
+        .value_of("GC_GRACE_PERIOD_SECS")
+        .map(|s| s.parse().unwrap())
+    {
+        config.crate_files_config.gc_grace_period_secs = gc_grace_period_secs;
+    }
+
+    #[cfg(feature = "storage-s3")]
+    if let Some(endpoint) = matches.value_of("S3_ENDPOINT") {
+        config.crate_files_config.s3_config.endpoint = endpoint.to_owned();
+    }
+
+    #[cfg(feature = "storage-s3")]
+    if let Some(bucket) = matches.value_of("S3_BUCKET") {
+        config.crate_files_config.s3_config.bucket = bucket.to_owned();
+    }
+
+    #[cfg(feature = "storage-s3")]
+    if let Some(region) = matches.value_of("S3_REGION") {
+        config.crate_files_config.s3_config.region = region.to_owned();
+    }
+
+    #[cfg(feature = "storage-s3")]
+    if let Some(access_key_id) = matches.value_of("S3_ACCESS_KEY_ID") {
+        config.crate_files_config.s3_config.access_key_id = access_key_id.to_owned();
+    }
+
+    #[cfg(feature = "storage-s3")]
+    if let Some(secret_access_key) = matches.value_of("S3_SECRET_ACCESS_KEY") {
+        config.crate_files_config.s3_config.secret_access_key = secret_access_key.to_owned();
+    }
+
+    #[cfg(feature = "storage-s3")]
+    if let Some(prefix) = matches.value_of("S3_PREFIX") {
+        config.crate_files_config.s3_config.prefix = prefix.to_owned();
+    }
+
     if let Some(login_prefix) = matches.value_of("LOGIN_PREFIX") {
         config.db_config.login_prefix = login_prefix.into();
     }
@@ -268,6 +669,112 @@ async fn main() -> anyhow::Result<()> {
         config.index_config.local_path = local_path;
     }
 
+    if let Some(sparse_path) = matches
+        .values_of("SPARSE_PATH")
+        .map(|vs| vs.map(ToOwned::to_owned).collect())
+    {
+        config.index_config.sparse_path = sparse_path;
+    }
+
+    if let Some(public_url) = matches.value_of("PUBLIC_URL").map(ToOwned::to_owned) {
+        config.index_config.public_url = Some(public_url);
+    }
+
+    if matches.is_present("AUTH_REQUIRED") {
+        config.index_config.auth_required = true;
+    }
+
+    if let Some(bare_path) = matches.value_of("BARE_PATH").map(PathBuf::from) {
+        config.index_config.bare_path = Some(bare_path);
+    }
+
+    if let Some(git_http_path) = matches
+        .values_of("GIT_HTTP_PATH")
+        .map(|vs| vs.map(ToOwned::to_owned).collect())
+    {
+        config.index_config.git_http_path = git_http_path;
+    }
+
+    if let Some(commit_batch_window_ms) = matches
+        .value_of("COMMIT_BATCH_WINDOW_MS")
+        .map(|s| s.parse().unwrap())
+    {
+        config.index_config.commit_batch_window_ms = Some(commit_batch_window_ms);
+    }
+
+    if let Some(pull_interval_secs) = matches
+        .value_of("PULL_INTERVAL_SECS")
+        .map(|s| s.parse().unwrap())
+    {
+        config.index_config.pull_interval_secs = Some(pull_interval_secs);
+    }
+
+    if let Some(webhook_secret) = matches.value_of("WEBHOOK_SECRET").map(ToOwned::to_owned) {
+        config.index_config.webhook_secret = Some(webhook_secret);
+    }
+
+    if let Some(signing_method) = matches.value_of("SIGNING_METHOD") {
+        config.index_config.signing_method = match signing_method {
+            "gpg" => Some(SigningMethod::Gpg),
+            "ssh" => Some(SigningMethod::Ssh),
+            _ => unreachable!(),
+        };
+    }
+
+    if let Some(signing_key) = matches.value_of("SIGNING_KEY").map(ToOwned::to_owned) {
+        config.index_config.signing_key = Some(signing_key);
+    }
+
+    if let Some(allowed_categories) = matches
+        .values_of("ALLOWED_CATEGORIES")
+        .map(|vs| vs.map(ToOwned::to_owned).collect())
+    {
+        config.publish_config.allowed_categories = Some(allowed_categories);
+    }
+
+    if let Some(allowed_badges) = matches
+        .values_of("ALLOWED_BADGES")
+        .map(|vs| vs.map(ToOwned::to_owned).collect())
+    {
+        config.publish_config.allowed_badges = Some(allowed_badges);
+    }
+
+    if let Some(max_crate_size) = matches
+        .value_of("MAX_CRATE_SIZE")
+        .map(|s| s.parse().unwrap())
+    {
+        config.publish_config.max_crate_size = max_crate_size;
+    }
+
+    if let Some(max_metadata_size) = matches
+        .value_of("MAX_METADATA_SIZE")
+        .map(|s| s.parse().unwrap())
+    {
+        config.publish_config.max_metadata_size = max_metadata_size;
+    }
+
+    if let Some(user_quota) = matches.value_of("USER_QUOTA").map(|s| s.parse().unwrap()) {
+        config.publish_config.user_quota = Some(user_quota);
+    }
+
+    if let Some(crate_quota) = matches.value_of("CRATE_QUOTA").map(|s| s.parse().unwrap()) {
+        config.publish_config.crate_quota = Some(crate_quota);
+    }
+
+    if let Some(allowed_registries) = matches
+        .values_of("ALLOWED_REGISTRIES")
+        .map(|vs| vs.map(|v| v.parse().unwrap()).collect())
+    {
+        config.publish_config.allowed_registries = Some(allowed_registries);
+    }
+
+    if let Some(admin_logins) = matches
+        .values_of("ADMIN_LOGINS")
+        .map(|vs| vs.map(ToOwned::to_owned).collect())
+    {
+        config.admin_config.logins = admin_logins;
+    }
+
     if let Some(branch) = matches.value_of("BRANCH").map(ToOwned::to_owned) {
         config.index_config.branch = branch;
     }
@@ -357,5 +864,116 @@ async fn main() -> anyhow::Result<()> {
             Some(gitlab_users.split(',').map(ToString::to_string).collect());
     }
 
-    run_server(config).await
+    match matches.subcommand() {
+        ("fsck", Some(matches)) => run_fsck(config, matches.is_present("REPAIR")).await,
+        ("rebuild-index", Some(matches)) => {
+            run_rebuild_index(config, matches.is_present("FORCE")).await
+        }
+        ("gc", Some(matches)) => run_gc(config, matches.is_present("DRY_RUN")).await,
+        ("delete", Some(matches)) => {
+            run_delete(
+                config,
+                matches.value_of("CRATE").unwrap(),
+                matches.value_of("VERSION").unwrap(),
+                matches.value_of("DELETED_BY").unwrap(),
+                matches.value_of("REASON").unwrap(),
+            )
+            .await
+        }
+        _ => run_server(config).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "db-sled")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_auth_required_on_index_and_crate_files() {
+        use super::{apis, handle_rejection, storage_manager};
+        use crate::config::{CrateFilesConfig, IndexConfig};
+        use crate::download_counter::DownloadCounter;
+        use crate::index_manager::IndexManager;
+        use crate::policy::Policy;
+        use crate::test_utils;
+        use std::sync::Arc;
+        use warp::http::StatusCode;
+        use warp::Filter;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (_, token) = test_utils::add_user(&db_manager, "alice").await;
+        let crate_files_config = CrateFilesConfig {
+            dl_dir_path: dir.path().join("crates"),
+            ..Default::default()
+        };
+        let index_config = IndexConfig {
+            local_path: dir.path().join("index"),
+            branch: "master".to_owned(),
+            name: "ktra".to_owned(),
+            bare_path: Some(dir.path().join("index.git")),
+            public_url: Some("http://localhost:8000".to_owned()),
+            auth_required: true,
+            ..Default::default()
+        };
+        let bare_path = index_config.bare_path.clone().map(Arc::new);
+        let index_manager = IndexManager::new(index_config, &["dl".to_owned()])
+            .await
+            .unwrap();
+        let routes = apis(
+            db_manager,
+            Arc::new(index_manager),
+            Arc::new(storage_manager(&crate_files_config).await.unwrap()),
+            Arc::new(DownloadCounter::new()),
+            true,
+            Arc::new(crate_files_config.dl_dir_path.clone()),
+            #[cfg(feature = "crates-io-mirroring")]
+            Default::default(),
+            #[cfg(feature = "crates-io-mirroring")]
+            Arc::new(dir.path().join("cache")),
+            vec!["dl".to_owned()],
+            vec!["index".to_owned()],
+            bare_path,
+            vec!["git".to_owned()],
+            None,
+            Default::default(),
+            Arc::new(Policy::new(&Default::default()).unwrap()),
+            Default::default(),
+        )
+        .recover(handle_rejection);
+
+        #[allow(unused_mut)]
+        let mut requests = vec![
+            ("GET", "/dl/foo/0.1.0/download"),
+            ("GET", "/index/config.json"),
+            ("GET", "/index/3/f/foo"),
+            ("GET", "/git/info/refs?service=git-upload-pack"),
+            ("POST", "/git/git-upload-pack"),
+            ("GET", "/api/v1/crates/foo"),
+            ("GET", "/api/v1/crates/foo/0.1.0"),
+            ("GET", "/api/v1/crates/foo/downloads"),
+            ("GET", "/api/v1/crates?q=foo"),
+        ];
+        #[cfg(feature = "crates-io-mirroring")]
+        requests.push(("GET", "/ktra/api/v1/mirror/foo/0.1.0/download"));
+
+        for (method, path) in requests {
+            for authorization in [None, Some("invalid")] {
+                let mut request = warp::test::request().method(method).path(path);
+                if let Some(authorization) = authorization {
+                    request = request.header("Authorization", authorization);
+                }
+                let response = request.reply(&routes).await;
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+                assert_eq!(response.headers()["WWW-Authenticate"], "Cargo");
+            }
+        }
+
+        // the valid token passes, e.g. the sparse index serves config.json.
+        let response = warp::test::request()
+            .path("/index/config.json")
+            .header("Authorization", &token)
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
 }
@@ -1,6 +1,7 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
 use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use url::Url;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -139,39 +140,206 @@ pub struct Metadata {
     /// The `links` string value from the package's manifest, or None if not
     /// specified.
     pub links: Option<String>,
+    /// The minimal supported Rust version, or None if not specified.
+    #[serde(default)]
+    pub rust_version: Option<String>,
     #[serde(default)]
     pub yanked: bool,
+    /// The size of the crate file in bytes.
+    /// This is `None` for the versions published before ktra records it.
+    #[serde(default)]
+    pub crate_size: Option<u64>,
+    /// The SHA256 checksum of the crate file.
+    /// This is `None` for the versions published before ktra records it.
+    #[serde(default)]
+    pub cksum: Option<String>,
+    /// The time when the version is published.
+    /// This is `None` for the versions published before ktra records it.
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// The ID of the user who published the version.
+    /// This is `None` for the versions published before ktra records it.
+    #[serde(default)]
+    pub published_by: Option<u32>,
+    /// The time when the version is yanked last.
+    #[serde(default)]
+    pub yanked_at: Option<DateTime<Utc>>,
+    /// The time when the version is unyanked last.
+    #[serde(default)]
+    pub unyanked_at: Option<DateTime<Utc>>,
+    /// The reason the version is yanked for, which is cleared when it is unyanked.
+    #[serde(default)]
+    pub yank_reason: Option<String>,
+    /// The number of times the version is downloaded.
+    #[serde(default)]
+    pub downloads: u64,
+    /// The number of downloads per day within the last `RECENT_DOWNLOADS_DAYS` days.
+    #[serde(default)]
+    pub daily_downloads: BTreeMap<NaiveDate, u64>,
 }
 
+/// The number of days the daily downloads are kept for, which is the same as crates.io.
+pub const RECENT_DOWNLOADS_DAYS: i64 = 90;
+
 impl Metadata {
     #[tracing::instrument(skip(self, checksum))]
     pub fn to_package(&self, checksum: impl Into<String>) -> Package {
+        // the features using the namespaced (`dep:`) or weak (`?/`) dependency syntax are
+        // separated so that the older cargos which do not understand them ignore them.
+        let (features2, features): (HashMap<_, _>, HashMap<_, _>) =
+            self.features.clone().into_iter().partition(|(_, values)| {
+                values
+                    .iter()
+                    .any(|value| value.starts_with("dep:") || value.contains("?/"))
+            });
+        let (features2, v) = if features2.is_empty() {
+            (None, None)
+        } else {
+            (Some(features2), Some(2))
+        };
+
         Package {
             name: self.name.clone(),
             vers: self.vers.clone(),
             deps: self.deps.iter().map(Clone::clone).map(Into::into).collect(),
             cksum: checksum.into(),
-            features: self.features.clone(),
-            yanked: false,
+            features,
+            yanked: self.yanked,
             links: self.links.clone(),
+            features2,
+            v,
+            rust_version: self.rust_version.clone(),
         }
     }
 
+    /// Records that the user publishes the version now,
+    /// overwriting the records the client may send.
+    #[tracing::instrument(skip(self, user_id))]
+    pub fn record_publishing(&mut self, user_id: u32) {
+        self.yanked = false;
+        self.created_at = Some(Utc::now());
+        self.published_by = Some(user_id);
+        self.yanked_at = None;
+        self.unyanked_at = None;
+        self.yank_reason = None;
+        self.downloads = 0;
+        self.daily_downloads.clear();
+    }
+
+    /// Adds the downloads on the date and drops the daily downloads which are no longer recent.
+    #[tracing::instrument(skip(self, date, count))]
+    pub fn add_downloads(&mut self, date: NaiveDate, count: u64) {
+        self.downloads += count;
+        *self.daily_downloads.entry(date).or_default() += count;
+
+        let oldest = date - Duration::days(RECENT_DOWNLOADS_DAYS - 1);
+        self.daily_downloads = self.daily_downloads.split_off(&oldest);
+    }
+
+    /// Returns the number of the downloads within the last `RECENT_DOWNLOADS_DAYS` days.
     #[tracing::instrument(skip(self))]
-    pub fn to_searched(&self) -> SearchedMetadata {
-        SearchedMetadata {
-            name: self.name.clone(),
-            max_version: self.vers.clone(),
-            description: self.description.as_ref().cloned().unwrap_or_default(),
+    pub fn recent_downloads(&self) -> u64 {
+        let oldest = Utc::now().date_naive() - Duration::days(RECENT_DOWNLOADS_DAYS - 1);
+        self.daily_downloads
+            .range(oldest..)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    #[tracing::instrument(skip(self, yanked, reason))]
+    pub fn set_yanked(&mut self, yanked: bool, reason: Option<String>) {
+        self.yanked = yanked;
+        if yanked {
+            self.yanked_at = Some(Utc::now());
+            self.yank_reason = reason;
+        } else {
+            self.unyanked_at = Some(Utc::now());
+            self.yank_reason = None;
+        }
+    }
+
+    #[tracing::instrument(skip(self, published_by))]
+    pub fn to_version_info(&self, published_by: Option<User>) -> VersionInfo {
+        VersionInfo {
+            num: self.vers.clone(),
+            yanked: self.yanked,
+            created_at: self.created_at,
+            published_by,
+            yanked_at: self.yanked_at,
+            unyanked_at: self.unyanked_at,
+            yank_reason: self.yank_reason.clone(),
+            crate_size: self.crate_size,
+            downloads: self.downloads,
         }
     }
 }
 
+/// The warnings returned to `cargo publish`.
+/// The published crate is accepted even if any warnings are reported.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PublishWarnings {
+    /// The categories which are not allowed on the registry.
+    pub invalid_categories: Vec<String>,
+    /// The badges which are not allowed on the registry.
+    pub invalid_badges: Vec<String>,
+    /// The other warning messages.
+    pub other: Vec<String>,
+}
+
+/// A version of a crate with its publish records.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionInfo {
+    pub num: Version,
+    pub yanked: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub published_by: Option<User>,
+    pub yanked_at: Option<DateTime<Utc>>,
+    pub unyanked_at: Option<DateTime<Utc>>,
+    pub yank_reason: Option<String>,
+    pub crate_size: Option<u64>,
+    pub downloads: u64,
+}
+
+/// The response of the version information API.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionResponse {
+    pub version: VersionInfo,
+}
+
+/// The downloads of a version on a day.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VersionDownloads {
+    pub version: Version,
+    pub downloads: u64,
+    pub date: NaiveDate,
+}
+
+/// The response of the downloads API.
+#[derive(Debug, Clone, Serialize)]
+pub struct Downloads {
+    pub version_downloads: Vec<VersionDownloads>,
+}
+
+/// The response of the crate information API.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrateInfo {
+    #[serde(rename = "crate")]
+    pub krate: SearchedMetadata,
+    pub versions: Vec<VersionInfo>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct SearchedMetadata {
     pub name: String,
     pub max_version: Version,
     pub description: String,
+    pub deprecated: bool,
+    /// The crate recommended instead of the deprecated one.
+    pub successor: Option<String>,
+    /// The number of the downloads of all the versions.
+    pub downloads: u64,
+    /// The number of the downloads of all the versions within the last `RECENT_DOWNLOADS_DAYS` days.
+    pub recent_downloads: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -183,6 +351,15 @@ pub struct Package {
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
     pub links: Option<String>,
+    /// The features using the namespaced or weak dependency syntax.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features2: Option<HashMap<String, Vec<String>>>,
+    /// The schema version of this entry, which is 2 if `features2` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<u32>,
+    /// The minimal supported Rust version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rust_version: Option<String>,
 }
 
 impl Package {
@@ -192,6 +369,25 @@ impl Package {
     }
 }
 
+/// The content of `config.json` placed at the root of the index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexConfigJson {
+    /// The URL template cargo uses to download crate files.
+    pub dl: String,
+    /// The base URL of the web API.
+    pub api: Option<String>,
+    /// Whether cargo must send the token with every request to the registry.
+    #[serde(default, rename = "auth-required", skip_serializing_if = "is_false")]
+    pub auth_required: bool,
+    /// Any other keys written by hand are kept as they are.
+    #[serde(flatten)]
+    pub others: HashMap<String, serde_json::Value>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct User {
     pub id: u32,
@@ -214,6 +410,8 @@ impl User {
 pub struct Entry {
     versions: HashMap<Version, Metadata>,
     owner_ids: Vec<u32>,
+    #[serde(default)]
+    deprecation: Option<Deprecation>,
 }
 
 impl Entry {
@@ -251,6 +449,65 @@ impl Entry {
     pub fn owner_ids_mut(&mut self) -> &mut Vec<u32> {
         &mut self.owner_ids
     }
+
+    #[tracing::instrument(skip(self, deprecation))]
+    pub fn set_deprecation(&mut self, deprecation: Option<Deprecation>) {
+        self.deprecation = deprecation;
+    }
+
+    #[tracing::instrument(skip(self, metadata))]
+    pub fn to_searched(&self, metadata: &Metadata) -> SearchedMetadata {
+        SearchedMetadata {
+            name: metadata.name.clone(),
+            max_version: metadata.vers.clone(),
+            description: metadata.description.as_ref().cloned().unwrap_or_default(),
+            deprecated: self.deprecation.is_some(),
+            successor: self.deprecation.as_ref().and_then(|d| d.successor.clone()),
+            downloads: self.versions.values().map(|m| m.downloads).sum(),
+            recent_downloads: self.versions.values().map(Metadata::recent_downloads).sum(),
+        }
+    }
+
+    /// Returns the recent daily downloads of all the versions, the newest first.
+    #[tracing::instrument(skip(self))]
+    pub fn to_downloads(&self) -> Downloads {
+        let oldest = Utc::now().date_naive() - Duration::days(RECENT_DOWNLOADS_DAYS - 1);
+        let mut version_downloads: Vec<_> = self
+            .versions
+            .values()
+            .flat_map(|metadata| {
+                metadata
+                    .daily_downloads
+                    .range(oldest..)
+                    .map(move |(date, downloads)| VersionDownloads {
+                        version: metadata.vers.clone(),
+                        downloads: *downloads,
+                        date: *date,
+                    })
+            })
+            .collect();
+        version_downloads.sort_by(|a, b| b.date.cmp(&a.date).then(b.version.cmp(&a.version)));
+
+        Downloads { version_downloads }
+    }
+
+    /// Returns the total size of the crate files of all the versions.
+    #[tracing::instrument(skip(self))]
+    pub fn storage_usage(&self) -> u64 {
+        self.versions
+            .values()
+            .filter_map(|metadata| metadata.crate_size)
+            .sum()
+    }
+
+    /// Returns the user the crate file of the version counts against,
+    /// which is the first owner if the publisher is unknown.
+    #[tracing::instrument(skip(self, metadata))]
+    pub fn charged_user_id(&self, metadata: &Metadata) -> Option<u32> {
+        metadata
+            .published_by
+            .or_else(|| self.owner_ids.first().copied())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -259,6 +516,71 @@ pub struct Owners {
     pub logins: Vec<String>,
 }
 
+/// The deprecation of a whole crate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Deprecation {
+    /// The name of the crate recommended instead.
+    pub successor: Option<String>,
+    pub deprecated_at: DateTime<Utc>,
+}
+
+impl Deprecation {
+    #[tracing::instrument(skip(successor))]
+    pub fn new(successor: Option<String>) -> Deprecation {
+        Deprecation {
+            successor,
+            deprecated_at: Utc::now(),
+        }
+    }
+}
+
+/// The query of the yank API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct YankQuery {
+    pub reason: Option<String>,
+}
+
+/// The query of the deprecation API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeprecationQuery {
+    pub successor: Option<String>,
+}
+
+/// The request body of the version deletion API.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Deletion {
+    pub reason: String,
+}
+
+/// The record of a version removed from the registry by an administrator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeletionRecord {
+    pub name: String,
+    pub vers: Version,
+    /// The login of the administrator who deleted the version.
+    pub deleted_by: String,
+    pub reason: String,
+    pub deleted_at: DateTime<Utc>,
+}
+
+impl DeletionRecord {
+    #[tracing::instrument(skip(name, vers, deleted_by, reason))]
+    pub fn new(
+        name: impl Into<String>,
+        vers: Version,
+        deleted_by: impl Into<String>,
+        reason: impl Into<String>,
+    ) -> DeletionRecord {
+        DeletionRecord {
+            name: name.into(),
+            vers,
+            deleted_by: deleted_by.into(),
+            reason: reason.into(),
+            deleted_at: Utc::now(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Query {
     #[serde(rename = "q")]
@@ -322,3 +644,87 @@ pub struct Claims {
     // This property is used when gitlab_authorized_groups is set in the configuration
     pub(crate) groups: Option<Vec<String>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Metadata, RECENT_DOWNLOADS_DAYS};
+    use chrono::{Duration, NaiveDate};
+
+    fn metadata() -> Metadata {
+        serde_json::from_value(serde_json::json!({
+            "name": "foo",
+            "vers": "0.1.0",
+            "deps": [],
+            "features": {
+                "default": ["std"],
+                "std": [],
+                "serde": ["dep:serde"],
+                "derive": ["serde?/derive"]
+            },
+            "authors": [],
+            "description": null,
+            "documentation": null,
+            "homepage": null,
+            "readme": null,
+            "readme_file": null,
+            "keywords": [],
+            "categories": [],
+            "license": null,
+            "license_file": null,
+            "repository": null,
+            "badges": {},
+            "links": null,
+            "rust_version": "1.56"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_to_package_separates_features2() {
+        let package = metadata().to_package("abc");
+        let mut features: Vec<_> = package.features.keys().collect();
+        features.sort();
+        let mut features2: Vec<_> = package.features2.as_ref().unwrap().keys().collect();
+        features2.sort();
+
+        assert_eq!(features, vec!["default", "std"]);
+        assert_eq!(features2, vec!["derive", "serde"]);
+        assert_eq!(package.v, Some(2));
+        assert_eq!(package.rust_version.as_deref(), Some("1.56"));
+
+        let json = package.to_json_string().unwrap();
+        assert!(json.contains(r#""v":2"#));
+        assert!(json.contains(r#""rust_version":"1.56""#));
+    }
+
+    #[test]
+    fn test_set_yanked_keeps_reason_until_unyanked() {
+        let mut metadata = metadata();
+
+        metadata.set_yanked(true, Some("broken".to_owned()));
+        assert!(metadata.yanked);
+        assert_eq!(metadata.yank_reason.as_deref(), Some("broken"));
+
+        metadata.set_yanked(false, Some("ignored".to_owned()));
+        assert!(!metadata.yanked);
+        assert_eq!(metadata.yank_reason, None);
+    }
+
+    #[test]
+    fn test_add_downloads_drops_old_daily_downloads() {
+        let mut metadata = metadata();
+        let today = NaiveDate::from_ymd_opt(2021, 6, 1).unwrap();
+        let oldest = today - Duration::days(RECENT_DOWNLOADS_DAYS - 1);
+
+        metadata.add_downloads(oldest - Duration::days(1), 1);
+        metadata.add_downloads(oldest, 2);
+        metadata.add_downloads(today, 3);
+        metadata.add_downloads(today, 4);
+
+        assert_eq!(metadata.downloads, 10);
+        assert_eq!(
+            metadata.daily_downloads.into_iter().collect::<Vec<_>>(),
+            vec![(oldest, 2), (today, 7)]
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use crate::config::{PolicyCondition, PolicyConfig, PolicyField, PolicyRule, PolicySeverity};
+use crate::error::Error;
+use crate::models::Metadata;
+use regex::Regex;
+use std::fmt;
+
+impl fmt::Display for PolicyField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PolicyField::Name => "name",
+            PolicyField::Authors => "authors",
+            PolicyField::Description => "description",
+            PolicyField::Documentation => "documentation",
+            PolicyField::Homepage => "homepage",
+            PolicyField::Readme => "readme",
+            PolicyField::Keywords => "keywords",
+            PolicyField::Categories => "categories",
+            PolicyField::License => "license",
+            PolicyField::LicenseFile => "license_file",
+            PolicyField::Repository => "repository",
+            PolicyField::Links => "links",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The messages of the violated rules grouped by their severities.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PolicyViolations {
+    pub denials: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    rule: PolicyRule,
+    regex: Option<Regex>,
+}
+
+/// The publish policy compiled from `PolicyConfig`.
+#[derive(Debug)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Compiles the rules so that the invalid regular expressions are reported on startup.
+    #[tracing::instrument(skip(config))]
+    pub fn new(config: &PolicyConfig) -> Result<Policy, Error> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let regex = match &rule.condition {
+                    PolicyCondition::Matches(pattern) => Some(
+                        Regex::new(pattern)
+                            .map_err(|e| Error::InvalidPolicy(format!("{}: {}", rule.field, e)))?,
+                    ),
+                    _ => None,
+                };
+                Ok(Rule {
+                    rule: rule.clone(),
+                    regex,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Policy { rules })
+    }
+
+    #[tracing::instrument(skip(self, metadata))]
+    pub fn evaluate(&self, metadata: &Metadata) -> PolicyViolations {
+        let mut violations = PolicyViolations::default();
+
+        for Rule { rule, regex } in &self.rules {
+            if rule.exceptions.iter().any(|name| name == &metadata.name) {
+                continue;
+            }
+
+            let values = field_values(metadata, rule.field);
+            let violation = match &rule.condition {
+                PolicyCondition::Required(true) if values.is_empty() => {
+                    Some(format!("{} is required", rule.field))
+                }
+                PolicyCondition::Forbidden(true) if !values.is_empty() => {
+                    Some(format!("{} is not allowed", rule.field))
+                }
+                PolicyCondition::OneOf(allowed) => values
+                    .iter()
+                    .find(|value| !allowed.contains(value))
+                    .map(|value| {
+                        format!(
+                            "{} must be one of {} but is {}",
+                            rule.field,
+                            allowed.join(", "),
+                            value
+                        )
+                    }),
+                PolicyCondition::Matches(pattern) => {
+                    let regex = regex.as_ref().expect("should be compiled by Policy::new");
+                    values
+                        .iter()
+                        .find(|value| !regex.is_match(value))
+                        .map(|value| {
+                            format!("{} must match {} but is {}", rule.field, pattern, value)
+                        })
+                }
+                _ => None,
+            };
+
+            if let Some(violation) = violation {
+                let message = rule.message.clone().unwrap_or(violation);
+                match rule.severity {
+                    PolicySeverity::Deny => violations.denials.push(message),
+                    PolicySeverity::Warn => violations.warnings.push(message),
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+/// Returns the non-empty values of the field.
+#[tracing::instrument(skip(metadata, field))]
+fn field_values(metadata: &Metadata, field: PolicyField) -> Vec<String> {
+    let values = match field {
+        PolicyField::Name => vec![metadata.name.clone()],
+        PolicyField::Authors => metadata.authors.clone(),
+        PolicyField::Description => metadata.description.iter().cloned().collect(),
+        PolicyField::Documentation => metadata.documentation.iter().cloned().collect(),
+        PolicyField::Homepage => metadata.homepage.iter().map(|u| u.to_string()).collect(),
+        PolicyField::Readme => metadata.readme.iter().cloned().collect(),
+        PolicyField::Keywords => metadata.keywords.clone(),
+        PolicyField::Categories => metadata.categories.clone(),
+        PolicyField::License => metadata.license.iter().cloned().collect(),
+        PolicyField::LicenseFile => metadata.license_file.iter().cloned().collect(),
+        PolicyField::Repository => metadata.repository.iter().map(|u| u.to_string()).collect(),
+        PolicyField::Links => metadata.links.iter().cloned().collect(),
+    };
+
+    values
+        .into_iter()
+        .filter(|value| !value.trim().is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policy, PolicyViolations};
+    use crate::config::PolicyConfig;
+    use crate::models::Metadata;
+
+    fn metadata() -> Metadata {
+        serde_json::from_value(serde_json::json!({
+            "name": "foo",
+            "vers": "0.1.0",
+            "deps": [],
+            "features": {},
+            "authors": [],
+            "description": "",
+            "documentation": null,
+            "homepage": null,
+            "readme": null,
+            "readme_file": null,
+            "keywords": [],
+            "categories": [],
+            "license": "GPL-3.0",
+            "license_file": null,
+            "repository": "https://github.com/foo/foo",
+            "badges": {},
+            "links": "foo"
+        }))
+        .unwrap()
+    }
+
+    fn policy(rules: &str) -> Policy {
+        let config: PolicyConfig = toml::from_str(rules).unwrap();
+        Policy::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_evaluate_policy() {
+        let policy = policy(
+            r#"
+            [[rules]]
+            field = "license"
+            one_of = ["MIT", "Apache-2.0"]
+
+            [[rules]]
+            field = "repository"
+            matches = "^https://gitlab\\.example\\.com/"
+            severity = "warn"
+
+            [[rules]]
+            field = "description"
+            required = true
+            message = "describe the crate"
+
+            [[rules]]
+            field = "links"
+            forbidden = true
+            exceptions = ["bar"]
+            "#,
+        );
+
+        assert_eq!(
+            policy.evaluate(&metadata()),
+            PolicyViolations {
+                denials: vec![
+                    "license must be one of MIT, Apache-2.0 but is GPL-3.0".to_owned(),
+                    "describe the crate".to_owned(),
+                    "links is not allowed".to_owned(),
+                ],
+                warnings: vec![
+                    "repository must match ^https://gitlab\\.example\\.com/ but is https://github.com/foo/foo".to_owned()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_evaluate_policy_exceptions() {
+        let policy = policy(
+            r#"
+            [[rules]]
+            field = "links"
+            forbidden = true
+            exceptions = ["foo"]
+            "#,
+        );
+
+        assert_eq!(policy.evaluate(&metadata()), PolicyViolations::default());
+    }
+
+    #[test]
+    fn test_invalid_regex_is_rejected() {
+        let config: PolicyConfig = toml::from_str(
+            r#"
+            [[rules]]
+            field = "name"
+            matches = "("
+            "#,
+        )
+        .unwrap();
+
+        assert!(Policy::new(&config).is_err());
+    }
+}
@@ -1,127 +1,242 @@
+use crate::config::PublishConfig;
+use crate::crate_file;
 use crate::db_manager::DbManager;
 use crate::error::Error;
 use crate::index_manager::IndexManager;
-use crate::models::{Metadata, Owners};
+use crate::models::{Deprecation, DeprecationQuery, Metadata, Owners, PublishWarnings};
+use crate::policy::Policy;
+use crate::storage_manager::StorageManager;
 use crate::utils::{
-    authorization_header, empty_json_message, ok_json_message, ok_with_msg_json_message,
-    with_db_manager, with_dl_dir_path, with_index_manager,
+    authorization_header, check_yanked_changeable, ok_json_message, ok_with_msg_json_message,
+    random_alphanumeric_string, with_db_manager, with_dl_dir_path, with_index_manager, with_policy,
+    with_publish_config, with_storage_manager,
 };
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
+use futures::stream::{BoxStream, Stream, StreamExt, TryStreamExt};
 use futures::TryFutureExt;
 use semver::Version;
 use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::convert::TryInto;
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::RwLock;
 use warp::{Filter, Rejection, Reply};
 
-#[tracing::instrument(skip(db_manager, index_manager, dl_dir_path))]
+/// The directory in `dl_dir_path` where the crate files are received before they are published.
+pub(crate) const UPLOADS_DIR: &str = ".uploads";
+
+type BodyStream = BoxStream<'static, Result<Bytes, warp::Error>>;
+
+#[tracing::instrument(skip(
+    db_manager,
+    index_manager,
+    storage_manager,
+    dl_dir_path,
+    publish_config,
+    policy
+))]
 pub fn apis(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
     dl_dir_path: Arc<PathBuf>,
+    publish_config: Arc<PublishConfig>,
+    policy: Arc<Policy>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    new(db_manager.clone(), index_manager.clone(), dl_dir_path)
-        .or(unyank(db_manager.clone(), index_manager))
-        .or(owners(db_manager))
+    new(
+        db_manager.clone(),
+        index_manager.clone(),
+        storage_manager,
+        dl_dir_path,
+        publish_config,
+        policy,
+    )
+    .or(unyank(db_manager.clone(), index_manager))
+    .or(owners(db_manager.clone()))
+    .or(deprecation(db_manager))
 }
 
-#[tracing::instrument(skip(db_manager, index_manager, dl_dir_path))]
+#[tracing::instrument(skip(
+    db_manager,
+    index_manager,
+    storage_manager,
+    dl_dir_path,
+    publish_config,
+    policy
+))]
 fn new(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
+    storage_manager: Arc<impl StorageManager>,
     dl_dir_path: Arc<PathBuf>,
+    publish_config: Arc<PublishConfig>,
+    policy: Arc<Policy>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::put()
         .and(with_db_manager(db_manager))
         .and(with_index_manager(index_manager))
         .and(authorization_header())
+        .and(with_storage_manager(storage_manager))
         .and(with_dl_dir_path(dl_dir_path))
+        .and(with_publish_config(publish_config.clone()))
+        .and(with_policy(policy))
         .and(warp::path!("api" / "v1" / "crates" / "new"))
-        .and(warp::body::bytes())
+        .and(body_length_limit(&publish_config))
+        .and(warp::body::stream().map(into_body_stream))
         .and_then(handle_new)
 }
 
-#[tracing::instrument(skip(db_manager, index_manager, token, dl_dir_path, body))]
+#[tracing::instrument(skip(
+    db_manager,
+    index_manager,
+    token,
+    storage_manager,
+    dl_dir_path,
+    publish_config,
+    policy,
+    body
+))]
+#[allow(clippy::too_many_arguments)]
 async fn handle_new(
     db_manager: Arc<RwLock<impl DbManager>>,
     index_manager: Arc<IndexManager>,
     token: String,
+    storage_manager: Arc<impl StorageManager>,
     dl_dir_path: Arc<PathBuf>,
-    body: Bytes,
+    publish_config: Arc<PublishConfig>,
+    policy: Arc<Policy>,
+    body: BodyStream,
 ) -> Result<impl Reply, Rejection> {
-    let db_manager = db_manager.write().await;
-
     let user_id = db_manager
+        .read()
+        .await
         .user_id_for_token(&token)
         .map_err(warp::reject::custom)
         .await?;
 
     tracing::debug!("user_id: {}", user_id);
 
-    // body length must be greater than or equals to 4 bytes.
-    let (metadata_length, remainder) = len(body, 4).map_err(warp::reject::custom)?;
+    let mut reader = BodyReader::new(body, body_limit(&publish_config));
+
+    let metadata_length = reader.read_length().map_err(warp::reject::custom).await?;
     tracing::debug!("metadata length: {}", metadata_length);
 
-    // the remainder's length must be greater than or equals to `metadata_length` bytes.
-    let (metadata_string, remainder) = map(remainder, metadata_length, |bytes| {
-        String::from_utf8(bytes[..].to_vec()).map_err(Error::InvalidUtf8Bytes)
-    })
-    .map_err(warp::reject::custom)?;
-    let metadata: Metadata = serde_json::from_str(&metadata_string)
+    if metadata_length as u64 > publish_config.max_metadata_size {
+        return Err(warp::reject::custom(Error::MetadataTooLarge(
+            metadata_length as u64,
+            publish_config.max_metadata_size,
+        )));
+    }
+
+    let metadata_string = reader
+        .read_exact(metadata_length)
+        .await
+        .and_then(|bytes| String::from_utf8(bytes).map_err(Error::InvalidUtf8Bytes))
+        .map_err(warp::reject::custom)?;
+    let mut metadata: Metadata = serde_json::from_str(&metadata_string)
         .map_err(Error::InvalidJson)
         .map_err(warp::reject::custom)?;
 
-    // check if not exist in the database
-    let name = metadata.name.clone();
-    let name_cloned = name.clone();
-    let version = metadata.vers.clone();
-    db_manager
-        .can_add_metadata(user_id, &name, version.clone())
-        .and_then(|addable| async move {
-            if addable {
-                Ok(())
-            } else {
-                Err(Error::OverlappedCrateName(name_cloned))
-            }
-        })
+    check_dependencies(&*db_manager.read().await, &publish_config, &metadata)
         .map_err(warp::reject::custom)
         .await?;
 
-    // the remainder's length must be greater than or equals to 4 bytes.
-    let (crate_length, remainder) = len(remainder, 4).map_err(warp::reject::custom)?;
+    let policy_violations = policy.evaluate(&metadata);
+    if !policy_violations.denials.is_empty() {
+        return Err(warp::reject::custom(Error::PolicyViolation(
+            policy_violations.denials,
+        )));
+    }
+
+    let crate_length = reader.read_length().map_err(warp::reject::custom).await?;
     tracing::debug!("crate length: {}", crate_length);
 
-    // the remainder's length must be `crate_length` exactly.
-    let (crate_data, remainder) =
-        map(remainder, crate_length, Result::Ok).map_err(warp::reject::custom)?;
+    if crate_length as u64 > publish_config.max_crate_size {
+        return Err(warp::reject::custom(Error::CrateTooLarge(
+            crate_length as u64,
+            publish_config.max_crate_size,
+        )));
+    }
 
-    if remainder.is_empty() {
-        let checksum = checksum(&crate_data);
+    metadata.crate_size = Some(crate_length as u64);
 
-        let package = metadata.to_package(checksum);
-        index_manager
-            .add_package(package)
-            .map_err(warp::reject::custom)
-            .await?;
+    // reject the publishing before receiving the crate file if possible.
+    check_publishable(
+        &*db_manager.read().await,
+        &publish_config,
+        user_id,
+        &metadata,
+    )
+    .map_err(warp::reject::custom)
+    .await?;
 
-        let mut crates_dir_path = dl_dir_path.to_path_buf();
-        crates_dir_path.push(&metadata.name);
-        crates_dir_path.push(metadata.vers.to_string());
-        let crates_dir_path = crates_dir_path;
+    let upload_path = upload_path(dl_dir_path.as_path())
+        .map_err(warp::reject::custom)
+        .await?;
+    let checksum = match receive_crate_file(reader, &upload_path, crate_length, &metadata).await {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            remove_upload(&upload_path).await;
+            return Err(warp::reject::custom(e));
+        }
+    };
 
-        save_crate_file(crates_dir_path, &crate_data)
-            .map_err(warp::reject::custom)
-            .await?;
-        db_manager
-            .add_new_metadata(user_id, metadata)
-            .map_ok(empty_json_message)
-            .map_err(warp::reject::custom)
-            .await
-    } else {
-        Err(Error::InvalidBodyLength(remainder.len())).map_err(warp::reject::custom)
+    let mut warnings = publish_warnings(&metadata, &publish_config);
+    warnings.other.extend(policy_violations.warnings);
+    // the records the client may send, e.g. `yanked`, are overwritten before the index line is made.
+    metadata.record_publishing(user_id);
+    let package = metadata.to_package(checksum.clone());
+    metadata.cksum = Some(checksum);
+    let name = metadata.name.clone();
+    let version = metadata.vers.clone();
+
+    // the write lock is taken only to commit the publishing, so the checks are done again
+    // because the concurrent publishes may be committed while receiving the crate file.
+    let db_manager_lock = db_manager.clone();
+    let db_manager = db_manager.write().await;
+
+    if let Err(e) = check_publishable(&*db_manager, &publish_config, user_id, &metadata).await {
+        remove_upload(&upload_path).await;
+        return Err(warp::reject::custom(e));
     }
+
+    // the index is updated at last so that it never refers to a crate
+    // which is not downloadable or not registered in the database.
+    if let Err(e) = storage_manager.put(&name, &version, &upload_path).await {
+        remove_upload(&upload_path).await;
+        return Err(warp::reject::custom(e));
+    }
+
+    if let Err(e) = db_manager.add_new_metadata(user_id, metadata).await {
+        remove_crate_file(&*storage_manager, &name, &version).await;
+        return Err(warp::reject::custom(e));
+    }
+
+    // release the lock while updating the index so that the index edits of
+    // the concurrent publishes can be committed together.
+    drop(db_manager);
+
+    if let Err(e) = index_manager.add_package(package).await {
+        tracing::error!(
+            "failed to update the index so roll back the publishing: {}",
+            e
+        );
+
+        let db_manager = db_manager_lock.write().await;
+        if let Err(e) = db_manager.remove_metadata(&name, version.clone()).await {
+            tracing::error!("failed to remove the metadata from the database: {}", e);
+        }
+        remove_crate_file(&*storage_manager, &name, &version).await;
+        return Err(warp::reject::custom(e));
+    }
+
+    Ok(warp::reply::json(
+        &serde_json::json!({ "warnings": warnings }),
+    ))
 }
 
 #[tracing::instrument(skip(db_manager, index_manager))]
@@ -147,15 +262,17 @@ async fn handle_unyank(
     crate_name: String,
     version: Version,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let db_manager = db_manager.write().await;
-
     let user_id = db_manager
+        .read()
+        .await
         .user_id_for_token(&token)
         .map_err(warp::reject::custom)
         .await?;
 
     let crate_name_cloned = crate_name.clone();
     db_manager
+        .read()
+        .await
         .can_edit_package(user_id, &crate_name, version.clone())
         .and_then(|editable| async move {
             if editable {
@@ -167,18 +284,63 @@ async fn handle_unyank(
         .map_err(warp::reject::custom)
         .await?;
 
+    // the crate lock, instead of the database lock, keeps the concurrent yanks and unyanks
+    // of the crate in order so that the index edits of the other crates can be committed together.
+    let _crate_lock = index_manager.lock_crate(&crate_name).await;
+    check_yanked_changeable(&*db_manager.read().await, &crate_name, &version, false)
+        .map_err(warp::reject::custom)
+        .await?;
     index_manager
         .unyank(&crate_name, version.clone())
         .map_err(warp::reject::custom)
         .await?;
 
     db_manager
+        .write()
+        .await
         .unyank(&crate_name, version)
         .map_ok(ok_json_message)
         .map_err(warp::reject::custom)
         .await
 }
 
+#[tracing::instrument(skip(db_manager))]
+fn deprecation(
+    db_manager: Arc<RwLock<impl DbManager>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::put()
+        .and(with_db_manager(db_manager))
+        .and(authorization_header())
+        .and(warp::path!("api" / "v1" / "crates" / String / "deprecate"))
+        .and(warp::query::<DeprecationQuery>())
+        .and_then(handle_deprecation)
+}
+
+#[tracing::instrument(skip(db_manager, token, name, query))]
+async fn handle_deprecation(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    token: String,
+    name: String,
+    query: DeprecationQuery,
+) -> Result<impl Reply, Rejection> {
+    let db_manager = db_manager.write().await;
+
+    let user_id = db_manager
+        .user_id_for_token(&token)
+        .map_err(warp::reject::custom)
+        .await?;
+    db_manager
+        .can_edit_owners(user_id, &name)
+        .map_err(warp::reject::custom)
+        .await?;
+
+    db_manager
+        .set_deprecation(&name, Some(Deprecation::new(query.successor)))
+        .map_ok(ok_json_message)
+        .map_err(warp::reject::custom)
+        .await
+}
+
 #[tracing::instrument(skip(db_manager))]
 fn owners(
     db_manager: Arc<RwLock<impl DbManager>>,
@@ -233,56 +395,761 @@ async fn handle_owners(
         .await
 }
 
-#[tracing::instrument(skip(bytes, required_length))]
-fn len(mut bytes: Bytes, required_length: usize) -> Result<(usize, Bytes), Error> {
-    if bytes.len() < required_length {
-        Err(Error::InvalidBodyLength(bytes.len()))
-    } else {
-        Ok((
-            u32::from_le_bytes(
-                bytes.split_to(required_length)[..]
-                    .try_into()
-                    .expect("should be 4 bytes"),
-            ) as usize,
-            bytes,
-        ))
+/// Returns the size of the largest body, which consists of the largest crate file and metadata.
+#[tracing::instrument(skip(publish_config))]
+fn body_limit(publish_config: &PublishConfig) -> u64 {
+    // the metadata and the crate file are prefixed by their 4 bytes lengths.
+    publish_config
+        .max_metadata_size
+        .saturating_add(publish_config.max_crate_size)
+        .saturating_add(8)
+}
+
+/// Rejects the request whose `Content-Length` exceeds the body limit before the body is buffered.
+/// The chunked requests without it are limited by `BodyReader` while the body is streamed.
+#[tracing::instrument(skip(publish_config))]
+fn body_length_limit(
+    publish_config: &PublishConfig,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let limit = body_limit(publish_config);
+
+    warp::header::optional::<u64>("Content-Length")
+        .and_then(move |length: Option<u64>| async move {
+            match length {
+                Some(length) if length > limit => {
+                    Err(warp::reject::custom(Error::BodyTooLarge(length, limit)))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+/// Checks if the user can publish the version of the crate within the storage quotas.
+#[tracing::instrument(skip(db_manager, publish_config, user_id, metadata))]
+async fn check_publishable(
+    db_manager: &impl DbManager,
+    publish_config: &PublishConfig,
+    user_id: u32,
+    metadata: &Metadata,
+) -> Result<(), Error> {
+    if !db_manager
+        .can_add_metadata(user_id, &metadata.name, metadata.vers.clone())
+        .await?
+    {
+        return Err(Error::OverlappedCrateName(metadata.name.clone()));
+    }
+
+    check_quotas(
+        db_manager,
+        publish_config,
+        user_id,
+        &metadata.name,
+        metadata.crate_size.unwrap_or_default(),
+    )
+    .await
+}
+
+/// Checks if the dependencies in this registry have the non-yanked versions matching the requirements
+/// and the other dependencies come from the allowed registries.
+#[tracing::instrument(skip(db_manager, publish_config, metadata))]
+async fn check_dependencies(
+    db_manager: &impl DbManager,
+    publish_config: &PublishConfig,
+    metadata: &Metadata,
+) -> Result<(), Error> {
+    for dependency in &metadata.deps {
+        match &dependency.registry {
+            Some(registry) => {
+                if let Some(allowed_registries) = &publish_config.allowed_registries {
+                    let is_allowed = allowed_registries.iter().any(|allowed| {
+                        allowed.as_str().trim_end_matches('/')
+                            == registry.as_str().trim_end_matches('/')
+                    });
+                    if !is_allowed {
+                        return Err(Error::RegistryNotAllowed(
+                            dependency.name.clone(),
+                            registry.clone(),
+                        ));
+                    }
+                }
+            }
+            None => {
+                let entry = db_manager.crate_entry(&dependency.name).await?;
+                let is_satisfied = entry.versions().values().any(|metadata| {
+                    !metadata.yanked && dependency.version_req.matches(&metadata.vers)
+                });
+                if !is_satisfied {
+                    return Err(Error::DependencyNotFound(
+                        dependency.name.clone(),
+                        dependency.version_req.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks if publishing the crate file of `crate_size` bytes keeps the storage usages within the quotas.
+#[tracing::instrument(skip(db_manager, publish_config, user_id, name, crate_size))]
+async fn check_quotas(
+    db_manager: &impl DbManager,
+    publish_config: &PublishConfig,
+    user_id: u32,
+    name: &str,
+    crate_size: u64,
+) -> Result<(), Error> {
+    if let Some(user_quota) = publish_config.user_quota {
+        let usage = db_manager.user_storage_usage(user_id).await?;
+        if usage + crate_size > user_quota {
+            return Err(Error::UserQuotaExceeded(usage, crate_size, user_quota));
+        }
+    }
+
+    if let Some(crate_quota) = publish_config.crate_quota {
+        let usage = db_manager.crate_storage_usage(name).await?;
+        if usage + crate_size > crate_quota {
+            return Err(Error::CrateQuotaExceeded(
+                name.to_owned(),
+                usage,
+                crate_size,
+                crate_quota,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the sizes of the crate files of the versions published before the sizes are recorded,
+/// which otherwise count as 0 bytes against the quotas, and returns how many are recorded.
+#[tracing::instrument(skip(db_manager, storage_manager))]
+pub async fn backfill_crate_sizes(
+    db_manager: &impl DbManager,
+    storage_manager: &impl StorageManager,
+) -> Result<usize, Error> {
+    let unknown_sizes: HashSet<(String, Version)> = db_manager
+        .entries()
+        .await?
+        .iter()
+        .flat_map(|entry| {
+            entry
+                .versions()
+                .values()
+                .filter(|metadata| metadata.crate_size.is_none())
+                .map(|metadata| (metadata.name.to_ascii_lowercase(), metadata.vers.clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    if unknown_sizes.is_empty() {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    for crate_file in storage_manager.list().await? {
+        let key = (crate_file.name.to_ascii_lowercase(), crate_file.version);
+        if unknown_sizes.contains(&key) {
+            db_manager
+                .set_crate_size(&crate_file.name, key.1, crate_file.size)
+                .await?;
+            count += 1;
+        }
     }
+    Ok(count)
 }
 
-#[tracing::instrument(skip(bytes, required_length, f))]
-fn map<F, T>(mut bytes: Bytes, required_length: usize, f: F) -> Result<(T, Bytes), Error>
+/// Collects the warnings about the metadata which is accepted but may be unintended.
+#[tracing::instrument(skip(metadata, publish_config))]
+fn publish_warnings(metadata: &Metadata, publish_config: &PublishConfig) -> PublishWarnings {
+    let is_allowed = |allowed: &Option<Vec<String>>, value: &str| match allowed {
+        Some(allowed) => allowed.iter().any(|a| a == value),
+        None => true,
+    };
+
+    let invalid_categories = metadata
+        .categories
+        .iter()
+        .filter(|category| !is_allowed(&publish_config.allowed_categories, category))
+        .cloned()
+        .collect();
+
+    let mut invalid_badges: Vec<String> = metadata
+        .badges
+        .keys()
+        .filter(|badge| !is_allowed(&publish_config.allowed_badges, badge))
+        .cloned()
+        .collect();
+    invalid_badges.sort();
+
+    // the same restrictions as crates.io.
+    let mut other = Vec::new();
+    if metadata.keywords.len() > 5 {
+        other.push(format!(
+            "expected at most 5 keywords but {} are given",
+            metadata.keywords.len()
+        ));
+    }
+    for keyword in &metadata.keywords {
+        let is_valid_keyword = keyword.len() <= 20
+            && keyword.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && keyword
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '+');
+        if !is_valid_keyword {
+            other.push(format!("invalid keyword: {}", keyword));
+        }
+    }
+
+    PublishWarnings {
+        invalid_categories,
+        invalid_badges,
+        other,
+    }
+}
+
+#[tracing::instrument(skip(stream))]
+fn into_body_stream<S, B>(stream: S) -> BodyStream
 where
-    F: FnOnce(Bytes) -> Result<T, Error>,
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
 {
-    if bytes.len() < required_length {
-        Err(Error::InvalidBodyLength(bytes.len()))
-    } else {
-        f(bytes.split_to(required_length)).map(|v| (v, bytes))
-    }
+    stream
+        .map_ok(|mut chunk| chunk.copy_to_bytes(chunk.remaining()))
+        .boxed()
 }
 
-#[tracing::instrument(skip(data))]
-fn checksum(data: &[u8]) -> String {
-    let mut hasher = Sha256::default();
-    hasher.update(data);
-    let checksum = hasher.finalize();
-    format!("{:x}", checksum)
+/// Reads the length-prefixed parts of the publish request body incrementally.
+struct BodyReader {
+    stream: BodyStream,
+    buffer: Bytes,
+    /// The number of bytes read so far.
+    position: usize,
+    /// The number of bytes received so far.
+    received: u64,
+    /// The maximum number of bytes to receive.
+    limit: u64,
 }
 
-#[tracing::instrument(skip(crates_dir_path, crate_data))]
-async fn save_crate_file(
-    crates_dir_path: impl AsRef<Path>,
-    crate_data: &[u8],
-) -> Result<(), Error> {
-    let crates_dir_path = crates_dir_path.as_ref().to_path_buf();
-    tokio::fs::create_dir_all(&crates_dir_path)
+impl BodyReader {
+    #[tracing::instrument(skip(stream, limit))]
+    fn new(stream: BodyStream, limit: u64) -> BodyReader {
+        BodyReader {
+            stream,
+            buffer: Bytes::new(),
+            position: 0,
+            received: 0,
+            limit,
+        }
+    }
+
+    /// Fills the buffer with the next chunk if it is empty and returns `false` at the end of the body.
+    #[tracing::instrument(skip(self))]
+    async fn fill_buffer(&mut self) -> Result<bool, Error> {
+        while self.buffer.is_empty() {
+            match self.stream.try_next().map_err(Error::Body).await? {
+                Some(chunk) => {
+                    self.received = self.received.saturating_add(chunk.len() as u64);
+                    if self.received > self.limit {
+                        return Err(Error::BodyTooLarge(self.received, self.limit));
+                    }
+                    self.buffer = chunk;
+                }
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads at most `max_length` bytes but at least 1 byte.
+    #[tracing::instrument(skip(self))]
+    async fn read_chunk(&mut self, max_length: usize) -> Result<Bytes, Error> {
+        if !self.fill_buffer().await? {
+            return Err(Error::InvalidBodyLength(self.position));
+        }
+
+        let chunk = self.buffer.split_to(max_length.min(self.buffer.len()));
+        self.position += chunk.len();
+        Ok(chunk)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn read_exact(&mut self, length: usize) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::with_capacity(length);
+        while bytes.len() < length {
+            bytes.extend_from_slice(&self.read_chunk(length - bytes.len()).await?);
+        }
+        Ok(bytes)
+    }
+
+    /// Reads a 32 bit little-endian length.
+    #[tracing::instrument(skip(self))]
+    async fn read_length(&mut self) -> Result<usize, Error> {
+        let bytes = self.read_exact(4).await?;
+        Ok(u32::from_le_bytes(bytes[..].try_into().expect("should be 4 bytes")) as usize)
+    }
+
+    /// Ensures that nothing remains in the body.
+    #[tracing::instrument(skip(self))]
+    async fn finish(mut self) -> Result<(), Error> {
+        if self.fill_buffer().await? {
+            Err(Error::InvalidBodyLength(self.position + self.buffer.len()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Returns a unique path in the `.uploads` directory to receive a crate file.
+#[tracing::instrument(skip(dl_dir_path))]
+async fn upload_path(dl_dir_path: impl AsRef<Path>) -> Result<PathBuf, Error> {
+    let mut upload_path = dl_dir_path.as_ref().to_path_buf();
+    upload_path.push(UPLOADS_DIR);
+    tokio::fs::create_dir_all(&upload_path)
         .map_err(Error::Io)
         .await?;
 
-    let mut crate_binary_path = crates_dir_path;
-    crate_binary_path.push("download");
+    upload_path.push(random_alphanumeric_string(32).await?);
+    Ok(upload_path)
+}
 
-    tokio::fs::write(crate_binary_path, &crate_data)
-        .map_err(Error::Io)
+/// Streams the crate file of `crate_length` bytes into `upload_path`, validates it
+/// and returns its checksum.
+#[tracing::instrument(skip(reader, upload_path, crate_length, metadata))]
+async fn receive_crate_file(
+    mut reader: BodyReader,
+    upload_path: &Path,
+    crate_length: usize,
+    metadata: &Metadata,
+) -> Result<String, Error> {
+    let mut file = File::create(upload_path).map_err(Error::Io).await?;
+    let mut hasher = Sha256::default();
+
+    let mut remaining = crate_length;
+    while remaining > 0 {
+        let chunk = reader.read_chunk(remaining).await?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).map_err(Error::Io).await?;
+        remaining -= chunk.len();
+    }
+    file.sync_all().map_err(Error::Io).await?;
+    reader.finish().await?;
+
+    let path = upload_path.to_path_buf();
+    let name = metadata.name.clone();
+    let version = metadata.vers.clone();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(path)?;
+        crate_file::validate(BufReader::new(file), &name, &version)
+    })
+    .map_err(Error::Join)
+    .await??;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Removes the crate file which is received but not saved.
+/// Failures are only logged because this is called while handling the other error.
+#[tracing::instrument(skip(upload_path))]
+async fn remove_upload(upload_path: impl AsRef<Path>) {
+    let upload_path = upload_path.as_ref();
+    if let Err(e) = tokio::fs::remove_file(upload_path).await {
+        tracing::error!("failed to remove {:?}: {}", upload_path, e);
+    }
+}
+
+/// Removes the crate file saved in the storage.
+/// Failures are only logged because this is called while rolling back the other error.
+#[tracing::instrument(skip(storage_manager, name, version))]
+async fn remove_crate_file(storage_manager: &impl StorageManager, name: &str, version: &Version) {
+    if let Err(e) = storage_manager.delete(name, version).await {
+        tracing::error!(
+            "failed to remove the crate file of {}#{}: {}",
+            name,
+            version,
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{body_limit, publish_warnings, BodyReader};
+    use crate::config::PublishConfig;
+    use crate::error::Error;
+    use crate::models::{Metadata, PublishWarnings};
+    use bytes::Bytes;
+    use futures::stream::StreamExt;
+
+    fn body_reader(chunks: &[&'static [u8]]) -> BodyReader {
+        let chunks: Vec<Result<Bytes, warp::Error>> =
+            chunks.iter().map(|c| Ok(Bytes::from_static(c))).collect();
+        BodyReader::new(futures::stream::iter(chunks).boxed(), 16)
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_across_chunks() {
+        let mut reader = body_reader(&[b"\x03\x00", b"\x00\x00fo", b"", b"obar"]);
+
+        assert_eq!(reader.read_length().await.unwrap(), 3);
+        assert_eq!(reader.read_exact(3).await.unwrap(), b"foo".to_vec());
+        assert_eq!(&reader.read_chunk(8).await.unwrap()[..], b"bar");
+        assert!(reader.finish().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_rejects_invalid_lengths() {
+        let mut reader = body_reader(&[b"\x03\x00\x00\x00fo"]);
+        reader.read_length().await.unwrap();
+        assert!(reader.read_exact(3).await.is_err());
+
+        let mut reader = body_reader(&[b"foo", b"bar"]);
+        reader.read_exact(3).await.unwrap();
+        assert!(reader.finish().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_body_reader_rejects_too_large_bodies() {
+        let mut reader = body_reader(&[b"0123456789", b"0123456789"]);
+        reader.read_exact(10).await.unwrap();
+        assert!(matches!(
+            reader.read_exact(10).await,
+            Err(Error::BodyTooLarge(20, 16))
+        ));
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let publish_config = PublishConfig {
+            max_metadata_size: u64::MAX,
+            max_crate_size: 10,
+            ..Default::default()
+        };
+        assert_eq!(body_limit(&publish_config), u64::MAX);
+    }
+
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test]
+    async fn test_storage_usages_and_quotas() {
+        use super::{backfill_crate_sizes, check_quotas};
+        use crate::config::CrateFilesConfig;
+        use crate::db_manager::DbManager;
+        use crate::storage_manager::{FsStorageManager, StorageManager};
+        use crate::test_utils;
+        use semver::Version;
+
+        let metadata = |version: &str, crate_size| {
+            let mut metadata = test_utils::metadata("foo", version);
+            metadata.crate_size = crate_size;
+            metadata
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (user_id, _) = test_utils::add_user(&db_manager, "alice").await;
+        let db_manager = db_manager.write().await;
+
+        // 0.1.0 is published before the crate sizes are recorded.
+        let mut old_metadata = metadata("0.1.0", None);
+        old_metadata.record_publishing(user_id);
+        old_metadata.published_by = None;
+        db_manager
+            .add_new_metadata(user_id, old_metadata)
+            .await
+            .unwrap();
+        let mut new_metadata = metadata("0.2.0", Some(100));
+        new_metadata.record_publishing(user_id);
+        db_manager
+            .add_new_metadata(user_id, new_metadata)
+            .await
+            .unwrap();
+        assert_eq!(db_manager.user_storage_usage(user_id).await.unwrap(), 100);
+
+        let publish_config = PublishConfig {
+            user_quota: Some(150),
+            crate_quota: Some(200),
+            ..Default::default()
+        };
+        assert!(
+            check_quotas(&*db_manager, &publish_config, user_id, "foo", 50)
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            check_quotas(&*db_manager, &publish_config, user_id, "foo", 51).await,
+            Err(Error::UserQuotaExceeded(100, 51, 150))
+        ));
+
+        let crate_files_config = CrateFilesConfig {
+            dl_dir_path: dir.path().join("crates"),
+            ..Default::default()
+        };
+        let storage_manager = FsStorageManager::new(&crate_files_config).await.unwrap();
+        let crate_file_path = dir.path().join("foo-0.1.0.crate");
+        tokio::fs::write(&crate_file_path, [0u8; 30]).await.unwrap();
+        storage_manager
+            .put("foo", &Version::new(0, 1, 0), &crate_file_path)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backfill_crate_sizes(&*db_manager, &storage_manager)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(db_manager.user_storage_usage(user_id).await.unwrap(), 130);
+        assert!(matches!(
+            check_quotas(&*db_manager, &publish_config, user_id, "foo", 50).await,
+            Err(Error::UserQuotaExceeded(130, 50, 150))
+        ));
+        assert!(matches!(
+            check_quotas(&*db_manager, &publish_config, user_id + 1, "foo", 80).await,
+            Err(Error::CrateQuotaExceeded(_, 130, 80, 200))
+        ));
+
+        db_manager
+            .remove_metadata("foo", Version::new(0, 2, 0))
+            .await
+            .unwrap();
+        assert_eq!(db_manager.user_storage_usage(user_id).await.unwrap(), 30);
+    }
+
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_publish_is_rolled_back_on_index_failure() {
+        use super::UPLOADS_DIR;
+        use crate::db_manager::DbManager;
+        use crate::storage_manager::StorageManager;
+        use crate::test_utils;
+        use semver::Version;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (db_manager, _, storage_manager, filter) = publish_filter(dir.path()).await;
+        let (_, token) = test_utils::add_user(&db_manager, "alice").await;
+        // the pushes fail because the origin is gone.
+        std::fs::remove_dir_all(dir.path().join("index.git")).unwrap();
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", token)
+            .body(test_utils::publish_body(&test_utils::metadata(
+                "foo", "0.1.0",
+            )))
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body["errors"][0]["detail"]
+            .as_str()
+            .unwrap()
+            .starts_with("git error"));
+
+        let db_manager = db_manager.read().await;
+        let entry = db_manager.crate_entry("foo").await.unwrap();
+        assert!(entry.versions().is_empty());
+        assert!(!storage_manager
+            .exists("foo", &Version::new(0, 1, 0))
+            .await
+            .unwrap());
+        let mut uploads = std::fs::read_dir(dir.path().join("crates").join(UPLOADS_DIR)).unwrap();
+        assert!(uploads.next().is_none());
+    }
+
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_publish_records_the_version_as_not_yanked() {
+        use crate::db_manager::DbManager;
+        use crate::test_utils;
+        use semver::Version;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (db_manager, index_manager, _, filter) = publish_filter(dir.path()).await;
+        let (_, token) = test_utils::add_user(&db_manager, "alice").await;
+
+        // the client sends the version as yanked.
+        let mut metadata = test_utils::metadata("foo", "0.1.0");
+        metadata.yanked = true;
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/api/v1/crates/new")
+            .header("Authorization", token)
+            .body(test_utils::publish_body(&metadata))
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert!(body.get("errors").is_none(), "{}", body);
+
+        let db_manager = db_manager.read().await;
+        let entry = db_manager.crate_entry("foo").await.unwrap();
+        assert!(!entry.versions()[&Version::new(0, 1, 0)].yanked);
+        let packages = index_manager.packages().await.unwrap();
+        assert_eq!(packages.len(), 1);
+        assert!(!packages[0].yanked);
+    }
+
+    /// Returns the managers and the publish filter of the registry in `dir_path`.
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    async fn publish_filter(
+        dir_path: &std::path::Path,
+    ) -> (
+        std::sync::Arc<tokio::sync::RwLock<crate::db_manager::SledDbManager>>,
+        std::sync::Arc<crate::index_manager::IndexManager>,
+        std::sync::Arc<crate::storage_manager::FsStorageManager>,
+        impl warp::Filter<Extract = impl warp::Reply, Error = std::convert::Infallible> + Clone,
+    ) {
+        use super::new;
+        use crate::config::{CrateFilesConfig, PolicyConfig};
+        use crate::index_manager::IndexManager;
+        use crate::policy::Policy;
+        use crate::storage_manager::{FsStorageManager, StorageManager};
+        use crate::test_utils;
+        use std::sync::Arc;
+        use warp::Filter;
+
+        let db_manager = test_utils::db_manager(dir_path).await;
+        let crate_files_config = CrateFilesConfig {
+            dl_dir_path: dir_path.join("crates"),
+            ..Default::default()
+        };
+        let storage_manager = Arc::new(FsStorageManager::new(&crate_files_config).await.unwrap());
+        let index_manager = IndexManager::new(test_utils::index_config(dir_path), &[])
+            .await
+            .unwrap();
+        let index_manager = Arc::new(index_manager);
+
+        let filter = new(
+            db_manager.clone(),
+            index_manager.clone(),
+            storage_manager.clone(),
+            Arc::new(crate_files_config.dl_dir_path),
+            Arc::new(PublishConfig::default()),
+            Arc::new(Policy::new(&PolicyConfig::default()).unwrap()),
+        )
+        .recover(crate::handle_rejection);
+        (db_manager, index_manager, storage_manager, filter)
+    }
+
+    #[cfg(feature = "db-sled")]
+    #[tokio::test]
+    async fn test_check_dependencies() {
+        use super::check_dependencies;
+        use crate::db_manager::DbManager;
+        use crate::models::MetadataDependency;
+        use crate::test_utils;
+        use url::Url;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (user_id, _) = test_utils::add_user(&db_manager, "alice").await;
+        let db_manager = db_manager.write().await;
+        db_manager
+            .add_new_metadata(user_id, test_utils::metadata("bar", "0.1.0"))
+            .await
+            .unwrap();
+        let mut yanked = test_utils::metadata("bar", "0.2.0");
+        yanked.yanked = true;
+        db_manager.add_new_metadata(user_id, yanked).await.unwrap();
+
+        let dependency = |name: &str, version_req: &str, registry: Option<&str>| {
+            let mut metadata = test_utils::metadata("foo", "0.1.0");
+            metadata.deps.push(MetadataDependency {
+                name: name.to_owned(),
+                version_req: version_req.parse().unwrap(),
+                features: Vec::new(),
+                optional: false,
+                default_features: true,
+                target: None,
+                kind: Some("normal".to_owned()),
+                registry: registry.map(|registry| registry.parse().unwrap()),
+                explicit_name_in_toml: None,
+            });
+            metadata
+        };
+        let publish_config = PublishConfig {
+            allowed_registries: Some(vec![Url::parse(
+                "https://github.com/rust-lang/crates.io-index/",
+            )
+            .unwrap()]),
+            ..Default::default()
+        };
+        let (db_manager, publish_config) = (&*db_manager, &publish_config);
+        let check = |metadata| async move {
+            check_dependencies(db_manager, publish_config, &metadata).await
+        };
+
+        assert!(check(dependency("bar", "^0.1", None)).await.is_ok());
+        // the yanked versions do not satisfy the requirements.
+        assert!(matches!(
+            check(dependency("bar", "^0.2", None)).await,
+            Err(Error::DependencyNotFound(name, _)) if name == "bar"
+        ));
+        assert!(matches!(
+            check(dependency("baz", "*", None)).await,
+            Err(Error::DependencyNotFound(name, _)) if name == "baz"
+        ));
+        assert!(check(dependency(
+            "serde",
+            "^1",
+            Some("https://github.com/rust-lang/crates.io-index")
+        ))
         .await
+        .is_ok());
+        assert!(matches!(
+            check(dependency(
+                "serde",
+                "^1",
+                Some("https://example.com/index")
+            ))
+            .await,
+            Err(Error::RegistryNotAllowed(name, _)) if name == "serde"
+        ));
+    }
+
+    #[test]
+    fn test_publish_warnings() {
+        let metadata: Metadata = serde_json::from_value(serde_json::json!({
+            "name": "foo",
+            "vers": "0.1.0",
+            "deps": [],
+            "features": {},
+            "authors": [],
+            "description": null,
+            "documentation": null,
+            "homepage": null,
+            "readme": null,
+            "readme_file": null,
+            "keywords": ["parser", "not a keyword"],
+            "categories": ["parsing", "junk"],
+            "license": null,
+            "license_file": null,
+            "repository": null,
+            "badges": { "maintenance": { "status": "passively-maintained" }, "travis-ci": {} },
+            "links": null
+        }))
+        .unwrap();
+
+        assert_eq!(
+            publish_warnings(&metadata, &PublishConfig::default()),
+            PublishWarnings {
+                other: vec!["invalid keyword: not a keyword".to_owned()],
+                ..Default::default()
+            }
+        );
+
+        let publish_config = PublishConfig {
+            allowed_categories: Some(vec!["parsing".to_owned()]),
+            allowed_badges: Some(vec!["maintenance".to_owned()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            publish_warnings(&metadata, &publish_config),
+            PublishWarnings {
+                invalid_categories: vec!["junk".to_owned()],
+                invalid_badges: vec!["travis-ci".to_owned()],
+                other: vec!["invalid keyword: not a keyword".to_owned()],
+            }
+        );
+    }
 }
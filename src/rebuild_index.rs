@@ -0,0 +1,191 @@
+use crate::config::IndexConfig;
+use crate::db_manager::DbManager;
+use crate::error::Error;
+use crate::index_manager::IndexManager;
+use crate::models::Package;
+use crate::storage_manager::StorageManager;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Regenerates all packages in the index from the database and the crate files in a fresh checkout
+/// and returns the number of the versions skipped because their crate files cannot be read.
+///
+/// All the crate files are checked before the checkout is made and nothing is pushed
+/// if any version would be dropped from the index unless `force` is set.
+/// The fresh checkout is cloned next to `local_path` and removed afterwards,
+/// so nothing left in the local checkout, e.g. broken files or unpushed commits, is pushed.
+#[tracing::instrument(skip(db_manager, storage_manager, index_config, dl_path))]
+pub async fn rebuild_index(
+    db_manager: &impl DbManager,
+    storage_manager: &impl StorageManager,
+    mut index_config: IndexConfig,
+    dl_path: &[String],
+    force: bool,
+) -> Result<usize, Error> {
+    let (packages, skipped) = packages(db_manager, storage_manager).await?;
+    if skipped != 0 && !force {
+        return Err(Error::VersionsWouldBeDropped(skipped));
+    }
+
+    let checkout_path = fresh_checkout_path(&index_config.local_path);
+    // the checkout may be left by the rebuild interrupted before.
+    remove_checkout(&checkout_path).await?;
+
+    tracing::info!("rebuild the index in a fresh checkout: {:?}", checkout_path);
+    index_config.local_path = checkout_path.clone();
+    let result = async {
+        let index_manager = IndexManager::new(index_config, dl_path).await?;
+        tracing::info!("rebuild the index with {} packages", packages.len());
+        index_manager.rebuild(packages).await
+    }
+    .await;
+
+    remove_checkout(&checkout_path).await?;
+    result.map(|_| skipped)
+}
+
+/// Returns the packages of all versions whose crate files can be read
+/// and the number of the versions skipped.
+#[tracing::instrument(skip(db_manager, storage_manager))]
+async fn packages(
+    db_manager: &impl DbManager,
+    storage_manager: &impl StorageManager,
+) -> Result<(Vec<Package>, usize), Error> {
+    let mut packages = Vec::new();
+    let mut skipped = 0;
+
+    for entry in db_manager.entries().await? {
+        for metadata in entry.versions().values() {
+            // the checksum in the index must be computed from the actual crate file.
+            match storage_manager
+                .checksum(&metadata.name, &metadata.vers)
+                .await
+            {
+                Ok(Some(checksum)) => packages.push(metadata.to_package(checksum)),
+                Ok(None) => {
+                    tracing::warn!(
+                        "{}#{}: skipped because the crate file does not exist",
+                        metadata.name,
+                        metadata.vers
+                    );
+                    skipped += 1;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "{}#{}: skipped because the crate file cannot be read: {}",
+                        metadata.name,
+                        metadata.vers,
+                        e
+                    );
+                    skipped += 1;
+                }
+            }
+        }
+    }
+
+    Ok((packages, skipped))
+}
+
+/// Returns the path next to `local_path` to clone the index into, e.g. `index.rebuild` for `index`.
+#[tracing::instrument(skip(local_path))]
+fn fresh_checkout_path(local_path: &Path) -> PathBuf {
+    let mut file_name = local_path
+        .file_name()
+        .map(ToOwned::to_owned)
+        .unwrap_or_default();
+    file_name.push(".rebuild");
+    local_path.with_file_name(file_name)
+}
+
+#[tracing::instrument(skip(checkout_path))]
+async fn remove_checkout(checkout_path: &Path) -> Result<(), Error> {
+    match tokio::fs::remove_dir_all(checkout_path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(Error::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(feature = "db-sled", not(feature = "storage-s3")))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rebuild_index() {
+        use super::rebuild_index;
+        use crate::config::{CrateFilesConfig, IndexConfig};
+        use crate::db_manager::DbManager;
+        use crate::error::Error;
+        use crate::storage_manager::{FsStorageManager, StorageManager};
+        use crate::test_utils;
+        use semver::Version;
+
+        let dir = tempfile::tempdir().unwrap();
+        let db_manager = test_utils::db_manager(dir.path()).await;
+        let (user_id, _) = test_utils::add_user(&db_manager, "alice").await;
+        let db_manager = db_manager.read().await;
+        for version in &["0.1.0", "0.2.0"] {
+            db_manager
+                .add_new_metadata(user_id, test_utils::metadata("foo", version))
+                .await
+                .unwrap();
+        }
+
+        let crate_files_config = CrateFilesConfig {
+            dl_dir_path: dir.path().join("crates"),
+            ..Default::default()
+        };
+        let storage_manager = FsStorageManager::new(&crate_files_config).await.unwrap();
+        let crate_file_path = dir.path().join("foo-0.1.0.crate");
+        tokio::fs::write(&crate_file_path, b"foo").await.unwrap();
+        storage_manager
+            .put("foo", &Version::new(0, 1, 0), &crate_file_path)
+            .await
+            .unwrap();
+
+        // the local checkout is left broken.
+        let local_path = dir.path().join("index");
+        tokio::fs::create_dir_all(&local_path).await.unwrap();
+        let index_config = IndexConfig {
+            local_path,
+            branch: "master".to_owned(),
+            name: "ktra".to_owned(),
+            bare_path: Some(dir.path().join("index.git")),
+            ..Default::default()
+        };
+
+        // nothing is pushed because 0.2.0 would be dropped as its crate file does not exist.
+        assert!(matches!(
+            rebuild_index(
+                &*db_manager,
+                &storage_manager,
+                index_config.clone(),
+                &[],
+                false
+            )
+            .await,
+            Err(Error::VersionsWouldBeDropped(1))
+        ));
+        assert!(!dir.path().join("index.git").exists());
+
+        assert_eq!(
+            rebuild_index(&*db_manager, &storage_manager, index_config, &[], true)
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(!dir.path().join("index.rebuild").exists());
+
+        let bare_repository = git2::Repository::open_bare(dir.path().join("index.git")).unwrap();
+        let tree = bare_repository
+            .head()
+            .and_then(|head| head.peel_to_tree())
+            .unwrap();
+        let blob = tree
+            .get_path(std::path::Path::new("3/f/foo"))
+            .and_then(|entry| entry.to_object(&bare_repository))
+            .and_then(|object| object.peel_to_blob())
+            .unwrap();
+        let content = String::from_utf8(blob.content().to_vec()).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.contains(&crate::utils::checksum(b"foo")));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::db_manager::DbManager;
+use crate::error::Error;
+use crate::get::into_boxed_filters;
+use crate::index_manager::IndexManager;
+use crate::utils::{authorization_if_required, package_dir_path, with_index_manager};
+use futures::TryFutureExt;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+use warp::http::{Response, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+const CONFIG_JSON: &str = "config.json";
+
+#[tracing::instrument(skip(index_manager, db_manager, auth_required, path))]
+pub fn apis(
+    index_manager: Arc<IndexManager>,
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+    path: Vec<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // `config.json` also requires the authorization so that cargo knows to send the token.
+    warp::get()
+        .and(into_boxed_filters(path))
+        .and(warp::path::tail())
+        .and(authorization_if_required(db_manager, auth_required))
+        .and(with_index_manager(index_manager))
+        .and(warp::header::optional::<String>("If-None-Match"))
+        .and(warp::header::optional::<String>("If-Modified-Since"))
+        .and_then(handle_index_file)
+}
+
+#[tracing::instrument(skip(tail, index_manager, if_none_match, if_modified_since))]
+async fn handle_index_file(
+    tail: warp::path::Tail,
+    index_manager: Arc<IndexManager>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+) -> Result<impl Reply, Rejection> {
+    let path = index_file_path(tail.as_str()).ok_or_else(warp::reject::not_found)?;
+
+    let (content, modified) = index_manager
+        .read_file(&path)
+        .map_err(|_| warp::reject::not_found())
+        .await?;
+
+    let etag = format!("\"{:x}\"", Sha256::digest(&content));
+    let last_modified = httpdate::fmt_http_date(modified);
+
+    // `If-None-Match` takes precedence over `If-Modified-Since` as RFC 7232 describes.
+    let not_modified = match (if_none_match, if_modified_since) {
+        (Some(if_none_match), _) => if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag),
+        (None, Some(if_modified_since)) => httpdate::parse_http_date(&if_modified_since)
+            .map(|since| truncate_to_seconds(modified) <= since)
+            .unwrap_or(false),
+        (None, None) => false,
+    };
+
+    let content_type = if path == CONFIG_JSON {
+        "application/json"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+
+    let builder = Response::builder()
+        .header("ETag", etag)
+        .header("Last-Modified", last_modified);
+    let response = if not_modified {
+        builder.status(StatusCode::NOT_MODIFIED).body(Vec::new())
+    } else {
+        builder.header("Content-Type", content_type).body(content)
+    };
+
+    response
+        .map_err(Error::HttpResponseBuilding)
+        .map_err(warp::reject::custom)
+}
+
+/// Converts the requested path into the relative path in the index repository.
+///
+/// Only `config.json` and the files laid out by `package_dir_path` are exposed
+/// so that any other files in the repository (e.g. `.git`) are never served.
+#[tracing::instrument]
+fn index_file_path(tail: &str) -> Option<String> {
+    if tail == CONFIG_JSON {
+        return Some(tail.to_owned());
+    }
+
+    let name = tail.rsplit('/').next()?;
+    let is_valid_name = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+    if !is_valid_name {
+        return None;
+    }
+
+    let dir_path = package_dir_path(name).ok()?;
+    let path = format!("{}/{}", dir_path.as_ref().to_str()?, name);
+
+    if path == tail {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+#[tracing::instrument]
+fn truncate_to_seconds(time: SystemTime) -> SystemTime {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| SystemTime::UNIX_EPOCH + Duration::from_secs(d.as_secs()))
+        .unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::index_file_path;
+
+    #[test]
+    fn test_index_file_path_config_json() {
+        assert_eq!(
+            index_file_path("config.json").as_deref(),
+            Some("config.json")
+        );
+    }
+
+    #[test]
+    fn test_index_file_path_packages() {
+        assert_eq!(index_file_path("1/a").as_deref(), Some("1/a"));
+        assert_eq!(index_file_path("3/a/abc").as_deref(), Some("3/a/abc"));
+        assert_eq!(
+            index_file_path("se/rd/serde").as_deref(),
+            Some("se/rd/serde")
+        );
+    }
+
+    #[test]
+    fn test_index_file_path_rejects_other_files() {
+        assert_eq!(index_file_path(".git/config"), None);
+        assert_eq!(index_file_path("se/rd/../../config.json"), None);
+        assert_eq!(index_file_path("ab/cd/serde"), None);
+        assert_eq!(index_file_path("se/rd/Serde"), None);
+    }
+}
@@ -0,0 +1,11 @@
+#[cfg(not(feature = "storage-s3"))]
+mod fs_storage_manager;
+#[cfg(feature = "storage-s3")]
+mod s3_storage_manager;
+mod traits;
+
+#[cfg(not(feature = "storage-s3"))]
+pub use fs_storage_manager::FsStorageManager;
+#[cfg(feature = "storage-s3")]
+pub use s3_storage_manager::S3StorageManager;
+pub use traits::{StorageManager, StoredCrateFile};
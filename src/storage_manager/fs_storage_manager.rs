@@ -0,0 +1,166 @@
+#![cfg(not(feature = "storage-s3"))]
+
+use crate::config::CrateFilesConfig;
+use crate::error::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryFutureExt;
+use semver::Version;
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncReadExt;
+
+use crate::storage_manager::{StorageManager, StoredCrateFile};
+
+/// Stores the crate files laid out as `{dl_dir_path}/{name}/{version}/download`.
+pub struct FsStorageManager {
+    dl_dir_path: PathBuf,
+}
+
+#[async_trait]
+impl StorageManager for FsStorageManager {
+    #[tracing::instrument(skip(config))]
+    async fn new(config: &CrateFilesConfig) -> Result<FsStorageManager, Error> {
+        tokio::fs::create_dir_all(&config.dl_dir_path)
+            .map_err(Error::Io)
+            .await?;
+
+        Ok(FsStorageManager {
+            dl_dir_path: config.dl_dir_path.clone(),
+        })
+    }
+
+    #[tracing::instrument(skip(self, name, version, path))]
+    async fn put(&self, name: &str, version: &Version, path: &Path) -> Result<(), Error> {
+        tokio::fs::create_dir_all(self.crate_dir_path(name, version))
+            .map_err(Error::Io)
+            .await?;
+
+        tokio::fs::rename(path, self.crate_file_path(name, version))
+            .map_err(Error::Io)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn get(&self, name: &str, version: &Version) -> Result<Option<Bytes>, Error> {
+        match tokio::fs::read(self.crate_file_path(name, version)).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn checksum(&self, name: &str, version: &Version) -> Result<Option<String>, Error> {
+        let mut file = match tokio::fs::File::open(self.crate_file_path(name, version)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut hasher = Sha256::default();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let length = file.read(&mut buffer).map_err(Error::Io).await?;
+            if length == 0 {
+                break;
+            }
+            hasher.update(&buffer[..length]);
+        }
+
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn delete(&self, name: &str, version: &Version) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.crate_file_path(name, version)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        // the version directory is left behind if anything else is placed in it.
+        let crate_dir_path = self.crate_dir_path(name, version);
+        if let Err(e) = tokio::fs::remove_dir(&crate_dir_path).await {
+            tracing::warn!("failed to remove {:?}: {}", crate_dir_path, e);
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn exists(&self, name: &str, version: &Version) -> Result<bool, Error> {
+        match tokio::fs::metadata(self.crate_file_path(name, version)).await {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn list(&self) -> Result<Vec<StoredCrateFile>, Error> {
+        let mut crate_files = Vec::new();
+
+        let mut names = tokio::fs::read_dir(&self.dl_dir_path)
+            .map_err(Error::Io)
+            .await?;
+        while let Some(name_entry) = names.next_entry().map_err(Error::Io).await? {
+            // the directories starting with a dot, e.g. `.uploads`, are not crates.
+            let name = match name_entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') && name_entry.file_type().await?.is_dir() => {
+                    name
+                }
+                _ => continue,
+            };
+
+            let mut versions = tokio::fs::read_dir(name_entry.path())
+                .map_err(Error::Io)
+                .await?;
+            while let Some(version_entry) = versions.next_entry().map_err(Error::Io).await? {
+                let version = match version_entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|v| Version::parse(v).ok())
+                {
+                    Some(version) => version,
+                    None => continue,
+                };
+
+                let metadata =
+                    match tokio::fs::metadata(self.crate_file_path(&name, &version)).await {
+                        Ok(metadata) if metadata.is_file() => metadata,
+                        Ok(_) => continue,
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        Err(e) => return Err(Error::Io(e)),
+                    };
+
+                crate_files.push(StoredCrateFile {
+                    name: name.clone(),
+                    version,
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                });
+            }
+        }
+
+        Ok(crate_files)
+    }
+}
+
+impl FsStorageManager {
+    #[tracing::instrument(skip(self, name, version))]
+    fn crate_dir_path(&self, name: &str, version: &Version) -> PathBuf {
+        let mut crate_dir_path = self.dl_dir_path.clone();
+        crate_dir_path.push(name);
+        crate_dir_path.push(version.to_string());
+        crate_dir_path
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    fn crate_file_path(&self, name: &str, version: &Version) -> PathBuf {
+        let mut crate_file_path = self.crate_dir_path(name, version);
+        crate_file_path.push("download");
+        crate_file_path
+    }
+}
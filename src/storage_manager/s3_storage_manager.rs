@@ -0,0 +1,412 @@
+#![cfg(feature = "storage-s3")]
+
+use crate::config::{CrateFilesConfig, S3Config};
+use crate::error::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::TryFutureExt;
+use hmac::{Hmac, Mac, NewMac};
+use regex::Regex;
+use reqwest::{Client, Method, Response, StatusCode};
+use semver::Version;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::SystemTime;
+use url::Url;
+
+use crate::storage_manager::{StorageManager, StoredCrateFile};
+
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+/// Stores the crate files as the objects keyed `{prefix}{name}/{version}/download`
+/// in an S3-compatible object storage.
+///
+/// The requests are signed with AWS Signature Version 4 and sent in the path-style
+/// (`{endpoint}/{bucket}/{key}`), which the S3-compatible storages commonly support.
+pub struct S3StorageManager {
+    client: Client,
+    endpoint: Url,
+    config: S3Config,
+}
+
+#[async_trait]
+impl StorageManager for S3StorageManager {
+    #[tracing::instrument(skip(config))]
+    async fn new(config: &CrateFilesConfig) -> Result<S3StorageManager, Error> {
+        let config = config.s3_config.clone();
+        tracing::info!("use S3 bucket: {} on {}", config.bucket, config.endpoint);
+
+        let endpoint = Url::parse(&config.endpoint).map_err(Error::UrlParsing)?;
+        let client = Client::builder().build().map_err(Error::HttpRequest)?;
+
+        Ok(S3StorageManager {
+            client,
+            endpoint,
+            config,
+        })
+    }
+
+    #[tracing::instrument(skip(self, name, version, path))]
+    async fn put(&self, name: &str, version: &Version, path: &Path) -> Result<(), Error> {
+        // the crate files are small enough to be sent at once because of `max_crate_size`.
+        let data = tokio::fs::read(path).map_err(Error::Io).await?;
+        let key = self.key(name, version);
+        let response = self
+            .send(Method::PUT, Some(&key), &[], data)
+            .await
+            .and_then(|response| self.check_status(&key, response))?;
+        drop(response);
+
+        tokio::fs::remove_file(path).map_err(Error::Io).await
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn get(&self, name: &str, version: &Version) -> Result<Option<Bytes>, Error> {
+        let key = self.key(name, version);
+        let response = self.send(Method::GET, Some(&key), &[], Vec::new()).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        self.check_status(&key, response)?
+            .bytes()
+            .map_ok(Some)
+            .map_err(Error::HttpRequest)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn checksum(&self, name: &str, version: &Version) -> Result<Option<String>, Error> {
+        let key = self.key(name, version);
+        let response = self.send(Method::GET, Some(&key), &[], Vec::new()).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let mut response = self.check_status(&key, response)?;
+        let mut hasher = Sha256::default();
+        while let Some(chunk) = response.chunk().map_err(Error::HttpRequest).await? {
+            hasher.update(&chunk);
+        }
+
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn delete(&self, name: &str, version: &Version) -> Result<(), Error> {
+        let key = self.key(name, version);
+        let response = self
+            .send(Method::DELETE, Some(&key), &[], Vec::new())
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            Ok(())
+        } else {
+            self.check_status(&key, response).map(drop)
+        }
+    }
+
+    #[tracing::instrument(skip(self, name, version))]
+    async fn exists(&self, name: &str, version: &Version) -> Result<bool, Error> {
+        let key = self.key(name, version);
+        let response = self.send(Method::HEAD, Some(&key), &[], Vec::new()).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            Ok(false)
+        } else {
+            self.check_status(&key, response).map(|_| true)
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn list(&self) -> Result<Vec<StoredCrateFile>, Error> {
+        let contents_regex = Regex::new(
+            "(?s)<Contents>.*?<Key>([^<]*)</Key>.*?<LastModified>([^<]*)</LastModified>.*?<Size>([0-9]+)</Size>.*?</Contents>",
+        )
+        .expect("must be a valid regex");
+        let token_regex = Regex::new("<NextContinuationToken>([^<]*)</NextContinuationToken>")
+            .expect("must be a valid regex");
+
+        let mut crate_files = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_owned()),
+                ("prefix", self.config.prefix.clone()),
+            ];
+            if let Some(token) = continuation_token.take() {
+                query.push(("continuation-token", token));
+            }
+
+            let response = self.send(Method::GET, None, &query, Vec::new()).await?;
+            let body = self
+                .check_status(&self.config.bucket, response)?
+                .text()
+                .map_err(Error::HttpRequest)
+                .await?;
+
+            crate_files.extend(contents_regex.captures_iter(&body).filter_map(|captures| {
+                let key = unescape_xml(&captures[1]);
+                let mut components = key.strip_prefix(&self.config.prefix)?.split('/');
+
+                let (name, version) = match (
+                    components.next(),
+                    components.next(),
+                    components.next(),
+                    components.next(),
+                ) {
+                    (Some(name), Some(version), Some("download"), None) => {
+                        (name.to_owned(), Version::parse(version).ok()?)
+                    }
+                    _ => return None,
+                };
+
+                Some(StoredCrateFile {
+                    name,
+                    version,
+                    size: captures[3].parse().ok()?,
+                    modified: SystemTime::from(DateTime::parse_from_rfc3339(&captures[2]).ok()?),
+                })
+            }));
+
+            match token_regex.captures(&body) {
+                Some(captures) => continuation_token = Some(unescape_xml(&captures[1])),
+                None => break,
+            }
+        }
+
+        Ok(crate_files)
+    }
+}
+
+impl S3StorageManager {
+    #[tracing::instrument(skip(self, name, version))]
+    fn key(&self, name: &str, version: &Version) -> String {
+        format!("{}{}/{}/download", self.config.prefix, name, version)
+    }
+
+    /// Sends the signed request to the bucket, or to the object if `key` is given.
+    #[tracing::instrument(skip(self, method, key, query, body))]
+    async fn send(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, String)],
+        body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let mut canonical_uri = format!(
+            "{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.config.bucket, true)
+        );
+        if let Some(key) = key {
+            canonical_uri.push('/');
+            canonical_uri.push_str(&uri_encode(key, false));
+        }
+
+        let mut query: Vec<_> = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+            .collect();
+        query.sort();
+        let canonical_query = query.join("&");
+
+        let host = match (self.endpoint.host_str(), self.endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(Error::Storage("the endpoint has no host".to_owned())),
+        };
+
+        let mut url = format!("{}://{}{}", self.endpoint.scheme(), host, canonical_uri);
+        if !canonical_query.is_empty() {
+            url.push('?');
+            url.push_str(&canonical_query);
+        }
+        let url = Url::parse(&url).map_err(Error::UrlParsing)?;
+
+        let payload_hash = hex_sha256(&body);
+        let now = Utc::now();
+        let authorization = self.authorization(
+            method.as_str(),
+            &canonical_uri,
+            &canonical_query,
+            &host,
+            &payload_hash,
+            now,
+        );
+
+        self.client
+            .request(method, url)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date(now))
+            .header("Authorization", authorization)
+            .body(body)
+            .send()
+            .map_err(Error::HttpRequest)
+            .await
+    }
+
+    /// Returns the value of `Authorization` header of AWS Signature Version 4.
+    #[tracing::instrument(skip(self, method, canonical_uri, canonical_query, host, payload_hash))]
+    fn authorization(
+        &self,
+        method: &str,
+        canonical_uri: &str,
+        canonical_query: &str,
+        host: &str,
+        payload_hash: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let amz_date = amz_date(now);
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            canonical_uri,
+            canonical_query,
+            host,
+            payload_hash,
+            amz_date,
+            SIGNED_HEADERS,
+            payload_hash
+        );
+        let string_to_sign = format!(
+            "{}\n{}\n{}\n{}",
+            ALGORITHM,
+            amz_date,
+            scope,
+            hex_sha256(canonical_request.as_bytes())
+        );
+
+        let signing_key = [
+            date.as_str(),
+            self.config.region.as_str(),
+            "s3",
+            "aws4_request",
+        ]
+        .iter()
+        .fold(
+            format!("AWS4{}", self.config.secret_access_key).into_bytes(),
+            |key, data| hmac_sha256(&key, data.as_bytes()),
+        );
+        let signature = hmac_sha256(&signing_key, string_to_sign.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        format!(
+            "{} Credential={}/{}, SignedHeaders={}, Signature={}",
+            ALGORITHM, self.config.access_key_id, scope, SIGNED_HEADERS, signature
+        )
+    }
+
+    #[tracing::instrument(skip(self, target, response))]
+    fn check_status(&self, target: &str, response: Response) -> Result<Response, Error> {
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(Error::Storage(format!(
+                "{} responded with {}",
+                target,
+                response.status()
+            )))
+        }
+    }
+}
+
+#[tracing::instrument(skip(now))]
+fn amz_date(now: DateTime<Utc>) -> String {
+    now.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+#[tracing::instrument(skip(data))]
+fn hex_sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[tracing::instrument(skip(key, data))]
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes the string except the unreserved characters as AWS Signature Version 4 requires.
+#[tracing::instrument(skip(s))]
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_owned(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[tracing::instrument(skip(s))]
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{uri_encode, S3StorageManager};
+    use crate::config::S3Config;
+    use chrono::{TimeZone, Utc};
+    use reqwest::Client;
+    use url::Url;
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("foo_bar/1.0.0+build.1/download", false),
+            "foo_bar/1.0.0%2Bbuild.1/download"
+        );
+        assert_eq!(uri_encode("crates/", true), "crates%2F");
+    }
+
+    #[test]
+    fn test_authorization() {
+        let storage_manager = S3StorageManager {
+            client: Client::new(),
+            endpoint: Url::parse("http://localhost:9000").unwrap(),
+            config: S3Config {
+                endpoint: "http://localhost:9000".to_owned(),
+                bucket: "crates".to_owned(),
+                region: "us-east-1".to_owned(),
+                access_key_id: "AKIDEXAMPLE".to_owned(),
+                secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+                prefix: String::new(),
+            },
+        };
+
+        let authorization = storage_manager.authorization(
+            "GET",
+            "/crates/foo/0.1.0/download",
+            "",
+            "localhost:9000",
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            Utc.with_ymd_and_hms(2021, 1, 2, 3, 4, 5).unwrap(),
+        );
+
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20210102/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+             Signature=223389882aeee6d722c93160831e779aa801e0d585fb76af20e1837decbe8d98"
+        );
+    }
+}
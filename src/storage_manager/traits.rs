@@ -0,0 +1,39 @@
+use crate::config::CrateFilesConfig;
+use crate::error::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use semver::Version;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// A crate file found in the storage.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredCrateFile {
+    pub name: String,
+    pub version: Version,
+    /// The size of the crate file in bytes.
+    pub size: u64,
+    /// The time when the crate file is stored last.
+    pub modified: SystemTime,
+}
+
+/// The storage of the crate files, each of which is identified by the crate name
+/// the crate is published with and the version.
+#[async_trait]
+pub trait StorageManager: Send + Sync + Sized {
+    async fn new(config: &CrateFilesConfig) -> Result<Self, Error>;
+
+    /// Moves the local file at `path` into the storage as the crate file.
+    /// The file is left as it is if this fails.
+    async fn put(&self, name: &str, version: &Version, path: &Path) -> Result<(), Error>;
+    /// Returns the content of the crate file, or `None` if it does not exist.
+    async fn get(&self, name: &str, version: &Version) -> Result<Option<Bytes>, Error>;
+    /// Returns the SHA256 checksum of the crate file, or `None` if it does not exist.
+    /// The content is hashed as it is read, so the crate file is never held in memory as a whole.
+    async fn checksum(&self, name: &str, version: &Version) -> Result<Option<String>, Error>;
+    /// Removes the crate file. Nothing happens if it does not exist.
+    async fn delete(&self, name: &str, version: &Version) -> Result<(), Error>;
+    async fn exists(&self, name: &str, version: &Version) -> Result<bool, Error>;
+    /// Lists all the crate files.
+    async fn list(&self) -> Result<Vec<StoredCrateFile>, Error>;
+}
@@ -0,0 +1,118 @@
+//! The helpers shared by the tests which need a database.
+#![cfg(all(test, feature = "db-sled"))]
+
+use crate::config::DbConfig;
+#[cfg(not(feature = "storage-s3"))]
+use crate::config::IndexConfig;
+use crate::db_manager::{DbManager, SledDbManager};
+use crate::models::{Metadata, User};
+#[cfg(not(feature = "storage-s3"))]
+use flate2::write::GzEncoder;
+#[cfg(not(feature = "storage-s3"))]
+use flate2::Compression;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Opens an empty database in `dir_path`.
+pub async fn db_manager(dir_path: &Path) -> Arc<RwLock<SledDbManager>> {
+    let config = DbConfig {
+        db_dir_path: dir_path.join("db"),
+        ..Default::default()
+    };
+    let db_manager = SledDbManager::new(&config).await.unwrap();
+    Arc::new(RwLock::new(db_manager))
+}
+
+/// Adds a user named `name` and returns the ID and the token of the user.
+pub async fn add_user(db_manager: &RwLock<impl DbManager>, name: &str) -> (u32, String) {
+    let db_manager = db_manager.write().await;
+    let user_id = db_manager
+        .last_user_id()
+        .await
+        .unwrap()
+        .map(|user_id| user_id + 1)
+        .unwrap_or(0);
+    let login = format!("{}{}", db_manager.get_login_prefix().await.unwrap(), name);
+    let token = format!("token-of-{}", name);
+
+    db_manager
+        .add_new_user(User::new(user_id, login, Some(name)), "password")
+        .await
+        .unwrap();
+    db_manager.set_token(user_id, &token).await.unwrap();
+    (user_id, token)
+}
+
+/// Returns the metadata of the version with the fields other than the name and the version empty.
+pub fn metadata(name: &str, version: &str) -> Metadata {
+    serde_json::from_value(serde_json::json!({
+        "name": name,
+        "vers": version,
+        "deps": [],
+        "features": {},
+        "authors": [],
+        "description": null,
+        "documentation": null,
+        "homepage": null,
+        "readme": null,
+        "readme_file": null,
+        "keywords": [],
+        "categories": [],
+        "license": null,
+        "license_file": null,
+        "repository": null,
+        "badges": {},
+        "links": null
+    }))
+    .unwrap()
+}
+
+#[cfg(not(feature = "storage-s3"))]
+/// Returns the config of the index cloned into `index` from the bare repository `index.git` in `dir_path`.
+pub fn index_config(dir_path: &Path) -> IndexConfig {
+    IndexConfig {
+        local_path: dir_path.join("index"),
+        branch: "master".to_owned(),
+        name: "ktra".to_owned(),
+        bare_path: Some(dir_path.join("index.git")),
+        ..Default::default()
+    }
+}
+
+#[cfg(not(feature = "storage-s3"))]
+/// Returns the body of the publish request cargo sends for the version.
+pub fn publish_body(metadata: &Metadata) -> Vec<u8> {
+    let json = serde_json::to_vec(metadata).unwrap();
+    let crate_file = crate_file(&metadata.name, &metadata.vers.to_string());
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    body.extend_from_slice(&json);
+    body.extend_from_slice(&(crate_file.len() as u32).to_le_bytes());
+    body.extend_from_slice(&crate_file);
+    body
+}
+
+#[cfg(not(feature = "storage-s3"))]
+/// Returns the crate file of the version containing only `Cargo.toml`.
+pub fn crate_file(name: &str, version: &str) -> Vec<u8> {
+    let manifest = format!(
+        "[package]\nname = \"{}\"\nversion = \"{}\"\n",
+        name, version
+    );
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    builder
+        .append_data(
+            &mut header,
+            format!("{}-{}/Cargo.toml", name, version),
+            manifest.as_bytes(),
+        )
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap()
+}
@@ -1,12 +1,17 @@
-use crate::config::OpenIdConfig;
+use crate::config::{AdminConfig, OpenIdConfig, PublishConfig};
 use crate::db_manager::DbManager;
+use crate::download_counter::DownloadCounter;
 use crate::error::Error;
 use crate::index_manager::IndexManager;
+use crate::policy::Policy;
+use crate::storage_manager::StorageManager;
 use futures::TryFutureExt;
 use rand::distributions::Alphanumeric;
 use rand::prelude::*;
 #[cfg(feature = "crates-io-mirroring")]
 use reqwest::Client;
+use semver::Version;
+use sha2::{Digest, Sha256};
 use std::convert::Infallible;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
@@ -18,6 +23,7 @@ pub fn always_true<T>(_: T) -> bool {
     true
 }
 
+#[cfg(feature = "crates-io-mirroring")]
 #[tracing::instrument(skip(path))]
 pub async fn file_exists_and_not_empty(path: impl AsRef<Path>) -> bool {
     tokio::fs::metadata(path)
@@ -50,10 +56,34 @@ pub fn package_dir_path(crate_name: &str) -> Result<impl AsRef<Path>, Error> {
     }
 }
 
-#[tracing::instrument]
-pub fn empty_json_message<T>(_: T) -> impl Reply {
-    tracing::info!("just returns an empty message JSON.");
-    warp::reply::json(&serde_json::json!({ "warning": null }))
+/// Checks that the version exists and its yanked state is not `yanked` yet in the database,
+/// which must be done before the index is updated so that the index is not left changed alone.
+#[tracing::instrument(skip(db_manager, name, version, yanked))]
+pub async fn check_yanked_changeable(
+    db_manager: &impl DbManager,
+    name: &str,
+    version: &Version,
+    yanked: bool,
+) -> Result<(), Error> {
+    let entry = db_manager.crate_entry(name).await?;
+    let metadata = entry
+        .versions()
+        .get(version)
+        .ok_or_else(|| Error::VersionNotFoundInDb(version.clone()))?;
+
+    match (metadata.yanked, yanked) {
+        (true, true) => Err(Error::AlreadyYanked(name.to_owned(), version.clone())),
+        (false, false) => Err(Error::NotYetYanked(name.to_owned(), version.clone())),
+        _ => Ok(()),
+    }
+}
+
+#[tracing::instrument(skip(data))]
+pub fn checksum(data: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.update(data);
+    let checksum = hasher.finalize();
+    format!("{:x}", checksum)
 }
 
 #[tracing::instrument]
@@ -100,6 +130,53 @@ pub fn with_db_manager(
     warp::any().map(move || db_manager.clone())
 }
 
+/// Rejects the request without a valid token if the registry requires the authorization.
+#[tracing::instrument(skip(db_manager, auth_required))]
+pub fn authorization_if_required(
+    db_manager: Arc<RwLock<impl DbManager>>,
+    auth_required: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .map(move || auth_required)
+        .and(with_db_manager(db_manager))
+        .and(warp::header::optional::<String>("Authorization"))
+        .and_then(check_authorization)
+        .untuple_one()
+}
+
+#[tracing::instrument(skip(auth_required, db_manager, token))]
+async fn check_authorization(
+    auth_required: bool,
+    db_manager: Arc<RwLock<impl DbManager>>,
+    token: Option<String>,
+) -> Result<(), Rejection> {
+    if !auth_required {
+        return Ok(());
+    }
+
+    let token = token.ok_or_else(|| warp::reject::custom(Error::AuthRequired))?;
+    let db_manager = db_manager.read().await;
+    db_manager
+        .user_id_for_token(&token)
+        .map_ok(|_| ())
+        .map_err(|_| warp::reject::custom(Error::AuthRequired))
+        .await
+}
+
+#[tracing::instrument(skip(storage_manager))]
+pub fn with_storage_manager(
+    storage_manager: Arc<impl StorageManager>,
+) -> impl Filter<Extract = (Arc<impl StorageManager>,), Error = Infallible> + Clone {
+    warp::any().map(move || storage_manager.clone())
+}
+
+#[tracing::instrument(skip(download_counter))]
+pub fn with_download_counter(
+    download_counter: Arc<DownloadCounter>,
+) -> impl Filter<Extract = (Arc<DownloadCounter>,), Error = Infallible> + Clone {
+    warp::any().map(move || download_counter.clone())
+}
+
 #[tracing::instrument(skip(index_manager))]
 pub fn with_index_manager(
     index_manager: Arc<IndexManager>,
@@ -114,6 +191,27 @@ pub fn with_openid_config(
     warp::any().map(move || openid_config.clone())
 }
 
+#[tracing::instrument(skip(publish_config))]
+pub fn with_publish_config(
+    publish_config: Arc<PublishConfig>,
+) -> impl Filter<Extract = (Arc<PublishConfig>,), Error = Infallible> + Clone {
+    warp::any().map(move || publish_config.clone())
+}
+
+#[tracing::instrument(skip(admin_config))]
+pub fn with_admin_config(
+    admin_config: Arc<AdminConfig>,
+) -> impl Filter<Extract = (Arc<AdminConfig>,), Error = Infallible> + Clone {
+    warp::any().map(move || admin_config.clone())
+}
+
+#[tracing::instrument(skip(policy))]
+pub fn with_policy(
+    policy: Arc<Policy>,
+) -> impl Filter<Extract = (Arc<Policy>,), Error = Infallible> + Clone {
+    warp::any().map(move || policy.clone())
+}
+
 #[tracing::instrument]
 pub fn authorization_header() -> impl Filter<Extract = (String,), Error = Rejection> + Copy {
     warp::header::<String>("Authorization")
@@ -0,0 +1,125 @@
+use crate::error::Error;
+use crate::index_manager::IndexManager;
+use crate::utils::{ok_json_message, with_index_manager};
+use bytes::Bytes;
+use futures::TryFutureExt;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+#[tracing::instrument(skip(index_manager, secret))]
+pub fn apis(
+    index_manager: Arc<IndexManager>,
+    secret: Option<Arc<String>>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::post()
+        .and(warp::path!("ktra" / "api" / "v1" / "index" / "pull"))
+        .and(with_index_manager(index_manager))
+        .and(with_secret(secret))
+        .and(warp::header::optional::<String>("X-Gitlab-Token"))
+        .and(warp::header::optional::<String>("X-Hub-Signature-256"))
+        .and(warp::body::bytes())
+        .and_then(handle_pull)
+}
+
+#[tracing::instrument(skip(secret))]
+fn with_secret(
+    secret: Option<Arc<String>>,
+) -> impl Filter<Extract = (Arc<String>,), Error = Rejection> + Clone {
+    warp::any().and_then(move || {
+        let secret = secret.clone();
+        // the endpoint does not exist unless the secret is configured.
+        async move { secret.ok_or_else(warp::reject::not_found) }
+    })
+}
+
+#[tracing::instrument(skip(index_manager, secret, gitlab_token, hub_signature, body))]
+async fn handle_pull(
+    index_manager: Arc<IndexManager>,
+    secret: Arc<String>,
+    gitlab_token: Option<String>,
+    hub_signature: Option<String>,
+    body: Bytes,
+) -> Result<impl Reply, Rejection> {
+    if !is_authorized(
+        &secret,
+        gitlab_token.as_deref(),
+        hub_signature.as_deref(),
+        &body,
+    ) {
+        return Err(warp::reject::custom(Error::InvalidWebhookSignature));
+    }
+
+    index_manager
+        .pull()
+        .map_ok(ok_json_message)
+        .map_err(warp::reject::custom)
+        .await
+}
+
+/// Verifies the webhook request by either the GitLab secret token
+/// or the HMAC-SHA256 signature of the body GitHub and Gitea send.
+#[tracing::instrument(skip(secret, gitlab_token, hub_signature, body))]
+fn is_authorized(
+    secret: &str,
+    gitlab_token: Option<&str>,
+    hub_signature: Option<&str>,
+    body: &[u8],
+) -> bool {
+    if let Some(gitlab_token) = gitlab_token {
+        return constant_time_eq(gitlab_token.as_bytes(), secret.as_bytes());
+    }
+
+    let signature = match hub_signature.and_then(|s| s.strip_prefix("sha256=")) {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    let expected = format!("{:x}", mac.finalize().into_bytes());
+
+    constant_time_eq(
+        signature.to_ascii_lowercase().as_bytes(),
+        expected.as_bytes(),
+    )
+}
+
+#[tracing::instrument(skip(a, b))]
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_authorized;
+
+    #[test]
+    fn test_is_authorized_gitlab_token() {
+        assert!(is_authorized("secret", Some("secret"), None, b"{}"));
+        assert!(!is_authorized("secret", Some("wrong"), None, b"{}"));
+    }
+
+    #[test]
+    fn test_is_authorized_hub_signature() {
+        // the example in the GitHub documentation.
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+        assert!(is_authorized(
+            "It's a Secret to Everybody",
+            None,
+            Some(signature),
+            b"Hello, World!"
+        ));
+        assert!(!is_authorized(
+            "It's a Secret to Everybody",
+            None,
+            Some(signature),
+            b"Hello, World?"
+        ));
+        assert!(!is_authorized("secret", None, None, b"{}"));
+    }
+}